@@ -0,0 +1,293 @@
+use crate::metrics::ServerMetrics;
+use crate::networking::{AsyncTcpServer, ClientConnections, ShutdownHandle};
+use crate::players::Players;
+use crate::protocol::{PlayerId, ServerMessage};
+use crate::session::Sessions;
+use async_std::task;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// Admin console for a running server. Commands are lines of text, typed into the terminal the
+// server was started from (see main.rs), and work on the live game state, connections and
+// sessions. Every command answers with text to print.
+
+pub const HELP: &str = "\
+players          list connected players
+rooms            list rooms and what is in them
+kick <id>        disconnect a player; it can join again as a new player
+ban <ip>         kick everyone from an address and refuse its new connections
+unban <ip>       let an address connect again
+say <message>    show a message to every player
+dump <room>      print a room's state, by roomID or key
+stats            print the server metrics summary
+shutdown         stop the server (also quit / stop)";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Help,
+    Players,
+    Rooms,
+    Kick(PlayerId),
+    Ban(IpAddr),
+    Unban(IpAddr),
+    Say(String),
+    Dump(String),
+    Stats,
+    Shutdown,
+}
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let ip = |rest: &str| rest.parse::<IpAddr>().map_err(|_| format!("{:?} is not an IP address", rest));
+        match name {
+            "help" | "?" => Ok(AdminCommand::Help),
+            "players" => Ok(AdminCommand::Players),
+            "rooms" => Ok(AdminCommand::Rooms),
+            "kick" => rest.parse().map(AdminCommand::Kick).map_err(|_| format!("{:?} is not a player id", rest)),
+            "ban" => ip(rest).map(AdminCommand::Ban),
+            "unban" => ip(rest).map(AdminCommand::Unban),
+            "say" if !rest.is_empty() => Ok(AdminCommand::Say(rest.to_string())),
+            "say" => Err("say what?".to_string()),
+            "dump" if !rest.is_empty() => Ok(AdminCommand::Dump(rest.to_string())),
+            "dump" => Err("dump which room?".to_string()),
+            "stats" => Ok(AdminCommand::Stats),
+            "shutdown" | "quit" | "stop" => Ok(AdminCommand::Shutdown),
+            other => Err(format!("unknown command {:?}, try help", other)),
+        }
+    }
+}
+
+/// Addresses that may not connect. Cloning gives another handle to the same list, so the
+/// server's accept filter sees bans as soon as they are made.
+#[derive(Clone, Default)]
+pub struct BanList {
+    ips: Arc<Mutex<HashSet<IpAddr>>>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    /// Returns false if the address was already banned.
+    pub fn ban(&self, ip: IpAddr) -> bool {
+        self.ips.lock().unwrap().insert(ip)
+    }
+
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.ips.lock().unwrap().remove(&ip)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.ips.lock().unwrap().contains(&ip)
+    }
+}
+
+/// Everything the console works on, shared with the running server.
+pub struct AdminConsole {
+    pub game: Arc<Mutex<Value>>,
+    pub clients: Arc<Mutex<ClientConnections>>,
+    pub players: Arc<Mutex<Players>>,
+    pub sessions: Arc<Mutex<Sessions>>,
+    pub bans: BanList,
+    pub metrics: ServerMetrics,
+    pub shutdown: ShutdownHandle,
+}
+
+impl AdminConsole {
+    /// Runs one line of input and returns what to print.
+    pub fn run(&self, line: &str) -> String {
+        match AdminCommand::parse(line) {
+            Ok(command) => self.execute(command),
+            Err(e) => e,
+        }
+    }
+
+    pub fn execute(&self, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::Help => HELP.to_string(),
+            AdminCommand::Players => self.players(),
+            AdminCommand::Rooms => self.rooms(),
+            AdminCommand::Kick(id) => match self.kick(id, "Kicked by the server admin") {
+                true => format!("Kicked player {}", id),
+                false => format!("No player {} is connected", id),
+            },
+            AdminCommand::Ban(ip) => {
+                let newly = self.bans.ban(ip);
+                let kicked = self.kick_address(ip);
+                format!("{} {}, kicked {} player(s)", if newly { "Banned" } else { "Already banned" }, ip, kicked)
+            }
+            AdminCommand::Unban(ip) => match self.bans.unban(ip) {
+                true => format!("Unbanned {}", ip),
+                false => format!("{} was not banned", ip),
+            },
+            AdminCommand::Say(text) => {
+                let notice = ServerMessage::Announcement { text };
+                let dropped = task::block_on(self.clients.lock().unwrap().broadcast(&notice.to_json()));
+                format!("Sent to everyone{}", if dropped.is_empty() { String::new() } else { format!(" but {:?}", dropped) })
+            }
+            AdminCommand::Dump(room) => self.dump(&room),
+            AdminCommand::Stats => self.metrics.summary(),
+            AdminCommand::Shutdown => {
+                self.shutdown.shutdown();
+                "Shutting down".to_string()
+            }
+        }
+    }
+
+    fn players(&self) -> String {
+        let sessions = self.sessions.lock().unwrap();
+        let mut clients = self.clients.lock().unwrap();
+        let flagged = self.players.lock().unwrap().flagged();
+        let game = clients.lock_game(None, &self.game).clone();
+        let mut ids = clients.client_ids();
+        ids.sort_unstable();
+        let mut lines = vec![format!("{} player(s) connected", ids.len())];
+        for id in ids {
+            let name = sessions.profile(id).map_or("?", |profile| profile.name.as_str());
+            let address = clients.get_client(id)
+                .and_then(|stream| stream.peer_addr().ok())
+                .map_or_else(|| "?".to_string(), |addr| addr.to_string());
+            let place = ClientConnections::room_of(&game, id)
+                .and_then(|room_id| find_player(&game, room_id, id).map(|player| (room_id, player)))
+                .map_or_else(|| "not in a room".to_string(), |(room_id, player)| {
+                    format!("room {} at ({}, {})", room_id, player["x"], player["y"])
+                });
+            let flag = if flagged.contains(&id) { ", flagged" } else { "" };
+            lines.push(format!("  {} {:?} from {}, {}{}", id, name, address, place, flag));
+        }
+        lines.join("\n")
+    }
+
+    fn rooms(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let game = clients.lock_game(None, &self.game);
+        let mut rooms: Vec<_> = game.as_object().into_iter().flatten().collect();
+        rooms.sort_by_key(|(_, room)| room["roomID"].as_i64());
+        let count = |room: &Value, list: &str| room[list].as_array().map_or(0, Vec::len);
+        rooms.iter()
+            .map(|(key, room)| format!(
+                "{} ({}): {} players, {} npcs, {} objects",
+                room["roomID"], key, count(room, "players"), count(room, "npcs"), count(room, "objects"),
+            ))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn dump(&self, room: &str) -> String {
+        let clients = self.clients.lock().unwrap();
+        let game = clients.lock_game(None, &self.game);
+        let room_id = room.parse::<i64>().ok();
+        let found = game.as_object().into_iter().flatten()
+            .find(|(key, value)| key.as_str() == room || room_id.is_some_and(|id| value["roomID"] == id));
+        match found {
+            Some((_, value)) => serde_json::to_string_pretty(value).unwrap_or_default(),
+            None => format!("No room {:?}", room),
+        }
+    }
+
+    /// Tells a player why, then closes its connection and revokes its session so it cannot
+    /// resume. Returns false if the player is not connected.
+    fn kick(&self, id: PlayerId, reason: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut clients = self.clients.lock().unwrap();
+        let Some(mut stream) = clients.get_client(id).cloned() else {
+            return false;
+        };
+        sessions.revoke(id);
+        let notice = ServerMessage::Rejected { reason: reason.to_string() };
+        task::block_on(async {
+            AsyncTcpServer::send(&mut stream, &notice.to_json()).await
+                .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+            AsyncTcpServer::close(&mut stream).await;
+        });
+        println!("Player {} kicked: {}", id, reason);
+        true
+    }
+
+    /// Kicks every player connected from `ip` and returns how many there were.
+    fn kick_address(&self, ip: IpAddr) -> usize {
+        let ids: Vec<PlayerId> = {
+            let mut clients = self.clients.lock().unwrap();
+            clients.client_ids().into_iter()
+                .filter(|id| clients.get_client(*id).and_then(|s| s.peer_addr().ok()).is_some_and(|addr| addr.ip() == ip))
+                .collect()
+        };
+        ids.into_iter().filter(|id| self.kick(*id, "Banned by the server admin")).count()
+    }
+}
+
+fn find_player(game: &Value, room_id: i32, id: PlayerId) -> Option<&Value> {
+    game.as_object()?.values()
+        .find(|room| room["roomID"] == room_id)?["players"]
+        .as_array()?
+        .iter()
+        .find(|player| player["id"] == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::AsyncTcpClient;
+    use async_std::net::TcpListener;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(AdminCommand::parse("kick 3"), Ok(AdminCommand::Kick(3)));
+        assert_eq!(AdminCommand::parse(" ban 10.0.0.7 "), Ok(AdminCommand::Ban("10.0.0.7".parse().unwrap())));
+        assert_eq!(AdminCommand::parse("say hello  there"), Ok(AdminCommand::Say("hello  there".to_string())));
+        assert_eq!(AdminCommand::parse("quit"), Ok(AdminCommand::Shutdown));
+        assert!(AdminCommand::parse("kick someone").is_err());
+        assert!(AdminCommand::parse("ban").is_err());
+        assert!(AdminCommand::parse("teleport 1").is_err());
+    }
+
+    #[async_std::test]
+    async fn test_console_works_on_the_live_server_state() -> async_std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut player = AsyncTcpClient::new(&listener.local_addr()?.to_string()).connect().await?;
+        let (server_side, _) = listener.accept().await?;
+
+        let mut sessions = Sessions::new();
+        let joined = sessions.join(1, None);
+        let mut clients = ClientConnections::new();
+        clients.add_client(joined.player_id, server_side.into());
+        let console = AdminConsole {
+            game: Arc::new(Mutex::new(json!({
+                "room1": {"players": [{"id": joined.player_id, "x": 5, "y": 6}], "npcs": [], "objects": [], "roomID": 1},
+            }))),
+            clients: Arc::new(Mutex::new(clients)),
+            players: Arc::new(Mutex::new(Players::new())),
+            sessions: Arc::new(Mutex::new(sessions)),
+            bans: BanList::new(),
+            metrics: ServerMetrics::new(),
+            shutdown: ShutdownHandle::new(),
+        };
+
+        assert!(console.run("players").contains("room 1 at (5, 6)"));
+        assert_eq!(console.run("rooms"), "1 (room1): 1 players, 0 npcs, 0 objects");
+        assert!(console.run("dump 1").contains("\"roomID\": 1"));
+
+        console.run("say hello");
+        let notice = AsyncTcpClient::receive(&mut player).await?;
+        assert_eq!(ServerMessage::from_json(&notice), Ok(ServerMessage::Announcement { text: "hello".to_string() }));
+
+        // Banning the address kicks the player, who cannot resume afterwards
+        assert_eq!(console.run("ban 127.0.0.1"), "Banned 127.0.0.1, kicked 1 player(s)");
+        assert!(console.bans.is_banned("127.0.0.1".parse().unwrap()));
+        let notice = AsyncTcpClient::receive(&mut player).await?;
+        assert!(matches!(ServerMessage::from_json(&notice), Ok(ServerMessage::Rejected { .. })));
+        assert!(AsyncTcpClient::receive(&mut player).await.is_err());
+        assert!(console.sessions.lock().unwrap().join(2, Some(&joined.token)).player_id != joined.player_id);
+
+        console.run("shutdown");
+        assert!(console.shutdown.is_shut_down());
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use crate::protocol::{ChatScope, ClientMessage, PlayerId};
+use crate::ratelimit::TokenBucket;
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+// Text chat. Players talk to their room, to everyone, or whisper to one player. The server cleans
+// every message up before passing it on: control characters go, the length is capped, blocked
+// words are masked and players who send too many messages in a row are told to slow down. On the
+// client, typed lines become messages through `parse_input` and whatever arrives is kept in a
+// `ChatLog` for the overlay.
+
+/// Longest message, in characters.
+pub const MAX_CHAT_LENGTH: usize = 200;
+/// Messages a player can send in one go, and how many more per second after that.
+pub const CHAT_BURST: f64 = 5.0;
+pub const CHAT_MESSAGES_PER_SECOND: f64 = 1.0;
+/// Masked in every message, as whole words and ignoring case. The CHAT_BLOCKED_WORDS setting adds more.
+pub const BLOCKED_WORDS: [&str; 6] = ["fuck", "shit", "bitch", "bastard", "cunt", "asshole"];
+/// Lines the client keeps for scrolling back.
+pub const CHAT_LOG_LINES: usize = 100;
+
+/// The server's checks on chat messages, with a flood bucket per player.
+#[derive(Debug, Clone)]
+pub struct ChatFilter {
+    blocked: Vec<String>,
+    buckets: HashMap<PlayerId, TokenBucket>,
+}
+
+impl Default for ChatFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChatFilter {
+    /// A filter that masks BLOCKED_WORDS.
+    pub fn new() -> Self {
+        ChatFilter { blocked: BLOCKED_WORDS.iter().map(|w| w.to_string()).collect(), buckets: HashMap::new() }
+    }
+
+    /// Masks `word` as well from now on.
+    pub fn block(&mut self, word: &str) {
+        let word = word.trim().to_lowercase();
+        if !word.is_empty() && !self.blocked.contains(&word) {
+            self.blocked.push(word);
+        }
+    }
+
+    /// Cleans up what `player` wants to say, or says why it cannot be sent.
+    pub fn check(&mut self, player: PlayerId, text: &str, now: Instant) -> Result<String, String> {
+        let text: String = text.chars().filter(|c| !c.is_control()).collect();
+        let text = text.trim();
+        if text.is_empty() {
+            return Err("Nothing to say".to_string());
+        }
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            return Err(format!("Chat messages can be at most {} characters", MAX_CHAT_LENGTH));
+        }
+        let bucket = self.buckets.entry(player)
+            .or_insert_with(|| TokenBucket::new(CHAT_MESSAGES_PER_SECOND, CHAT_BURST));
+        if !bucket.has(1.0, now) {
+            return Err("You are chatting too fast, slow down".to_string());
+        }
+        bucket.take(1.0, now);
+        Ok(self.mask(text))
+    }
+
+    /// Forgets `player`'s flood bucket once they disconnect.
+    pub fn remove_client(&mut self, player: PlayerId) {
+        self.buckets.remove(&player);
+    }
+
+    /// Replaces every letter of a blocked word with '*'.
+    fn mask(&self, text: &str) -> String {
+        let mut masked = String::with_capacity(text.len());
+        let mut word = String::new();
+        let flush = |word: &mut String, masked: &mut String| {
+            if self.blocked.contains(&word.to_lowercase()) {
+                masked.extend(word.chars().map(|_| '*'));
+            } else {
+                masked.push_str(word);
+            }
+            word.clear();
+        };
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            } else {
+                flush(&mut word, &mut masked);
+                masked.push(c);
+            }
+        }
+        flush(&mut word, &mut masked);
+        masked
+    }
+}
+
+/// Turns a line typed into the chat box into a message: "/g text" (or "/all") goes to everyone,
+/// "/w <id> text" to one player and anything else to the room.
+pub fn parse_input(line: &str) -> Result<ClientMessage, String> {
+    let line = line.trim();
+    let chat = |scope, to, text: &str| match text.trim() {
+        "" => Err("Nothing to say".to_string()),
+        text => Ok(ClientMessage::Chat { scope, to, text: text.to_string() }),
+    };
+    let Some(command) = line.strip_prefix('/') else {
+        return chat(ChatScope::Room, None, line);
+    };
+    let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+    match name {
+        "g" | "all" => chat(ChatScope::Global, None, rest),
+        "w" | "whisper" => {
+            let (to, text) = rest.trim_start().split_once(' ').unwrap_or((rest, ""));
+            let to = to.parse().map_err(|_| "Whisper to whom? Use /w <player id> <message>".to_string())?;
+            chat(ChatScope::Whisper, Some(to), text)
+        }
+        other => Err(format!("Unknown chat command /{}", other)),
+    }
+}
+
+/// One line in the chat overlay. Lines from the server itself have no scope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatLine {
+    pub scope: Option<ChatScope>,
+    pub text: String,
+}
+
+/// What the client shows in the chat overlay, newest last, and how far it is scrolled back.
+#[derive(Debug, Clone, Default)]
+pub struct ChatLog {
+    lines: VecDeque<ChatLine>,
+    scroll: usize,
+}
+
+impl ChatLog {
+    pub fn new() -> Self {
+        ChatLog::default()
+    }
+
+    /// Adds a chat message as the player `own_id` should read it.
+    pub fn push_chat(&mut self, own_id: PlayerId, scope: ChatScope, from: PlayerId, name: &str, to: Option<PlayerId>, text: &str) {
+        let text = match scope {
+            ChatScope::Room => format!("{}: {}", name, text),
+            ChatScope::Global => format!("[all] {}: {}", name, text),
+            ChatScope::Whisper if from == own_id => format!("[to {}] {}", to.unwrap_or(0), text),
+            ChatScope::Whisper => format!("[from {} ({})] {}", name, from, text),
+        };
+        self.push(ChatLine { scope: Some(scope), text });
+    }
+
+    /// Adds a line from the server, e.g. why a message was not sent.
+    pub fn push_notice(&mut self, text: &str) {
+        self.push(ChatLine { scope: None, text: text.to_string() });
+    }
+
+    fn push(&mut self, line: ChatLine) {
+        self.lines.push_back(line);
+        if self.lines.len() > CHAT_LOG_LINES {
+            self.lines.pop_front();
+        }
+        // Someone reading back keeps their place
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.lines.len().saturating_sub(1));
+        }
+    }
+
+    /// Scrolls back (positive) or forward (negative) by `lines`.
+    pub fn scroll(&mut self, lines: i32) {
+        let max = self.lines.len().saturating_sub(1) as i64;
+        self.scroll = (self.scroll as i64 + lines as i64).clamp(0, max) as usize;
+    }
+
+    /// The `count` lines to draw, oldest first.
+    pub fn visible(&self, count: usize) -> Vec<&ChatLine> {
+        let end = self.lines.len() - self.scroll;
+        self.lines.range(end.saturating_sub(count)..end).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_filter_cleans_and_limits_messages() {
+        let start = Instant::now();
+        let mut filter = ChatFilter::new();
+        filter.block("Darn");
+        assert_eq!(filter.check(1, "  what the Shit, darn\u{7}it!  ", start), Ok("what the ****, darnit!".to_string()));
+        assert_eq!(filter.check(1, "darn. shitake", start), Ok("****. shitake".to_string()));
+        assert!(filter.check(1, &"a".repeat(MAX_CHAT_LENGTH + 1), start).is_err());
+        assert!(filter.check(1, " \n ", start).is_err());
+
+        // Two sent already; the rest of the burst, then a wait for the bucket to refill
+        for _ in 2..CHAT_BURST as usize {
+            assert!(filter.check(1, "hi", start).is_ok());
+        }
+        assert!(filter.check(1, "hi", start).is_err());
+        assert!(filter.check(2, "hi", start).is_ok());
+        assert!(filter.check(1, "hi", start + Duration::from_secs(1)).is_ok());
+
+        // A player who left takes their bucket with them
+        filter.remove_client(2);
+        assert!(!filter.buckets.contains_key(&2));
+        assert!(filter.buckets.contains_key(&1));
+    }
+
+    #[test]
+    fn test_input_and_log() {
+        assert_eq!(parse_input("hello"), Ok(ClientMessage::Chat { scope: ChatScope::Room, to: None, text: "hello".to_string() }));
+        assert_eq!(parse_input("/g hi all"), Ok(ClientMessage::Chat { scope: ChatScope::Global, to: None, text: "hi all".to_string() }));
+        assert_eq!(parse_input("/w 4 psst"), Ok(ClientMessage::Chat { scope: ChatScope::Whisper, to: Some(4), text: "psst".to_string() }));
+        assert!(parse_input("/w bob psst").is_err());
+        assert!(parse_input("/w 4").is_err());
+        assert!(parse_input("/dance").is_err());
+
+        let mut log = ChatLog::new();
+        log.push_chat(1, ChatScope::Room, 2, "Ada", None, "hi");
+        log.push_chat(1, ChatScope::Whisper, 1, "Me", Some(2), "psst");
+        log.push_notice("You are chatting too fast, slow down");
+        let texts = |log: &ChatLog, count| log.visible(count).iter().map(|l| l.text.clone()).collect::<Vec<_>>();
+        assert_eq!(texts(&log, 2), vec!["[to 2] psst", "You are chatting too fast, slow down"]);
+
+        log.scroll(1);
+        assert_eq!(texts(&log, 2), vec!["Ada: hi", "[to 2] psst"]);
+        // New lines do not move what is being read back
+        log.push_chat(1, ChatScope::Global, 3, "Bo", None, "yo");
+        assert_eq!(texts(&log, 2), vec!["Ada: hi", "[to 2] psst"]);
+        log.scroll(-10);
+        assert_eq!(texts(&log, 1), vec!["[all] Bo: yo"]);
+    }
+}
@@ -1,23 +1,191 @@
 use async_std::path::PathBuf;
 use raylib::prelude::*;
 use raylib_interactive;
-use raylib_interactive::button::Button;
+use raylib_interactive::textfield::TextField;
 use serde_json::Value;
 use serde_json::json;
 use std::env;
-use std::thread;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::ops::DerefMut;
 use crate::movement;
 use crate::collision;
 use crate::networking::*;
-use crate::randommods::get_socket_id;
+use crate::protocol::{self, ChatScope, ClientDatagram, ClientMessage, EntityPosition, PlayerId, PositionUpdate, ServerDatagram, ServerMessage, StateDelta};
+use crate::handle_read::entity_position;
+use crate::tls::TlsSettings;
+use crate::replication::{self, ClientReplica, DeltaOutcome};
+use crate::interpolation::{Interpolator, MAX_EXTRAPOLATION};
+use crate::movement::MovementInput;
+use crate::prediction::{Authoritative, Prediction};
+use crate::chat::{self, ChatLog, MAX_CHAT_LENGTH};
+use async_std::io::{self, ErrorKind};
 use super::*;
 use crate::randommods;
 use async_std::task;
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
 
-pub fn main() {
+/// How long an admin announcement stays on screen.
+const ANNOUNCEMENT_TIME: Duration = Duration::from_secs(5);
+/// Chat lines shown above the chat box; the mouse wheel scrolls back through the rest.
+const CHAT_VISIBLE_LINES: usize = 8;
+
+/// What the server told us in `Joined`.
+struct JoinedGame {
+    player_id: PlayerId,
+    token: String,
+    /// The player entity, when an earlier session was resumed.
+    player: Option<Value>,
+    udp_port: Option<u16>,
+    /// What our datagrams are sent with, from `Welcome`.
+    udp_key: Option<String>,
+}
+
+/// Says hello, then sends `Join` and waits for the server's answer. A server that turns us away
+/// gives an error of kind `ConnectionRefused` whose message is the server's reason.
+async fn join_game(stream: &mut NetStream, hello: &ClientMessage, token: Option<String>, preferred_latency_ms: u32) -> async_std::io::Result<JoinedGame> {
+    AsyncTcpClient::send(stream, &hello.to_json()).await?;
+    let reply = AsyncTcpClient::receive(stream).await?;
+    let udp_key = match ServerMessage::from_json(&reply) {
+        Ok(ServerMessage::Welcome { version, capabilities, codec, udp_key }) => {
+            println!("Server speaks protocol {} with {:?}, using {}", version, capabilities, codec);
+            let codec = codec_by_name(&codec)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("server picked unknown codec {:?}", codec)))?;
+            stream.set_codec(codec);
+            udp_key
+        }
+        Ok(ServerMessage::Rejected { reason }) => return Err(io::Error::new(ErrorKind::ConnectionRefused, reason)),
+        Ok(other) => return Err(io::Error::new(ErrorKind::InvalidData, format!("expected welcome, got {:?}", other))),
+        // A server too old to know `hello` answers with something we cannot read either
+        Err(e) => return Err(io::Error::new(ErrorKind::ConnectionRefused, format!("The server is out of date ({}).", e))),
+    };
+
+    let join = ClientMessage::Join { token, preferred_latency_ms: Some(preferred_latency_ms) };
+    AsyncTcpClient::send(stream, &join.to_json()).await?;
+    let reply = AsyncTcpClient::receive(stream).await?;
+    match ServerMessage::from_json(&reply) {
+        Ok(ServerMessage::Joined { player_id, token, player, udp_port }) => Ok(JoinedGame { player_id, token, player, udp_port, udp_key }),
+        Ok(other) => Err(io::Error::new(ErrorKind::InvalidData, format!("expected joined, got {:?}", other))),
+        Err(e) => Err(io::Error::new(ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Feeds remote movement into the interpolator as it arrives. Entities that (re)appear or leave
+/// start over, so they are placed instead of sliding in from where they were last seen. Deltas
+/// only carry what changed, so their positions are read back from `game` once it is applied.
+fn record_remote(interpolator: &Mutex<Interpolator>, message: &ServerMessage, game: &Mutex<Value>) {
+    // Read before taking the interpolator, which the render loop locks ahead of the game
+    let moved = match message {
+        ServerMessage::Delta { changes, .. } => moved_in_delta(changes, &game.lock().unwrap()),
+        _ => Vec::new(),
+    };
+    let mut interpolator = interpolator.lock().unwrap();
+    let now = Instant::now();
+    match message {
+        ServerMessage::UpdatePosition(position) => interpolator.record(false, position, now),
+        ServerMessage::UpdateNpcPosition(position) => interpolator.record(true, position, now),
+        ServerMessage::RoomUpdate { players, npcs, .. } => {
+            players.iter().for_each(|position| interpolator.record(false, position, now));
+            npcs.iter().for_each(|position| interpolator.record(true, position, now));
+        }
+        ServerMessage::Player { player } => {
+            if let Some(id) = player["id"].as_u64() {
+                interpolator.forget(false, id as PlayerId);
+            }
+        }
+        ServerMessage::PlayerLeft { id } => interpolator.forget(false, *id),
+        ServerMessage::Spawn { npc, entity, .. } => {
+            if let Some(id) = entity["id"].as_u64() {
+                interpolator.forget(*npc, id as PlayerId);
+                interpolator.record(*npc, &entity_position(id as PlayerId, entity), now);
+            }
+        }
+        ServerMessage::Despawn { npc, id, .. } => interpolator.forget(*npc, *id),
+        ServerMessage::Delta { .. } => {
+            for (npc, added, position) in &moved {
+                if *added {
+                    interpolator.forget(*npc, position.id);
+                }
+                interpolator.record(*npc, position, now);
+            }
+        }
+        // A new snapshot may have moved anything anywhere
+        ServerMessage::Game { .. } => *interpolator = Interpolator::new(interpolator.delay(), MAX_EXTRAPOLATION),
+        _ => {}
+    }
+}
+
+/// The players and NPCs a delta added or moved, as `(npc, added, position)`, with their
+/// positions taken from `game` after the delta went in.
+fn moved_in_delta(changes: &StateDelta, game: &Value) -> Vec<(bool, bool, EntityPosition)> {
+    let mut moved = Vec::new();
+    for (npc, list) in [(false, "players"), (true, "npcs")] {
+        let find = |room: &str, id: &Value| game[room][list].as_array()
+            .and_then(|entities| entities.iter().find(|entity| entity["id"] == *id));
+        for (room_name, room) in &changes.rooms {
+            let added = room.added.get(list).into_iter().flatten().map(|entity| (true, entity));
+            let changed = room.changed.get(list).into_iter().flatten()
+                .filter(|entity| entity.get("x").is_some() || entity.get("y").is_some())
+                .map(|entity| (false, entity));
+            for (is_new, entity) in added.chain(changed) {
+                if let (Some(id), Some(current)) = (entity["id"].as_u64(), find(room_name, &entity["id"])) {
+                    moved.push((npc, is_new, entity_position(id as PlayerId, current)));
+                }
+            }
+        }
+        for room in changes.new_rooms.values() {
+            for entity in room[list].as_array().into_iter().flatten() {
+                if let Some(id) = entity["id"].as_u64() {
+                    moved.push((npc, true, entity_position(id as PlayerId, entity)));
+                }
+            }
+        }
+    }
+    moved
+}
+
+/// Our own position in a server update, with the input it includes.
+fn own_position(message: &ServerMessage, own_id: PlayerId) -> Option<Authoritative> {
+    let position = match message {
+        ServerMessage::UpdatePosition(position) => Some(position),
+        ServerMessage::RoomUpdate { players, .. } => players.iter().find(|p| p.id == own_id),
+        _ => None,
+    }.filter(|position| position.id == own_id)?;
+    position.input.map(|input| Authoritative { input, x: position.x, y: position.y })
+}
+
+/// Keeps the newest position the server confirmed for us, for the render loop to reconcile against.
+fn offer_authoritative(slot: &Mutex<Option<Authoritative>>, server: Authoritative) {
+    let mut slot = slot.lock().unwrap();
+    if slot.is_none_or(|current| server.input > current.input) {
+        *slot = Some(server);
+    }
+}
+
+/// Where to draw a remote entity: interpolated once updates for it arrive, from the game state until then.
+fn remote_rect(interpolator: &mut Interpolator, npc: bool, entity: &Value, now: Instant) -> (i32, i32, i32, i32) {
+    let (x, y) = entity["id"].as_u64()
+        .and_then(|id| interpolator.position(npc, id as PlayerId, now))
+        .map(|drawn| (drawn.x, drawn.y))
+        .unwrap_or((entity["x"].as_f64().unwrap_or(0.0) as f32, entity["y"].as_f64().unwrap_or(0.0) as f32));
+    (x as i32, y as i32, entity["width"].as_i64().unwrap_or(50) as i32, entity["height"].as_i64().unwrap_or(50) as i32)
+}
+
+/// Shows why the server turned us away until the window is closed.
+fn show_rejection(rl: &mut RaylibHandle, thread: &RaylibThread, reason: &str) {
+    eprintln!("Server rejected us: {}", reason);
+    while !rl.window_should_close() {
+        let mut d = rl.begin_drawing(thread);
+        d.clear_background(Color::WHITE);
+        d.draw_text("Could not join the server:", 20, 20, 20, Color::BLACK);
+        d.draw_text(reason, 20, 50, 20, Color::RED);
+    }
+}
+
+/// Runs the game client. `server_addr` overrides the IP / PORT settings, e.g. with the address a
+/// server launched alongside the client actually bound.
+pub fn main(server_addr: Option<SocketAddr>) {
     // Read settings from data.json
     let settings: Value = if std::path::Path::new("data.json").exists() {
         let file = std::fs::File::open("data.json").unwrap();
@@ -31,7 +199,9 @@ pub fn main() {
             "IP": "127.0.0.1",
             "PORT": "5766",
             "PREFERRED_LATENCY": "4",
-            "SKIN": "0"
+            "SKIN": "0",
+            "NAME": "Player",
+            "TLS": "off"
         })
     };
 
@@ -62,61 +232,135 @@ pub fn main() {
 
 
     //init stuff here
-    
-    let mut client = AsyncTcpClient::new(format!("{}:{}", settings["IP"].as_str().unwrap(), settings["PORT"].as_str().unwrap()).as_str());
-    let io_stream = Arc::new(Mutex::new({
-        let mut retry_count = 0;
-        const MAX_RETRIES: u32 = 5;
-        const RETRY_DELAY_MS: u64 = 500;
-
-        loop {
-            match task::block_on(client.connect()) {
-                Ok(stream) => break stream,
-                Err(e) => {
-                    if retry_count >= MAX_RETRIES {
-                        panic!("Failed to connect after {} retries: {}", MAX_RETRIES, e);
-                    }
-                    println!("Connection attempt {} failed, retrying in {}ms...", retry_count + 1, RETRY_DELAY_MS);
-                    task::block_on(async_std::task::sleep(Duration::from_millis(RETRY_DELAY_MS)));
-                    retry_count += 1;
-                }
+    let preferred_latency_ms: u32 = settings["PREFERRED_LATENCY"].as_str()
+        .unwrap_or("40")
+        .parse()
+        .unwrap_or(40);
+    // Remote entities are drawn this far behind the updates we get for them, which
+    // INTERPOLATION_DELAY (in ms) can override
+    let interpolator = match settings["INTERPOLATION_DELAY"].as_str().and_then(|ms| ms.parse().ok()) {
+        Some(ms) => Interpolator::new(Duration::from_millis(ms), MAX_EXTRAPOLATION),
+        None => Interpolator::for_latency(Duration::from_millis(preferred_latency_ms as u64)),
+    };
+    let interpolator = Arc::new(Mutex::new(interpolator));
+
+    let (host, address) = match server_addr {
+        Some(addr) => {
+            // A server bound to every interface is reached over loopback
+            let ip = match addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+            (ip.to_string(), SocketAddr::new(ip, addr.port()).to_string())
+        }
+        None => {
+            let host = settings["IP"].as_str().unwrap().to_string();
+            let address = format!("{}:{}", host, settings["PORT"].as_str().unwrap());
+            (host, address)
+        }
+    };
+    // SIMULATE_NETWORK (e.g. "latency=100,jitter=20,loss=0.05") routes everything through a
+    // local proxy that makes the connection that bad, TCP and UDP alike
+    let network_proxy = settings["SIMULATE_NETWORK"].as_str().map(|spec| {
+        let conditions = NetworkConditions::parse(spec).expect("Invalid SIMULATE_NETWORK setting");
+        let target = std::net::ToSocketAddrs::to_socket_addrs(&address)
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .expect("Could not resolve the server address");
+        let bind = if target.is_ipv6() { "[::1]:0" } else { "127.0.0.1:0" };
+        let proxy = task::block_on(ConditionedProxy::start(bind, target, conditions)).expect("Failed to start the network simulator");
+        println!("Simulating {:?} through {}", conditions, proxy.local_addr());
+        proxy
+    });
+    let address = network_proxy.as_ref().map_or(address, |proxy| proxy.local_addr().to_string());
+    let mut client = AsyncTcpClient::new(&address);
+    let tls_settings = TlsSettings::from_settings(&settings).expect("Invalid TLS settings");
+    if let Some((config, server_name)) = tls_settings.client_config(&host).expect("Failed to set up TLS") {
+        client.set_tls(config, server_name);
+    }
+    let status = client.status();
+    let io_stream = Arc::new(Mutex::new(
+        task::block_on(client.connect_with_retry()).expect("Could not reach the server")
+    ));
+
+    let hello = ClientMessage::Hello {
+        version: protocol::PROTOCOL_VERSION,
+        name: settings["NAME"].as_str().unwrap_or("Player").to_string(),
+        skin: settings["SKIN"].as_str().unwrap_or("0").parse().unwrap_or(0),
+        capabilities: protocol::capabilities(),
+        // Offer every codec unless the settings ask for readable JSON
+        codecs: match settings["CODEC"].as_str() {
+            Some(protocol::DEFAULT_CODEC) => vec![protocol::DEFAULT_CODEC.to_string()],
+            _ => CODECS.iter().map(|c| c.to_string()).collect(),
+        },
+    };
+
+    // Join first so the server hands out our player id
+    let joined = match task::block_on(join_game(&mut io_stream.lock().unwrap(), &hello, None, preferred_latency_ms)) {
+        Ok(joined) => joined,
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            show_rejection(&mut rl, &thread, &e.to_string());
+            return;
+        }
+        Err(e) => panic!("Failed to join server: {}", e),
+    };
+    let resumed_player = joined.player;
+    println!("Joined as player {}", joined.player_id);
+    // Player id and token can change if a reconnect comes too late to resume the session, and
+    // every connection gets its own UDP key
+    let session: Arc<Mutex<(PlayerId, String, Option<String>)>> = Arc::new(Mutex::new((joined.player_id, joined.token, joined.udp_key)));
+
+    // Positions go over UDP when the server offers it; everything else stays on TCP
+    let udp_link = joined.udp_port.and_then(|udp_port| {
+        let mut server_addr = io_stream.lock().unwrap().peer_addr().ok()?;
+        // The network simulator takes datagrams on its own port and passes them on
+        if network_proxy.is_none() {
+            server_addr.set_port(udp_port);
+        }
+        let bind_addr = if server_addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        match task::block_on(AsyncUdpSocket::bind(bind_addr)) {
+            Ok(udp) => Some((udp, server_addr)),
+            Err(e) => {
+                eprintln!("UDP unavailable, sending positions over TCP: {}", e);
+                None
             }
         }
-    }));
-    let sockID: i32 = AsyncTcpClient::get_socket_id(&io_stream.lock().unwrap()) as i32;
+    });
+    let mut udp_bound = false;
+    // Latest admin announcement and when it arrived
+    let mut announcement: Option<(String, Instant)> = None;
+    let mut chat_log = ChatLog::new();
+    let mut prediction = Prediction::new();
+    let mut udp_seq: u32 = 0;
+    // Newest datagram seen per entity, keyed by (is npc, id)
+    let udp_sequences: Arc<Mutex<SequenceFilter<(bool, PlayerId)>>> = Arc::new(Mutex::new(SequenceFilter::new()));
+
+    // Ask for the full world once so the local game starts in sync with the server
+    task::block_on(AsyncTcpClient::send(&mut io_stream.lock().unwrap(), &ClientMessage::GetGame.to_json()))
+        .unwrap_or_else(|e| eprintln!("Send error: {}", e));
 
     // Create message channel for communication between render and network threads
     let (tx, rx) = async_std::channel::bounded(100);
     let tx_clone = tx.clone();
 
-    // Spawn network receive handler
-    let io_stream_clone = Arc::clone(&io_stream);
-    task::spawn(async move {
-        let mut stream = io_stream_clone.lock().unwrap().deref_mut().clone();
-        client.handle_messages(&mut stream, move |msg| {
-            let tx = tx_clone.clone();
-            async move {
-                if let Ok(msg_value) = serde_json::from_str::<Value>(&msg) {
-                    tx.send(msg_value).await.unwrap_or_else(|e| eprintln!("Send error: {}", e));
-                }
-                Ok(())
-            }
-        }).await.unwrap_or_else(|e| eprintln!("Network error: {}", e));
-    });
-
     let mut movement = movement::Movement {
         position: Vector2::new(400.0, 250.0),
-        speed: 5.0,
+        speed: movement::PLAYER_SPEED,
         width: 50,
         height: 50,
     };
+    if let Some(player) = &resumed_player {
+        movement.position.x = player["x"].as_f64().unwrap_or(400.0) as f32;
+        movement.position.y = player["y"].as_f64().unwrap_or(250.0) as f32;
+    }
 
     let mut checklist: Value = json!({
         "x": 400,
         "y": 250,
         "width": 50,
         "height": 50,
-        "id": get_socket_id(&*io_stream.lock().unwrap()),
+        "id": joined.player_id,
         "initGameFully": false,
         "localPlayerSet": false,
         "room": 1,
@@ -143,43 +387,235 @@ pub fn main() {
 
 
 
-    let objects_interpret_inside: Value = json!([
-        0
-    ]);
-    let mut open: bool = rl.window_should_close();
-
-
-    let mut button = Button::new(((window_length as i32) / 2) as f32, ((window_height as i32) / 2) as f32, 100 as f32, 50 as f32, "position");
-    button.set_colors(Color::GRAY, Color::DARKGRAY, Color::LIGHTGRAY, Color::BLACK, Color::BLACK);
-    button.set_font_size(10);
+    // T or Enter opens the chat box, and Enter sends what is in it and closes it again
+    let mut chat_box = TextField::new(10.0, (window_height - 40) as f32, 500.0, 30.0, MAX_CHAT_LENGTH);
+    chat_box.set_colors(Color::WHITE.fade(0.8), Color::DARKGRAY, Color::BLACK);
+    chat_box.set_font_size(18);
+    let latency = client.latency();
     //loop
+    // Spawn network receive handler; this is the only reader on the stream so frames never interleave
     let game_clone = Arc::clone(&game);
-    let io_stream_clone_for_receive = Arc::clone(&io_stream);
-    let receive_thread: thread::JoinHandle<()> = thread::spawn(move || {
-        let io_stream = io_stream_clone_for_receive;
-        while open {
-            let message = task::block_on(AsyncTcpClient::receive(&mut io_stream.lock().unwrap())).unwrap();
-            println!("Received: {}", message);
-            handle_read::handle_readd::handle_read_msg(&message, Arc::clone(&game_clone), &mut io_stream.lock().unwrap());
-        }
+    let io_stream_clone = Arc::clone(&io_stream);
+    let tcp_sequences = Arc::clone(&udp_sequences);
+    let reconnect_session = Arc::clone(&session);
+    // Server snapshots we were sent, for applying deltas; starts out waiting for the first full one
+    let replica = Arc::new(Mutex::new(ClientReplica::new()));
+    let reconnect_replica = Arc::clone(&replica);
+    let reply_stream = Arc::clone(&io_stream);
+    let tcp_interpolator = Arc::clone(&interpolator);
+    // Our own position as the server last confirmed it, from its echo or from a synced snapshot
+    let authoritative: Arc<Mutex<Option<Authoritative>>> = Arc::new(Mutex::new(None));
+    let tcp_authoritative = Arc::clone(&authoritative);
+    let own_session = Arc::clone(&session);
+    // Set when the server turns a reconnect away, so the reason can be shown
+    let rejection: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let reconnect_rejection = Arc::clone(&rejection);
+    // Counts finished reconnects, so the render loop knows to start its UDP channel over
+    let reconnects = Arc::new(AtomicU32::new(0));
+    let reconnect_count = Arc::clone(&reconnects);
+    task::spawn(async move {
+        let stream = io_stream_clone.lock().unwrap().deref_mut().clone();
+        // After a reconnect, resume the session and pull a full snapshot so the local game matches the server again
+        let on_reconnect = move |mut stream: NetStream| {
+            let session = Arc::clone(&reconnect_session);
+            let io_stream = Arc::clone(&io_stream_clone);
+            let rejection = Arc::clone(&reconnect_rejection);
+            let replica = Arc::clone(&reconnect_replica);
+            let reconnects = Arc::clone(&reconnect_count);
+            let hello = hello.clone();
+            async move {
+                let token = session.lock().unwrap().1.clone();
+                let joined = join_game(&mut stream, &hello, Some(token), preferred_latency_ms).await
+                    .inspect_err(|e| {
+                        if e.kind() == ErrorKind::ConnectionRefused {
+                            *rejection.lock().unwrap() = Some(e.to_string());
+                        }
+                    })?;
+                *rejection.lock().unwrap() = None;
+                let previous_id = session.lock().unwrap().0;
+                if joined.player_id != previous_id {
+                    println!("Session expired, rejoined as player {}", joined.player_id);
+                }
+                // Before the new key is visible, so no datagram with it goes out as already bound
+                reconnects.fetch_add(1, Ordering::SeqCst);
+                *session.lock().unwrap() = (joined.player_id, joined.token, joined.udp_key);
+                *io_stream.lock().unwrap() = stream.clone();
+                // The new connection numbers its snapshots from scratch
+                *replica.lock().unwrap() = ClientReplica::new();
+                AsyncTcpClient::send(&mut stream, &ClientMessage::GetGame.to_json()).await
+            }
+        };
+        client.run_with_reconnect(stream, on_reconnect, move |msg| {
+            let tx = tx_clone.clone();
+            println!("Received: {}", msg);
+            let parsed = ServerMessage::from_json(&msg);
+            // Acknowledge every snapshot and delta we could apply, and ask for a full snapshot
+            // when a delta is based on one we never got
+            let mut apply = true;
+            let reply = match &parsed {
+                Ok(ServerMessage::Game { game, seq: Some(seq) }) => {
+                    replica.lock().unwrap().snapshot(*seq, game);
+                    Some(ClientMessage::Ack { seq: *seq })
+                }
+                Ok(ServerMessage::Delta { base, seq, changes }) => match replica.lock().unwrap().delta(*base, *seq, changes) {
+                    DeltaOutcome::Applied => Some(ClientMessage::Ack { seq: *seq }),
+                    DeltaOutcome::Desync => {
+                        println!("Missed the snapshot delta {} is based on, asking for a full one", seq);
+                        apply = false;
+                        Some(ClientMessage::GetGame)
+                    }
+                    DeltaOutcome::Waiting => {
+                        apply = false;
+                        None
+                    }
+                },
+                _ => None,
+            };
+            let own_id = own_session.lock().unwrap().0;
+            if let (Ok(message), true) = (&parsed, apply) {
+                match message {
+                    // Deltas are built on the snapshots we acked, not on the live game with its
+                    // local and UDP edits
+                    ServerMessage::Delta { .. } => {
+                        if let Some(latest) = replica.lock().unwrap().latest() {
+                            replication::adopt_latest(&mut game_clone.lock().unwrap(), latest, own_id);
+                        }
+                    }
+                    message => handle_read::handle_readd::apply_msg(message, &game_clone),
+                }
+                record_remote(&tcp_interpolator, message, &game_clone);
+            }
+            let server_position = match (&parsed, apply) {
+                (Ok(message @ (ServerMessage::UpdatePosition(_) | ServerMessage::RoomUpdate { .. })), _) => own_position(message, own_id),
+                (Ok(ServerMessage::Game { game, .. }), _) => Authoritative::from_game(game, own_id),
+                (Ok(ServerMessage::Delta { .. }), true) => {
+                    replica.lock().unwrap().latest().and_then(|game| Authoritative::from_game(game, own_id))
+                }
+                _ => None,
+            };
+            if let Some(server) = server_position {
+                offer_authoritative(&tcp_authoritative, server);
+            }
+            let mut reply_stream = reply_stream.lock().unwrap().clone();
+            // A player that (re)appears starts counting datagrams from scratch
+            if let Ok(ServerMessage::Player { player: entity } | ServerMessage::Spawn { npc: false, entity, .. }) = &parsed {
+                if let Some(id) = entity["id"].as_u64() {
+                    tcp_sequences.lock().unwrap().forget(&(false, id as PlayerId));
+                }
+            }
+            if let Ok(ServerMessage::PlayerLeft { id }) = &parsed {
+                tcp_sequences.lock().unwrap().forget(&(false, *id));
+            }
+            async move {
+                if let Some(reply) = reply {
+                    AsyncTcpClient::send(&mut reply_stream, &reply.to_json()).await?;
+                }
+                match parsed {
+                    Ok(message) => tx.send(message).await.unwrap_or_else(|e| eprintln!("Send error: {}", e)),
+                    Err(e) => eprintln!("Ignoring message from server: {}", e),
+                }
+                Ok(())
+            }
+        }).await.unwrap_or_else(|e| eprintln!("Lost connection to server: {}", e));
     });
 
+    if let Some((udp, server_addr)) = udp_link.clone() {
+        let game_clone = Arc::clone(&game);
+        let udp_interpolator = Arc::clone(&interpolator);
+        let udp_authoritative = Arc::clone(&authoritative);
+        let udp_session = Arc::clone(&session);
+        // Room updates are numbered by server tick, so late ones are dropped per room
+        let room_sequences: Mutex<SequenceFilter<i32>> = Mutex::new(SequenceFilter::new());
+        task::spawn(async move {
+            udp.run_with_messages(move |datagram, from| {
+                if from == server_addr {
+                    match ServerDatagram::from_json(&datagram) {
+                        Ok(ServerDatagram { seq, message }) => {
+                            let key = match &message {
+                                ServerMessage::UpdatePosition(position) => Some((false, position.id)),
+                                ServerMessage::UpdateNpcPosition(position) => Some((true, position.id)),
+                                _ => None,
+                            };
+                            let accepted = match (&message, key) {
+                                (ServerMessage::RoomUpdate { room, .. }, _) => room_sequences.lock().unwrap().accept(*room, seq),
+                                (_, Some(key)) => udp_sequences.lock().unwrap().accept(key, seq),
+                                // Anything that is not a position update has no business arriving unreliably
+                                _ => false,
+                            };
+                            if accepted {
+                                handle_read::handle_readd::apply_msg(&message, &game_clone);
+                                record_remote(&udp_interpolator, &message, &game_clone);
+                                if let Some(server) = own_position(&message, udp_session.lock().unwrap().0) {
+                                    offer_authoritative(&udp_authoritative, server);
+                                }
+                            }
+                        }
+                        Err(e) => eprintln!("Ignoring datagram from server: {}", e),
+                    }
+                }
+                async {}
+            }).await.unwrap_or_else(|e| eprintln!("UDP error: {}", e));
+        });
+    }
+
+    let mut seen_reconnects = 0;
     while !rl.window_should_close() {
-        button.update(&mut rl);
-        if button.is_clicked(&mut rl) {
-            movement.position.x = 400.0;
-            movement.position.y = 250.0;
+        let (player_id, _, udp_key) = session.lock().unwrap().clone();
+        // The server binds UDP per connection and counts its datagrams from scratch, so positions
+        // go over TCP again until it confirms the new one. Inputs the old connection never
+        // delivered are not replayed either
+        let reconnects_now = reconnects.load(Ordering::SeqCst);
+        if reconnects_now != seen_reconnects {
+            seen_reconnects = reconnects_now;
+            udp_bound = false;
+            udp_seq = 0;
+            prediction.clear();
         }
-        movement.update(rl.get_frame_time());
+        let connection = status.get();
+        // Move straight away, and put right what the server saw differently
+        if let Some(server) = authoritative.lock().unwrap().take() {
+            let game_lock = game.lock().unwrap();
+            let objects = game_lock[whole_room_in.clone()]["objects"].as_array().map_or(&[][..], Vec::as_slice);
+            prediction.reconcile(&mut movement, server, objects);
+        }
+        // Checked before the chat box takes this frame's keys
+        let enter = rl.is_key_pressed(KeyboardKey::KEY_ENTER);
+        if !chat_box.is_active() {
+            if enter || rl.is_key_pressed(KeyboardKey::KEY_T) {
+                chat_box.activate();
+            }
+        } else if enter {
+            let line = chat_box.get_text().trim().to_string();
+            if !line.is_empty() {
+                match chat::parse_input(&line) {
+                    Ok(message) => task::block_on(AsyncTcpClient::send(&mut io_stream.lock().unwrap(), &message.to_json()))
+                        .unwrap_or_else(|e| eprintln!("Send error: {}", e)),
+                    Err(e) => chat_log.push_notice(&e),
+                }
+            }
+            chat_box.set_value("");
+            chat_box.deactivate();
+        } else {
+            chat_box.update(&mut rl);
+        }
+        chat_log.scroll(rl.get_mouse_wheel_move() as i32);
+        // Typing into the chat box does not move the player
+        let input = if chat_box.is_active() {
+            MovementInput { delta_time: rl.get_frame_time(), ..Default::default() }
+        } else {
+            MovementInput::read(rl.get_frame_time())
+        };
+        movement.apply(&input);
+        let input_seq = prediction.record(input);
         {
             let mut game_lock = game.lock().unwrap();
             let player_data = json!({
-                "id": "player1",
+                "id": player_id,
                 "x": movement.position.x,
                 "y": movement.position.y
             });
             if let Some(players) = game_lock[whole_room_in.clone()]["players"].as_array_mut() {
-                if let Some(player) = players.iter_mut().find(|p| p["id"] == "player1") {
+                if let Some(player) = players.iter_mut().find(|p| p["id"] == player_id) {
                     *player = player_data;
                 } else {
                     players.push(player_data);
@@ -189,19 +625,27 @@ pub fn main() {
         let mut d: RaylibDrawHandle<'_> = rl.begin_drawing(&thread);
         //get collisions
         if let Some(objects) = game.lock().unwrap()[whole_room_in.clone()]["objects"].as_array() {
-            for object in objects {
-                if objects_interpret_inside.as_array().unwrap().contains(&object["id"]) {
-                    //treat like inside object
-                    collision::reverse_do_get_collision(&mut movement, &mut object.clone());
-                } else {
-                    //treat like outside object
-                    collision::do_get_collision(&mut movement, &mut object.clone());
-                }
-            }
+            collision::collide_with_room(&mut movement, objects);
         }
         //drawing code seperate line here
         d.clear_background(Color::WHITE);
         d.draw_rectangle(1, 1, 1000, 1000, Color::GRAY);
+        // Draw everyone else in the room, smoothed out between the updates we got for them
+        let now = Instant::now();
+        let mut smoothing = interpolator.lock().unwrap();
+        if let Some(players) = game.lock().unwrap()[whole_room_in.clone()]["players"].as_array() {
+            for player in players.iter().filter(|p| p["id"] != player_id) {
+                let (x, y, width, height) = remote_rect(&mut smoothing, false, player, now);
+                d.draw_rectangle(x, y, width, height, Color::BLUE);
+            }
+        }
+        if let Some(npcs) = game.lock().unwrap()[whole_room_in.clone()]["npcs"].as_array() {
+            for npc in npcs {
+                let (x, y, width, height) = remote_rect(&mut smoothing, true, npc, now);
+                d.draw_rectangle(x, y, width, height, Color::DARKGREEN);
+            }
+        }
+        drop(smoothing);
         d.draw_rectangle(
             movement.position.x as i32,
             movement.position.y as i32,
@@ -209,32 +653,86 @@ pub fn main() {
             movement.height,
             Color::RED,
         );
-        button.draw(&mut d);
+        match latency.rtt() {
+            Some(rtt) => {
+                let ms = rtt.as_millis() as u32;
+                let color = if ms > preferred_latency_ms { Color::RED } else { Color::BLACK };
+                d.draw_text(&format!("Ping: {} ms", ms), 10, 10, 20, color);
+            }
+            None => d.draw_text("Ping: --", 10, 10, 20, Color::BLACK),
+        }
+        match connection {
+            ConnectionState::Connected => {}
+            ConnectionState::Reconnecting { attempt } => {
+                d.draw_text(&format!("Reconnecting (attempt {})...", attempt), 10, 35, 20, Color::ORANGE);
+            }
+            ConnectionState::Lost => d.draw_text("Connection lost", 10, 35, 20, Color::RED),
+            ConnectionState::Closed => d.draw_text("Server closed", 10, 35, 20, Color::RED),
+        }
+        if let Some(reason) = rejection.lock().unwrap().as_ref() {
+            d.draw_text(reason, 10, 60, 20, Color::RED);
+        }
+        if let Some((text, since)) = &announcement {
+            if since.elapsed() < ANNOUNCEMENT_TIME {
+                d.draw_text(&format!("Server: {}", text), 10, 85, 20, Color::DARKBLUE);
+            }
+        }
+        let chat_top = window_height - 45 - CHAT_VISIBLE_LINES as i32 * 20;
+        for (i, line) in chat_log.visible(CHAT_VISIBLE_LINES).iter().enumerate() {
+            let color = match line.scope {
+                Some(ChatScope::Room) => Color::BLACK,
+                Some(ChatScope::Global) => Color::DARKBLUE,
+                Some(ChatScope::Whisper) => Color::PURPLE,
+                None => Color::MAROON,
+            };
+            d.draw_text(&line.text, 10, chat_top + i as i32 * 20, 18, color);
+        }
+        if chat_box.is_active() {
+            chat_box.draw(&mut d);
+        }
 
         // Send position updates
-        let update_msg = json!({
-            "type": "update",
-            "player_id": sockID.to_string(),
-            "data": {
-                "x": movement.position.x,
-                "y": movement.position.y
-            }
+        let update_msg = ClientMessage::UpdatePosition(PositionUpdate {
+            room: room_in,
+            x: movement.position.x,
+            y: movement.position.y,
+            width: Some(movement.width),
+            height: Some(movement.height),
+            sprite_state: None,
+            input: Some(input_seq),
         });
 
-        task::block_on(AsyncTcpClient::send(&mut io_stream.lock().unwrap(), &update_msg.to_string())).unwrap_or_else(|e| eprintln!("Send error: {}", e));
+        // Until the server confirms it gets our datagrams, send over TCP as well
+        if let (Some((udp, server_addr)), Some(key)) = (&udp_link, udp_key) {
+            udp_seq = udp_seq.wrapping_add(1);
+            let datagram = ClientDatagram { key, seq: udp_seq, message: update_msg.clone() };
+            task::block_on(udp.send_to(&datagram.to_json(), *server_addr)).unwrap_or_else(|e| eprintln!("UDP send error: {}", e));
+        }
+        if !udp_bound && connection == ConnectionState::Connected {
+            task::block_on(AsyncTcpClient::send(&mut io_stream.lock().unwrap(), &update_msg.to_json())).unwrap_or_else(|e| eprintln!("Send error: {}", e));
+        }
 
         // Process received messages
         while let Ok(msg) = rx.try_recv() {
-            match msg["type"].as_str() {
-                Some("update_confirm") => {
-                    // Handle confirmation
-                },
-                Some(msg_type) => println!("Received message type: {}", msg_type),
-                None => println!("Received invalid message format")
+            match msg {
+                ServerMessage::Error { reason } => {
+                    println!("Server rejected a message: {}", reason);
+                    chat_log.push_notice(&reason);
+                }
+                ServerMessage::UdpBound => udp_bound = true,
+                // Kicked or banned; the connection closes right after
+                ServerMessage::Rejected { reason } => *rejection.lock().unwrap() = Some(reason),
+                ServerMessage::Announcement { text } => {
+                    chat_log.push_notice(&format!("Server: {}", text));
+                    announcement = Some((text, Instant::now()));
+                }
+                ServerMessage::Chat { scope, from, name, to, text } => chat_log.push_chat(player_id, scope, from, &name, to, &text),
+                _ => {}
             }
         }
     }
-    //closing sequence
-    open = rl.window_should_close();
-    receive_thread.join().unwrap();
+
+    if let Some(proxy) = &network_proxy {
+        proxy.stop();
+    }
 }
\ No newline at end of file
@@ -75,6 +75,23 @@ fn push_inward(player: &mut Movement, objectrect: &Rectangle, wall: i32) {
     }
 }
 
+/// Ids of objects players stay inside of, like the floor square, instead of being kept out.
+pub const INSIDE_OBJECTS: [i64; 1] = [0];
+
+/// Applies every object in a room to the player. Client and server both use this, so they agree
+/// on where a move ends up.
+pub fn collide_with_room(player: &mut Movement, objects: &[serde_json::Value]) {
+    for object in objects {
+        if object["id"].as_i64().is_some_and(|id| INSIDE_OBJECTS.contains(&id)) {
+            //treat like inside object
+            reverse_do_get_collision(player, &mut object.clone());
+        } else {
+            //treat like outside object
+            do_get_collision(player, &mut object.clone());
+        }
+    }
+}
+
 pub fn do_get_collision(player: &mut Movement, object: &mut serde_json::Value) {
     let objectrect = Rectangle {
         x: object["x"].as_f64().unwrap_or(0.0) as f32,
@@ -0,0 +1,185 @@
+use crate::networking::{AsyncUdpSocket, ShutdownHandle};
+use crate::protocol::PROTOCOL_VERSION;
+use async_std::future::timeout;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+// LAN discovery. The client sends a probe datagram to the broadcast address and to a multicast
+// group on DISCOVERY_PORT; every server listening there answers with a ServerInfo, and the
+// address the answer came from tells the client where to connect.
+
+/// Port servers listen on for probes, next to the default game port 5766, unless the
+/// DISCOVERY_PORT setting says otherwise.
+pub const DISCOVERY_PORT: u16 = 5767;
+/// Administratively scoped group, so probes never leave the site.
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 57, 67);
+/// Payload of a probe. Anything else arriving on the discovery port is ignored.
+pub const DISCOVERY_PROBE: &str = "rust_sandbox_discover";
+
+/// What a server says about itself in answer to a probe.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: u32,
+    pub players: usize,
+    pub rooms: Vec<String>,
+    /// TCP port the game itself is on.
+    pub port: u16,
+}
+
+impl ServerInfo {
+    /// Describes a server from its game state, counting the players in every room.
+    pub fn from_game(name: &str, game: &serde_json::Value, port: u16) -> Self {
+        let rooms = game.as_object().map(|rooms| rooms.keys().cloned().collect()).unwrap_or_default();
+        let players = game.as_object()
+            .map(|rooms| rooms.values().filter_map(|room| room["players"].as_array()).map(Vec::len).sum())
+            .unwrap_or(0);
+        ServerInfo { name: name.to_string(), version: PROTOCOL_VERSION, players, rooms, port }
+    }
+}
+
+/// A server that answered a probe, and the address to join it on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    pub addr: SocketAddr,
+    pub info: ServerInfo,
+}
+
+/// The discovery port from the DISCOVERY_PORT setting, or DISCOVERY_PORT. The server and the
+/// settings screen both read it through here.
+pub fn port_setting(settings: &serde_json::Value) -> u16 {
+    settings["DISCOVERY_PORT"].as_str()
+        .and_then(|port| port.trim().parse().ok())
+        .unwrap_or(DISCOVERY_PORT)
+}
+
+/// Where `discover` sends probes by default: the local broadcast address and the discovery
+/// group, on `port`.
+pub fn default_targets(port: u16) -> Vec<SocketAddr> {
+    vec![
+        SocketAddrV4::new(Ipv4Addr::BROADCAST, port).into(),
+        SocketAddrV4::new(DISCOVERY_GROUP, port).into(),
+    ]
+}
+
+/// Binds the socket a server answers probes on and joins the discovery group. Joining can fail
+/// on hosts without multicast, in which case the server still answers broadcasts.
+pub async fn bind_responder(address: &str) -> async_std::io::Result<AsyncUdpSocket> {
+    let socket = AsyncUdpSocket::bind(address).await?;
+    if let Err(e) = socket.join_multicast_v4(DISCOVERY_GROUP, Ipv4Addr::UNSPECIFIED) {
+        eprintln!("Discovery is broadcast only, could not join {}: {}", DISCOVERY_GROUP, e);
+    }
+    Ok(socket)
+}
+
+/// Answers every probe that reaches `socket` with whatever `info` returns at that moment, until
+/// `shutdown` is called.
+pub async fn respond<F>(socket: AsyncUdpSocket, shutdown: ShutdownHandle, info: F) -> async_std::io::Result<()>
+where
+    F: Fn() -> ServerInfo + Send + Sync + 'static,
+{
+    let replies = socket.clone();
+    socket.run_until_shutdown(&shutdown, move |message, from| {
+        let reply = (message == DISCOVERY_PROBE)
+            .then(|| serde_json::to_string(&info()).expect("ServerInfo always serializes"));
+        let replies = replies.clone();
+        async move {
+            if let Some(reply) = reply {
+                if let Err(e) = replies.send_to(&reply, from).await {
+                    eprintln!("Failed to answer discovery probe from {}: {}", from, e);
+                }
+            }
+        }
+    }).await
+}
+
+/// Probes `targets` and collects the servers that answer within `wait`. A server reached
+/// through more than one target is listed once.
+pub async fn discover(targets: &[SocketAddr], wait: Duration) -> async_std::io::Result<Vec<DiscoveredServer>> {
+    let socket = AsyncUdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    let mut sent = false;
+    for target in targets {
+        match socket.send_to(DISCOVERY_PROBE, *target).await {
+            Ok(()) => sent = true,
+            Err(e) => eprintln!("Could not probe {}: {}", target, e),
+        }
+    }
+    if !sent {
+        return Ok(Vec::new());
+    }
+
+    let mut found: HashMap<SocketAddr, ServerInfo> = HashMap::new();
+    let deadline = Instant::now() + wait;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        match timeout(left, socket.recv_from()).await {
+            Ok(Ok((reply, from))) => match serde_json::from_str::<ServerInfo>(&reply) {
+                Ok(info) => {
+                    found.insert(SocketAddr::new(from.ip(), info.port), info);
+                }
+                Err(e) => eprintln!("Ignoring bad discovery reply from {}: {}", from, e),
+            },
+            Ok(Err(e)) => eprintln!("Ignoring discovery reply: {}", e),
+            Err(_) => break, // Waited long enough
+        }
+    }
+
+    let mut servers: Vec<DiscoveredServer> = found.into_iter()
+        .map(|(addr, info)| DiscoveredServer { addr, info })
+        .collect();
+    servers.sort_by(|a, b| a.info.name.cmp(&b.info.name).then(a.addr.cmp(&b.addr)));
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use serde_json::json;
+
+    #[async_std::test]
+    async fn test_discovery_finds_a_server_once() -> async_std::io::Result<()> {
+        let responder = bind_responder("0.0.0.0:0").await?;
+        let port = responder.local_addr()?.port();
+        let game = json!({
+            "room1": {"players": [{"id": 1}, {"id": 2}]},
+            "room2": {"players": []},
+        });
+        let shutdown = ShutdownHandle::new();
+        let responding = task::spawn(respond(responder, shutdown.clone(), move || ServerInfo::from_game("Test server", &game, 5766)));
+
+        // The same responder, probed over loopback and through the discovery group
+        let targets = [
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            SocketAddr::from((DISCOVERY_GROUP, port)),
+        ];
+        let servers = discover(&targets, Duration::from_millis(300)).await?;
+        let loopback: Vec<_> = servers.iter().filter(|s| s.addr.ip().is_loopback()).collect();
+        assert_eq!(loopback.len(), 1);
+        assert_eq!(loopback[0].addr, SocketAddr::from((Ipv4Addr::LOCALHOST, 5766)));
+        assert_eq!(loopback[0].info, ServerInfo {
+            name: "Test server".to_string(),
+            version: PROTOCOL_VERSION,
+            players: 2,
+            rooms: vec!["room1".to_string(), "room2".to_string()],
+            port: 5766,
+        });
+
+        shutdown.shutdown();
+        responding.await
+    }
+
+    #[test]
+    fn test_port_setting() {
+        assert_eq!(port_setting(&json!({"DISCOVERY_PORT": "6001"})), 6001);
+        assert_eq!(port_setting(&json!({"DISCOVERY_PORT": "nope"})), DISCOVERY_PORT);
+        assert_eq!(port_setting(&json!({})), DISCOVERY_PORT);
+        assert!(default_targets(6001).iter().all(|target| target.port() == 6001));
+    }
+}
@@ -1,142 +1,194 @@
 use serde_json::Value;
 use serde_json::json;
-use crate::networking::{AsyncTcpClient, AsyncTcpServer, ClientConnections};
-use crate::randommods::*;
+use crate::networking::{codec_by_name, AsyncTcpServer, ClientConnections, NetStream};
+use crate::protocol::{self, capability, ChatScope, ClientDatagram, ClientMessage, EntityPosition, PlayerId, PositionUpdate, ProtocolError, ServerMessage};
+use crate::session::{PlayerProfile, Sessions};
+use crate::players::Players;
+use crate::replication;
+use crate::validation::{MotionCheck, Verdict, FLAG_WINDOW};
+use crate::simulation::QueuedInput;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use async_std::task;
-use async_std::net::TcpStream;
+use async_std::net::SocketAddr;
 
 pub struct handle_readd;
 
+/// The `roomID` of the room players join on their first position update.
+pub const SPAWN_ROOM: i32 = 1;
+/// Where players join a room without a `spawn` of its own.
+const DEFAULT_SPAWN: (f32, f32) = (400.0, 250.0);
+
+/// Where new players are placed in a room: its `spawn` (`{"x":...,"y":...}`), if it has one.
+fn spawn_point(room: &Value) -> (f32, f32) {
+    match (room["spawn"]["x"].as_f64(), room["spawn"]["y"].as_f64()) {
+        (Some(x), Some(y)) => (x as f32, y as f32),
+        _ => DEFAULT_SPAWN,
+    }
+}
+
+/// Copies the fields present in `position` onto an entity in the game state.
+fn apply_position(entity: &mut Value, position: &EntityPosition) {
+    entity["x"] = json!(position.x);
+    entity["y"] = json!(position.y);
+    if let Some(width) = position.width {
+        entity["width"] = json!(width);
+    }
+    if let Some(height) = position.height {
+        entity["height"] = json!(height);
+    }
+    if let Some(sprite_state) = position.sprite_state {
+        entity["sprite_state"] = json!(sprite_state);
+    }
+    if let Some(input) = position.input {
+        entity["input"] = json!(input);
+    }
+}
+
+/// Removes a player from whichever room lists it. Returns that room's `roomID` and the entity.
+fn remove_player(game: &mut Value, id: PlayerId) -> Option<(i32, Value)> {
+    let mut removed = None;
+    if let Value::Object(rooms) = game {
+        for (_, room) in rooms.iter_mut() {
+            let room_id = room["roomID"].as_i64().unwrap_or(0) as i32;
+            if let Some(players) = room.get_mut("players").and_then(|p| p.as_array_mut()) {
+                if let Some(index) = players.iter().position(|p| p["id"] == id) {
+                    removed = Some((room_id, players.remove(index)));
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// Puts a player entity back into the room with the given `roomID`.
+fn restore_player(game: &mut Value, room_id: i32, entity: Value) -> bool {
+    if let Value::Object(rooms) = game {
+        for (_, room) in rooms.iter_mut() {
+            if room["roomID"].as_i64() == Some(room_id as i64) {
+                if let Some(players) = room.get_mut("players").and_then(|p| p.as_array_mut()) {
+                    players.push(entity);
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
 impl handle_readd {
-    fn get_game_handler(game: &mut Value, message_json: &String) -> Value {
-        // Update the game state
-        let json_value: Value = serde_json::from_str(message_json).unwrap_or_else(|_| Value::Null);
-        *game = json_value
-            .as_object()
-            .and_then(|obj| obj.get("get_game").cloned())
-            .unwrap_or_else(|| Value::Null);
-        game.clone()
+    fn get_game_handler(game: &mut Value, snapshot: &Value) {
+        // Replace the whole game state with the server's snapshot
+        *game = snapshot.clone();
     }
 
-    fn get_player_handler(game: &mut Value, message_json: &Value) {
+    fn get_player_handler(game: &mut Value, new_player: &Value) {
         // Locate the correct room and check/add the player
         if let Value::Object(rooms) = game {
             for (_, room) in rooms.iter_mut() {
                 if let Some(players) = room.get_mut("players").and_then(|p| p.as_array_mut()) {
-                    let player_id = message_json["get_player"]["id"].clone();
+                    let player_id = new_player["id"].clone();
                     let mut player_set = false;
 
                     for player in players.iter_mut() {
                         if player["id"] == player_id {
-                            *player = message_json["get_player"].clone();
+                            *player = new_player.clone();
                             player_set = true;
                             break;
                         }
                     }
 
                     if !player_set {
-                        players.push(message_json["get_player"].clone());
+                        players.push(new_player.clone());
                     }
                 }
             }
         }
     }
 
-    fn update_position(game: &mut Value, message_json: &Value) {
-        // Locate the correct room and update player position
+    fn update_entity_position(game: &mut Value, list: &str, position: &EntityPosition) {
+        // Locate the correct room and update the entity's position
         if let Value::Object(rooms) = game {
             for (_, room) in rooms.iter_mut() {
-                if let Some(players) = room.get_mut("players").and_then(|p| p.as_array_mut()) {
-                    let player_id = message_json["update_position"]["id"].clone();
-
-                    for player in players.iter_mut() {
-                        if player["id"] == player_id {
-                            // Update position-related fields
-                            if let Some(x) = message_json["update_position"].get("x") {
-                                player["x"] = x.clone();
-                            }
-                            if let Some(y) = message_json["update_position"].get("y") {
-                                player["y"] = y.clone();
-                            }
-                            if let Some(width) = message_json["update_position"].get("width") {
-                                player["width"] = width.clone();
-                            }
-                            if let Some(height) = message_json["update_position"].get("height") {
-                                player["height"] = height.clone();
-                            }
-                            if let Some(sprite_state) = message_json["update_position"].get("sprite_state") {
-                                player["sprite_state"] = sprite_state.clone();
-                            }
-                            break;
-                        }
+                if let Some(entities) = room.get_mut(list).and_then(|p| p.as_array_mut()) {
+                    if let Some(entity) = entities.iter_mut().find(|e| e["id"] == position.id) {
+                        apply_position(entity, position);
+                        break;
                     }
                 }
             }
         }
     }
 
-    fn update_npc_position(game: &mut Value, message_json: &Value) {
-        // Locate the correct room and update NPC position
-        if let Value::Object(rooms) = game {
-            for (_, room) in rooms.iter_mut() {
-                if let Some(npcs) = room.get_mut("npcs").and_then(|p| p.as_array_mut()) {
-                    let npc_id = message_json["update_npc_position"]["id"].clone();
-
-                    for npc in npcs.iter_mut() {
-                        if npc["id"] == npc_id {
-                            // Update position-related fields
-                            if let Some(x) = message_json["update_npc_position"].get("x") {
-                                npc["x"] = x.clone();
-                            }
-                            if let Some(y) = message_json["update_npc_position"].get("y") {
-                                npc["y"] = y.clone();
-                            }
-                            if let Some(width) = message_json["update_npc_position"].get("width") {
-                                npc["width"] = width.clone();
-                            }
-                            if let Some(height) = message_json["update_npc_position"].get("height") {
-                                npc["height"] = height.clone();
-                            }
-                            if let Some(sprite_state) = message_json["update_npc_position"].get("sprite_state") {
-                                npc["sprite_state"] = sprite_state.clone();
-                            }
-                            break;
-                        }
-                    }
-                }
+    /// Adds or replaces an entity that came into view in the room with the given `roomID`.
+    fn spawn(game: &mut Value, room_id: i32, npc: bool, entity: &Value) {
+        if let Some(entities) = handle_readd::room_list(game, room_id, npc) {
+            match entities.iter_mut().find(|e| e["id"] == entity["id"]) {
+                Some(existing) => *existing = entity.clone(),
+                None => entities.push(entity.clone()),
             }
         }
     }
 
-    // Main checking
-    pub fn handle_read_msg(message: &String, game: Arc<Mutex<Value>>, stream: &mut TcpStream) {
-        // Parse the message
-        let message_json: Value = serde_json::from_str(message).unwrap_or_else(|_| Value::Null);
+    fn despawn(game: &mut Value, room_id: i32, npc: bool, id: PlayerId) {
+        if let Some(entities) = handle_readd::room_list(game, room_id, npc) {
+            entities.retain(|e| e["id"] != id);
+        }
+    }
 
-        // Lock the game state
-        let mut game = game.lock().unwrap();
+    /// The "players" or "npcs" list of the room with the given `roomID`.
+    fn room_list(game: &mut Value, room_id: i32, npc: bool) -> Option<&mut Vec<Value>> {
+        game.as_object_mut()?
+            .values_mut()
+            .find(|room| room["roomID"].as_i64() == Some(room_id as i64))?
+            .get_mut(if npc { "npcs" } else { "players" })?
+            .as_array_mut()
+    }
 
-        // Check the message type and dispatch to handlers
-        if json_contains(&message_json, "get_game") {
-            *game = handle_readd::get_game_handler(&mut *game, message);
-        }
+    fn player_left(game: &mut Value, id: PlayerId) {
+        remove_player(game, id);
+    }
 
-        if json_contains(&message_json, "get_player") {
-            handle_readd::get_player_handler(&mut *game, &message_json);
-        }
+    fn update_position(game: &mut Value, position: &EntityPosition) {
+        handle_readd::update_entity_position(game, "players", position);
+    }
 
-        if json_contains(&message_json, "update_position") {
-            handle_readd::update_position(&mut *game, &message_json);
-        }
+    fn update_npc_position(game: &mut Value, position: &EntityPosition) {
+        handle_readd::update_entity_position(game, "npcs", position);
+    }
 
-        if json_contains(&message_json, "update_npc_position") {
-            handle_readd::update_npc_position(&mut *game, &message_json);
-        }
+    /// Applies one server message to the local game state and hands it back for the render loop.
+    pub fn handle_read_msg(message: &String, game: Arc<Mutex<Value>>) -> Result<ServerMessage, ProtocolError> {
+        let message = ServerMessage::from_json(message)?;
+        handle_readd::apply_msg(&message, &game);
+        Ok(message)
+    }
+
+    /// Applies a message that has already been decoded, e.g. one that came in a datagram.
+    pub fn apply_msg(message: &ServerMessage, game: &Arc<Mutex<Value>>) {
+        // Lock the game state
+        let mut game = game.lock().unwrap();
 
-        // Send a response if needed
-        let response = json!({"status": "ok"});
-        task::block_on(AsyncTcpClient::send(stream, &response.to_string())).unwrap_or_else(|e| eprintln!("Send error: {}", e));
+        match message {
+            ServerMessage::Game { game: snapshot, .. } => handle_readd::get_game_handler(&mut game, snapshot),
+            ServerMessage::Delta { changes, .. } => replication::apply(&mut game, changes),
+            ServerMessage::Player { player } => handle_readd::get_player_handler(&mut game, player),
+            ServerMessage::UpdatePosition(position) => handle_readd::update_position(&mut game, position),
+            ServerMessage::UpdateNpcPosition(position) => handle_readd::update_npc_position(&mut game, position),
+            ServerMessage::RoomUpdate { players, npcs, .. } => {
+                players.iter().for_each(|position| handle_readd::update_position(&mut game, position));
+                npcs.iter().for_each(|position| handle_readd::update_npc_position(&mut game, position));
+            }
+            ServerMessage::PlayerLeft { id } => handle_readd::player_left(&mut game, *id),
+            ServerMessage::Spawn { room, npc, entity } => handle_readd::spawn(&mut game, *room, *npc, entity),
+            ServerMessage::Despawn { room, npc, id } => handle_readd::despawn(&mut game, *room, *npc, *id),
+            ServerMessage::Error { reason } => eprintln!("Server reported an error: {}", reason),
+            ServerMessage::Rejected { reason } => eprintln!("Server rejected us: {}", reason),
+            ServerMessage::Announcement { text } => println!("Server: {}", text),
+            // Chat only goes to the overlay
+            ServerMessage::Chat { .. } => {}
+            ServerMessage::Welcome { .. } | ServerMessage::Joined { .. } | ServerMessage::UdpBound => {}
+        }
     }
 }
 
@@ -158,55 +210,301 @@ what client sends server some parts are usually missing:
     });
 */
 
-pub fn handle_read_server(message: &String, game: Arc<Mutex<Value>>, client_id: u32, clients: &mut ClientConnections) {
-    let message_json: Value = serde_json::from_str(message).unwrap_or_else(|_| Value::Null);
-    
+/// Reads an entity's position fields back out of the game state.
+pub fn entity_position(id: PlayerId, entity: &Value) -> EntityPosition {
+    EntityPosition {
+        id,
+        x: entity["x"].as_f64().unwrap_or(0.0) as f32,
+        y: entity["y"].as_f64().unwrap_or(0.0) as f32,
+        width: entity["width"].as_i64().map(|w| w as i32),
+        height: entity["height"].as_i64().map(|h| h as i32),
+        sprite_state: entity["sprite_state"].as_i64().map(|s| s as i32),
+        input: entity["input"].as_u64().map(|i| i as u32),
+    }
+}
+
+/// Updates (or first places, moving from the spawn point of SPAWN_ROOM) the sending client's
+/// player, at the position `motion` says it could really have reached. Updates for any room other
+/// than the one the player is in are refused. The size always comes from the movement rules, not the client.
+/// Returns the player entity, whether it was just added to a room, and the verdict on the move.
+fn update_player_position(game: &mut Value, client_id: PlayerId, update: &PositionUpdate, motion: &mut MotionCheck, now: Instant) -> Result<(Value, bool, Verdict), String> {
+    let game_obj = match game.as_object_mut() {
+        Some(game_obj) => game_obj,
+        None => return Err("invalid game state".to_string()),
+    };
+
+    // find client based on id, or put it at the spawn point of the spawn room on its first update
+    let current_room = game_obj.iter()
+        .find(|(_, room)| room["players"].as_array().is_some_and(|players| players.iter().any(|p| p["id"] == client_id)))
+        .map(|(key, _)| key.clone());
+    let room_key = current_room
+        .or_else(|| game_obj.iter().find(|(_, room)| room["roomID"].as_i64() == Some(SPAWN_ROOM as i64)).map(|(key, _)| key.clone()))
+        .ok_or_else(|| format!("no such room: {}", SPAWN_ROOM))?;
+    let room = game_obj.get_mut(&room_key).ok_or_else(|| format!("no such room: {}", update.room))?;
+    // Nothing moves players between rooms yet, so the only room a player may be in is its own
+    let room_id = room["roomID"].as_i64().unwrap_or(0) as i32;
+    if update.room != room_id {
+        return Err(format!("cannot move to room {} from room {}", update.room, room_id));
+    }
+    let spawn = spawn_point(room);
+    let objects = room["objects"].as_array().cloned().unwrap_or_default();
+    let players = room.get_mut("players")
+        .and_then(|p| p.as_array_mut())
+        .ok_or_else(|| format!("no such room: {}", update.room))?;
+    let existing = players.iter().position(|p| p["id"] == client_id);
+    // A new player moves from the spawn point like any other move, so it cannot join anywhere
+    let from = existing.map_or(spawn, |index| {
+        let player = &players[index];
+        (player["x"].as_f64().unwrap_or(0.0) as f32, player["y"].as_f64().unwrap_or(0.0) as f32)
+    });
+
+    let ((x, y), verdict) = motion.check(Some(from), (update.x, update.y), (update.width, update.height), &objects, now);
+    let position = EntityPosition {
+        id: client_id,
+        x,
+        y,
+        width: Some(motion.rules().width),
+        height: Some(motion.rules().height),
+        sprite_state: update.sprite_state,
+        input: update.input,
+    };
+    match existing {
+        Some(index) => {
+            apply_position(&mut players[index], &position);
+            Ok((players[index].clone(), false, verdict))
+        }
+        None => {
+            let mut player = json!({"id": client_id});
+            apply_position(&mut player, &position);
+            players.push(player.clone());
+            Ok((player, true, verdict))
+        }
+    }
+}
+
+/// Applies one queued move during a server tick. Returns the player entity and the `roomID` of the
+/// room it is in.
+pub fn apply_input_server(game: &mut Value, players: &mut Players, input: &QueuedInput) -> Result<(Value, i32), String> {
+    let motion = players.motion(input.player).ok_or_else(|| "not connected".to_string())?;
+    let (player, _, verdict) = update_player_position(game, input.player, &input.update, motion, input.received)?;
+    report_verdict(input.player, verdict, motion);
+    let room_id = ClientConnections::room_of(game, input.player).unwrap_or(input.update.room);
+    Ok((player, room_id))
+}
+
+/// Logs a player once it has been caught moving impossibly too often.
+fn report_verdict(client_id: PlayerId, verdict: Verdict, motion: &MotionCheck) {
+    if verdict == Verdict::Flagged {
+        println!("Player {} flagged for impossible movement ({} corrections in {}s)", client_id, motion.recent_violations(), FLAG_WINDOW.as_secs());
+    }
+}
+
+pub fn handle_read_server(message: Result<ClientMessage, ProtocolError>, game: Arc<Mutex<Value>>, client_id: u32, sessions: &Sessions, clients: &mut ClientConnections, players: &mut Players) {
+    if clients.get_client(client_id).is_none() {
+        return;
+    }
+
+    let response = match message {
+        Ok(ClientMessage::Hello { .. }) | Ok(ClientMessage::Join { .. }) => ServerMessage::Error { reason: "already joined".to_string() },
+        // A full snapshot, which deltas are based on once the client acknowledges it
+        Ok(ClientMessage::GetGame) => {
+            // Only what the client can see
+            let view = players.view(client_id, &clients.lock_game(Some(client_id), &game));
+            match players.replica(client_id) {
+                Some(replica) => replica.full(&view),
+                None => ServerMessage::Game { game: view, seq: None },
+            }
+        }
+        Ok(ClientMessage::Ack { seq }) => {
+            if let Some(replica) = players.replica(client_id) {
+                replica.ack(seq);
+            }
+            return;
+        }
+        // Moves are applied on the next server tick, together with everyone else's
+        Ok(ClientMessage::UpdatePosition(update)) => {
+            players.queue_input(QueuedInput { player: client_id, update, received: Instant::now() });
+            return;
+        }
+        // Everyone it reaches gets the same message, the sender included, so there is no reply
+        Ok(ClientMessage::Chat { scope, to, text }) => match players.chat_filter().check(client_id, &text, Instant::now())
+            .and_then(|text| handle_chat_server(client_id, scope, to, &text, &game, sessions, clients)) {
+            Ok(()) => return,
+            Err(reason) => ServerMessage::Error { reason },
+        },
+        Err(e) => {
+            println!("Rejected message from client {}: {}", client_id, e);
+            ServerMessage::Error { reason: e.to_string() }
+        }
+    };
+
+    // Reply to the original client
     if let Some(client_stream) = clients.get_client(client_id) {
-        if message_json.is_null() {
-            println!("Invalid JSON message: {}", message);
+        task::block_on(AsyncTcpServer::send(client_stream, &response.to_json()))
+            .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+    }
+}
+
+/// Passes a chat message that made it through the filter on to whoever `scope` says should read it.
+fn handle_chat_server(client_id: PlayerId, scope: ChatScope, to: Option<PlayerId>, text: &str, game: &Mutex<Value>, sessions: &Sessions, clients: &mut ClientConnections) -> Result<(), String> {
+    let name = sessions.profile(client_id)
+        .map_or_else(|| format!("Player {}", client_id), |profile| profile.name.clone());
+    println!("[chat {:?}] {} ({}){}: {}", scope, name, client_id, to.map_or_else(String::new, |to| format!(" to {}", to)), text);
+    let message = ServerMessage::Chat { scope, from: client_id, name, to, text: text.to_string() };
+    let game = clients.lock_game(Some(client_id), game);
+    task::block_on(clients.route_chat(&game, client_id, scope, to, &message.to_json()))?;
+    Ok(())
+}
+
+/// Handles a datagram from the UDP socket. Only position updates from joined players in the room
+/// they claim are applied; anything else is dropped, since UDP senders are not authenticated by a
+/// connection and lost datagrams are never answered.
+pub fn handle_datagram_server(datagram: &str, from: SocketAddr, game: Arc<Mutex<Value>>, sessions: &Sessions, clients: &mut ClientConnections, players: &mut Players) {
+    clients.metrics().datagram_received(datagram.len());
+    let datagram = match ClientDatagram::from_json(datagram) {
+        Ok(datagram) => datagram,
+        Err(e) => {
+            println!("Ignoring datagram from {}: {}", from, e);
             return;
-        } else {
-            if json_contains(&message_json, "x") {
-                // find client based on id and update position
-                let mut game_unwrapped = game.lock().unwrap();
-                if let Value::Object(game_obj) = &mut *game_unwrapped {
-                    for (_key, room) in game_obj.iter_mut() {
-                        if let Some(players) = room.get_mut("players").and_then(|p| p.as_array_mut()) {
-                            for player in players.iter_mut() {
-                                if player["id"] == message_json["id"] {
-                                    // Update position-related fields
-                                    if let Some(x) = message_json.get("x") {
-                                        player["x"] = x.clone();
-                                    }
-                                    if let Some(y) = message_json.get("y") {
-                                        player["y"] = y.clone();
-                                    }
-                                    if let Some(width) = message_json.get("width") {
-                                        player["width"] = width.clone();
-                                    }
-                                    if let Some(height) = message_json.get("height") {
-                                        player["height"] = height.clone();
-                                    }
-                                    if let Some(sprite_state) = message_json.get("sprite_state") {
-                                        player["sprite_state"] = sprite_state.clone();
-                                    }
-                                    let response: Value = json!({"update_position": {"x": player["x"], "y": player["y"], "width": player["width"], "height": player["height"], "sprite_state": player["sprite_state"], "id": player["id"]}});
-                                    task::block_on(AsyncTcpServer::send_to_socket(client_stream, &response.to_string(), client_id as usize))
-                                        .unwrap_or_else(|e| eprintln!("Send error: {}", e));
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    println!("Invalid game state: {:?}", game_unwrapped);
+        }
+    };
+    let client_id = match sessions.player_for_udp_key(&datagram.key) {
+        Some(client_id) => client_id,
+        None => return,
+    };
+    let update = match datagram.message {
+        ClientMessage::UpdatePosition(update) => update,
+        other => {
+            println!("Ignoring {:?} from player {} over UDP", other, client_id);
+            return;
+        }
+    };
+
+    let first_datagram = clients.udp_peer(client_id).is_none();
+    if !clients.accept_datagram(client_id, from, datagram.seq) {
+        return; // Late or duplicated
+    }
+    if first_datagram {
+        if let Some(client_stream) = clients.get_client(client_id) {
+            task::block_on(AsyncTcpServer::send(client_stream, &ServerMessage::UdpBound.to_json()))
+                .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+        }
+    }
+
+    // Joining a room and moving between rooms have to arrive, so they only happen over TCP
+    if ClientConnections::room_of(&clients.lock_game(Some(client_id), &game), client_id) != Some(update.room) {
+        return;
+    }
+    players.queue_input(QueuedInput { player: client_id, update, received: Instant::now() });
+}
+
+/// Handles the first message on a connection, which must be a `Hello` this server can talk to.
+/// Anything else gets `Rejected` with the reason and the connection is closed.
+/// `preferred_codec` is the codec to switch the connection to if the client can read it.
+pub fn handle_hello_server(message: Result<ClientMessage, ProtocolError>, mut stream: NetStream, connection_id: usize, sessions: &mut Sessions, preferred_codec: &str) {
+    let outcome = match message {
+        Ok(ClientMessage::Hello { version, name, skin, capabilities, codecs }) => {
+            protocol::negotiate(version, &name, &capabilities, &codecs, preferred_codec).map(|welcome| (welcome, name, skin))
+        }
+        Ok(_) => Err("Say hello first; your game may be too old for this server.".to_string()),
+        Err(e) => Err(format!("Could not read the handshake ({}); your game may be too old for this server.", e)),
+    };
+
+    match outcome {
+        Ok((mut welcome, name, skin)) => {
+            if let ServerMessage::Welcome { capabilities, udp_key, .. } = &mut welcome {
+                println!("Connection {} says hello as {:?}", connection_id, name.trim());
+                let profile = PlayerProfile { name: name.trim().to_string(), skin, capabilities: capabilities.clone() };
+                if profile.has_capability(capability::UDP) {
+                    *udp_key = Some(sessions.new_udp_key(connection_id));
+                }
+                sessions.greet(connection_id, profile);
+            }
+            task::block_on(AsyncTcpServer::send(&mut stream, &welcome.to_json()))
+                .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+            // The welcome itself still goes out in JSON; everything after it uses the agreed codec
+            if let ServerMessage::Welcome { codec, .. } = &welcome {
+                if let Some(codec) = codec_by_name(codec) {
+                    stream.set_codec(codec);
                 }
             }
+        }
+        Err(reason) => {
+            println!("Rejecting connection {}: {}", connection_id, reason);
+            let response = ServerMessage::Rejected { reason };
+            task::block_on(AsyncTcpServer::send(&mut stream, &response.to_json()))
+                .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+            // Stop sending but keep reading, so the rejection is not lost to a reset
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+        }
+    }
+}
 
-            // Send a response only to the original client
-            let response = json!({"status": "ok"});
-            task::block_on(AsyncTcpServer::send(client_stream, &response.to_string()))
+/// Handles the message after `Hello`, which must be `Join`.
+/// Registers the stream under the player id handed out by `sessions`.
+pub fn handle_join_server(message: Result<ClientMessage, ProtocolError>, mut stream: NetStream, connection_id: usize, game: Arc<Mutex<Value>>, sessions: &mut Sessions, clients: &mut ClientConnections, players: &mut Players) {
+    let (token, preferred_latency_ms) = match message {
+        Ok(ClientMessage::Join { token, preferred_latency_ms }) => (token, preferred_latency_ms),
+        Ok(_) => {
+            let response = ServerMessage::Error { reason: "send join first".to_string() };
+            task::block_on(AsyncTcpServer::send(&mut stream, &response.to_json()))
+                .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+            return;
+        }
+        Err(e) => {
+            println!("Rejected message from connection {}: {}", connection_id, e);
+            let response = ServerMessage::Error { reason: e.to_string() };
+            task::block_on(AsyncTcpServer::send(&mut stream, &response.to_json()))
                 .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+            return;
         }
+    };
+
+    let outcome = sessions.join(connection_id, token.as_deref());
+    let wants_udp = sessions.profile(outcome.player_id).is_some_and(|p| p.has_capability(capability::UDP));
+    if let Some(ms) = preferred_latency_ms {
+        sessions.set_preferred_latency(outcome.player_id, std::time::Duration::from_millis(ms as u64));
     }
-}
\ No newline at end of file
+    clients.add_client(outcome.player_id, stream);
+    players.add(outcome.player_id);
+
+    let mut player = None;
+    if let Some((room_id, entity)) = outcome.resumed {
+        let mut game = clients.lock_game(Some(outcome.player_id), &game);
+        // Whoever it comes into view for is sent a Spawn on the next tick
+        if restore_player(&mut game, room_id, entity.clone()) {
+            println!("Player {} resumed in room {}", outcome.player_id, room_id);
+            player = Some(entity);
+        }
+    } else {
+        println!("Player {} joined", outcome.player_id);
+    }
+
+    let udp_port = clients.udp_socket()
+        .filter(|_| wants_udp)
+        .and_then(|udp| udp.local_addr().ok())
+        .map(|addr| addr.port());
+    let response = ServerMessage::Joined { player_id: outcome.player_id, token: outcome.token, player, udp_port };
+    if let Some(client_stream) = clients.get_client(outcome.player_id) {
+        task::block_on(AsyncTcpServer::send(client_stream, &response.to_json()))
+            .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+    }
+}
+
+/// Cleans up after a player whose connection closed: forgets its stream, takes its entity out
+/// of the room and tells whoever could see it. Returns the room and entity so the session can
+/// be resumed later.
+pub fn handle_disconnect_server(game: Arc<Mutex<Value>>, client_id: u32, clients: &mut ClientConnections, players: &mut Players) -> Option<(i32, Value)> {
+    clients.remove_client(client_id);
+    players.remove(client_id);
+
+    let mut game = clients.lock_game(None, &game);
+    let (room_id, entity) = remove_player(&mut game, client_id)?;
+    println!("Player {} left room {}", client_id, room_id);
+    let notice = ServerMessage::PlayerLeft { id: client_id };
+    let watchers = ClientConnections::room_members(&game, room_id).into_iter()
+        .filter(|id| players.can_see(*id, false, client_id))
+        .collect();
+    task::block_on(clients.send_to_many(watchers, &notice.to_json()));
+    Some((room_id, entity))
+}
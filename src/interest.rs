@@ -0,0 +1,174 @@
+use crate::protocol::PlayerId;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// Area of interest. A client is only told about the players and NPCs in its own room that are
+// within a radius of its player; everything else is left out of its room updates and of the state
+// it is synced. Entities coming into range are sent whole (Spawn) and ones going out of range are
+// taken away again (Despawn), so the client never has to guess why something stopped moving.
+
+/// How far a player sees, in game units, unless the INTEREST_RADIUS setting says otherwise.
+pub const DEFAULT_INTEREST_RADIUS: f32 = 600.0;
+/// Entities in view stay until they are this much further out than the radius, so one standing
+/// right on the edge does not spawn and despawn over and over.
+const LEAVE_MARGIN: f32 = 1.2;
+
+/// What came into and went out of a client's view, as (`roomID`, is npc, ...).
+#[derive(Debug, Default, PartialEq)]
+pub struct InterestChange {
+    pub spawned: Vec<(i32, bool, Value)>,
+    pub despawned: Vec<(i32, bool, PlayerId)>,
+}
+
+impl InterestChange {
+    pub fn is_empty(&self) -> bool {
+        self.spawned.is_empty() && self.despawned.is_empty()
+    }
+}
+
+/// The entities one client can see, keyed by (is npc, id) with the `roomID` they are in.
+#[derive(Debug, Clone)]
+pub struct Interest {
+    radius: f32,
+    visible: HashMap<(bool, PlayerId), i32>,
+}
+
+/// Center of an entity's box.
+fn center(entity: &Value) -> (f32, f32) {
+    let x = entity["x"].as_f64().unwrap_or(0.0) + entity["width"].as_f64().unwrap_or(0.0) / 2.0;
+    let y = entity["y"].as_f64().unwrap_or(0.0) + entity["height"].as_f64().unwrap_or(0.0) / 2.0;
+    (x as f32, y as f32)
+}
+
+/// Straight-line distance between two entities' centers, like movement::calculate_distance.
+fn distance(a: &Value, b: &Value) -> f32 {
+    let ((x1, y1), (x2, y2)) = (center(a), center(b));
+    ((x2 - x1).powf(2.0) + (y2 - y1).powf(2.0)).sqrt()
+}
+
+/// The room `viewer` is in, and its player entity.
+fn find_viewer(game: &Value, viewer: PlayerId) -> Option<(&Value, &Value)> {
+    game.as_object()?.values().find_map(|room| {
+        let player = room["players"].as_array()?.iter().find(|p| p["id"] == viewer)?;
+        Some((room, player))
+    })
+}
+
+impl Interest {
+    pub fn new(radius: f32) -> Self {
+        Interest { radius, visible: HashMap::new() }
+    }
+
+    pub fn is_visible(&self, npc: bool, id: PlayerId) -> bool {
+        self.visible.contains_key(&(npc, id))
+    }
+
+    /// Works out what `viewer` sees in `game` now. Its own player is always in view. A viewer
+    /// that is not in any room sees nothing.
+    pub fn update(&mut self, game: &Value, viewer: PlayerId) -> InterestChange {
+        let mut now_visible = HashMap::new();
+        let mut change = InterestChange::default();
+        if let Some((room, player)) = find_viewer(game, viewer) {
+            let room_id = room["roomID"].as_i64().unwrap_or(0) as i32;
+            for (npc, list) in [(false, "players"), (true, "npcs")] {
+                for entity in room[list].as_array().into_iter().flatten() {
+                    let id = match entity["id"].as_u64() {
+                        Some(id) => id as PlayerId,
+                        None => continue,
+                    };
+                    let seen = self.visible.get(&(npc, id)) == Some(&room_id);
+                    let range = if seen { self.radius * LEAVE_MARGIN } else { self.radius };
+                    let own = !npc && id == viewer;
+                    if own || distance(player, entity) <= range {
+                        now_visible.insert((npc, id), room_id);
+                        if !seen {
+                            change.spawned.push((room_id, npc, entity.clone()));
+                        }
+                    }
+                }
+            }
+        }
+        for (&(npc, id), &room_id) in &self.visible {
+            if now_visible.get(&(npc, id)) != Some(&room_id) {
+                change.despawned.push((room_id, npc, id));
+            }
+        }
+        change.despawned.sort_unstable_by_key(|(room, npc, id)| (*room, *npc, *id));
+        self.visible = now_visible;
+        change
+    }
+
+    /// The game as this client should see it: every room with its own fields and objects, but
+    /// only the players and NPCs in view.
+    pub fn filter(&self, game: &Value) -> Value {
+        let mut view = json!({});
+        if let (Value::Object(rooms), Value::Object(view_rooms)) = (game, &mut view) {
+            for (key, room) in rooms {
+                let mut room = room.clone();
+                let room_id = room["roomID"].as_i64().unwrap_or(0) as i32;
+                for (npc, list) in [(false, "players"), (true, "npcs")] {
+                    if let Some(entities) = room.get_mut(list).and_then(|l| l.as_array_mut()) {
+                        entities.retain(|entity| {
+                            let id = entity["id"].as_u64().unwrap_or(0) as PlayerId;
+                            self.visible.get(&(npc, id)) == Some(&room_id)
+                        });
+                    }
+                }
+                view_rooms.insert(key.clone(), room);
+            }
+        }
+        view
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(other_x: f64) -> Value {
+        json!({
+            "room1": {
+                "players": [
+                    {"id": 1, "x": 0.0, "y": 0.0, "width": 50, "height": 50},
+                    {"id": 2, "x": other_x, "y": 0.0, "width": 50, "height": 50},
+                ],
+                "npcs": [{"id": 1, "x": 900.0, "y": 0.0}],
+                "objects": [{"id": 0}],
+                "roomID": 1
+            },
+            "room2": {"players": [{"id": 3, "x": 0.0, "y": 0.0}], "npcs": [], "roomID": 2},
+        })
+    }
+
+    #[test]
+    fn test_entities_spawn_and_despawn_with_distance() {
+        let mut interest = Interest::new(500.0);
+
+        let change = interest.update(&game(400.0), 1);
+        let spawned: Vec<_> = change.spawned.iter().map(|(room, npc, e)| (*room, *npc, e["id"].clone())).collect();
+        assert_eq!(spawned, vec![(1, false, json!(1)), (1, false, json!(2))]);
+        assert!(change.despawned.is_empty());
+
+        // Just past the radius is still inside the leave margin
+        assert!(interest.update(&game(550.0), 1).is_empty());
+        assert!(interest.is_visible(false, 2));
+
+        let change = interest.update(&game(700.0), 1);
+        assert_eq!(change.despawned, vec![(1, false, 2)]);
+        assert!(!interest.is_visible(false, 2));
+        // The own player never leaves view
+        assert!(interest.is_visible(false, 1));
+    }
+
+    #[test]
+    fn test_filter_keeps_objects_and_visible_entities() {
+        let mut interest = Interest::new(500.0);
+        interest.update(&game(700.0), 1);
+        let view = interest.filter(&game(700.0));
+        assert_eq!(view["room1"]["players"], json!([{"id": 1, "x": 0.0, "y": 0.0, "width": 50, "height": 50}]));
+        assert_eq!(view["room1"]["npcs"], json!([]));
+        assert_eq!(view["room1"]["objects"], json!([{"id": 0}]));
+        // Other rooms are out of view entirely
+        assert_eq!(view["room2"]["players"], json!([]));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::protocol::{EntityPosition, PlayerId};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Smooths out remote entities on the client. Positions arrive at network rate, so drawing them
+// as they come makes other players and NPCs jump. Instead every update is stored with the time it
+// arrived and entities are drawn a little in the past, between the two updates around that moment.
+// When updates stop coming the entity keeps its last velocity for a short while, then stops.
+
+/// Delay added on top of PREFERRED_LATENCY, so there is usually a newer update to move towards.
+pub const INTERPOLATION_MARGIN: Duration = Duration::from_millis(50);
+/// How far past the newest update an entity may be extrapolated before it stops.
+pub const MAX_EXTRAPOLATION: Duration = Duration::from_millis(250);
+/// Updates kept per entity. Older ones are dropped even if they are still inside the delay.
+const MAX_SAMPLES: usize = 32;
+
+/// Where to draw an entity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderedPosition {
+    pub x: f32,
+    pub y: f32,
+    pub sprite_state: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    time: Instant,
+    position: RenderedPosition,
+}
+
+/// The updates received for one entity, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotBuffer {
+    samples: VecDeque<Sample>,
+}
+
+impl SnapshotBuffer {
+    pub fn new() -> Self {
+        SnapshotBuffer::default()
+    }
+
+    /// Records an update that arrived at `time`. Updates must be pushed in arrival order.
+    pub fn push(&mut self, time: Instant, position: RenderedPosition) {
+        self.samples.push_back(Sample { time, position });
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Drops updates that can no longer be drawn, keeping the newest one before `render_time`.
+    fn prune(&mut self, render_time: Instant) {
+        while self.samples.len() > 2 && self.samples[1].time <= render_time {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The position at `render_time`. Before the first update it is that update, between two it
+    /// is interpolated, and past the newest it is extrapolated for at most `max_extrapolation`.
+    /// The sprite state is the one of whichever update is closer in time.
+    pub fn sample(&self, render_time: Instant, max_extrapolation: Duration) -> Option<RenderedPosition> {
+        let newest = self.samples.back()?;
+        let next = self.samples.iter().position(|s| s.time > render_time);
+        match next {
+            Some(0) => Some(self.samples[0].position),
+            Some(index) => {
+                let (from, to) = (&self.samples[index - 1], &self.samples[index]);
+                let t = fraction(from.time, to.time, render_time);
+                Some(lerp(&from.position, &to.position, t, if t < 0.5 { from } else { to }.position.sprite_state))
+            }
+            None if self.samples.len() < 2 => Some(newest.position),
+            None => {
+                // Keep moving the way the last two updates went, for a while
+                let previous = &self.samples[self.samples.len() - 2];
+                let late = render_time.saturating_duration_since(newest.time).min(max_extrapolation);
+                let span = newest.time.saturating_duration_since(previous.time);
+                if span.is_zero() {
+                    return Some(newest.position);
+                }
+                let t = 1.0 + late.as_secs_f32() / span.as_secs_f32();
+                Some(lerp(&previous.position, &newest.position, t, newest.position.sprite_state))
+            }
+        }
+    }
+}
+
+fn fraction(from: Instant, to: Instant, at: Instant) -> f32 {
+    let span = to.saturating_duration_since(from).as_secs_f32();
+    if span == 0.0 {
+        return 1.0;
+    }
+    at.saturating_duration_since(from).as_secs_f32() / span
+}
+
+fn lerp(from: &RenderedPosition, to: &RenderedPosition, t: f32, sprite_state: Option<i32>) -> RenderedPosition {
+    RenderedPosition {
+        x: from.x + (to.x - from.x) * t,
+        y: from.y + (to.y - from.y) * t,
+        sprite_state,
+    }
+}
+
+/// Snapshot buffers for every remote entity, keyed by (is npc, id) like the datagram sequence filter.
+#[derive(Debug, Clone)]
+pub struct Interpolator {
+    buffers: HashMap<(bool, PlayerId), SnapshotBuffer>,
+    delay: Duration,
+    max_extrapolation: Duration,
+}
+
+impl Interpolator {
+    /// Draws entities `delay` behind the newest updates.
+    pub fn new(delay: Duration, max_extrapolation: Duration) -> Self {
+        Interpolator { buffers: HashMap::new(), delay, max_extrapolation }
+    }
+
+    /// The delay for a PREFERRED_LATENCY setting: the latency plus INTERPOLATION_MARGIN.
+    pub fn for_latency(preferred_latency: Duration) -> Self {
+        Interpolator::new(preferred_latency + INTERPOLATION_MARGIN, MAX_EXTRAPOLATION)
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Records a position update for an entity, received at `now`.
+    pub fn record(&mut self, npc: bool, position: &EntityPosition, now: Instant) {
+        let rendered = RenderedPosition { x: position.x, y: position.y, sprite_state: position.sprite_state };
+        self.buffers.entry((npc, position.id)).or_default().push(now, rendered);
+    }
+
+    /// Forgets an entity, e.g. when it leaves or reappears somewhere else. The next update
+    /// places it without sliding there from its old spot.
+    pub fn forget(&mut self, npc: bool, id: PlayerId) {
+        self.buffers.remove(&(npc, id));
+    }
+
+    /// Where to draw an entity at `now`, or None if no update for it has arrived yet.
+    pub fn position(&mut self, npc: bool, id: PlayerId, now: Instant) -> Option<RenderedPosition> {
+        let render_time = now.checked_sub(self.delay).unwrap_or(now);
+        let buffer = self.buffers.get_mut(&(npc, id))?;
+        buffer.prune(render_time);
+        buffer.sample(render_time, self.max_extrapolation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, sprite_state: i32) -> RenderedPosition {
+        RenderedPosition { x, y: 0.0, sprite_state: Some(sprite_state) }
+    }
+
+    #[test]
+    fn test_buffer_interpolates_between_updates() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::new();
+        buffer.push(start, at(0.0, 1));
+        buffer.push(start + Duration::from_millis(100), at(100.0, 2));
+
+        let max = Duration::from_millis(50);
+        assert_eq!(buffer.sample(start, max), Some(at(0.0, 1)));
+        assert_eq!(buffer.sample(start + Duration::from_millis(25), max), Some(at(25.0, 1)));
+        assert_eq!(buffer.sample(start + Duration::from_millis(75), max), Some(at(75.0, 2)));
+        // Late updates are extrapolated up to the limit, then the entity stops
+        assert_eq!(buffer.sample(start + Duration::from_millis(130), max), Some(at(130.0, 2)));
+        assert_eq!(buffer.sample(start + Duration::from_millis(500), max), Some(at(150.0, 2)));
+    }
+
+    #[test]
+    fn test_interpolator_renders_behind_by_the_delay() {
+        let start = Instant::now();
+        let mut interpolator = Interpolator::new(Duration::from_millis(100), MAX_EXTRAPOLATION);
+        let update = |x: f32| EntityPosition { id: 7, x, y: 0.0, width: None, height: None, sprite_state: None, input: None };
+        interpolator.record(false, &update(0.0), start);
+        interpolator.record(false, &update(100.0), start + Duration::from_millis(100));
+
+        let drawn = interpolator.position(false, 7, start + Duration::from_millis(150)).unwrap();
+        assert_eq!(drawn.x, 50.0);
+        // Players and NPCs with the same id are tracked apart
+        assert_eq!(interpolator.position(true, 7, start + Duration::from_millis(150)), None);
+
+        interpolator.forget(false, 7);
+        assert_eq!(interpolator.position(false, 7, start + Duration::from_millis(150)), None);
+    }
+}
@@ -5,6 +5,21 @@ pub mod pathfinding;
 pub mod client;
 pub mod settings;
 pub mod networking;
+pub mod protocol;
 pub mod handle_read;
 pub mod randommods;
 pub mod server;
+pub mod ratelimit;
+pub mod session;
+pub mod tls;
+pub mod discovery;
+pub mod replication;
+pub mod interpolation;
+pub mod prediction;
+pub mod validation;
+pub mod simulation;
+pub mod interest;
+pub mod metrics;
+pub mod admin;
+pub mod chat;
+pub mod players;
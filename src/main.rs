@@ -14,7 +14,22 @@ mod randommods;
 mod settings;
 mod collision;
 mod networking;
+mod protocol;
+mod ratelimit;
+mod session;
 mod handle_read;
+mod tls;
+mod discovery;
+mod replication;
+mod interpolation;
+mod prediction;
+mod validation;
+mod simulation;
+mod interest;
+mod metrics;
+mod admin;
+mod chat;
+mod players;
 
 fn main() {
     println!("Starting settings...");
@@ -84,23 +99,51 @@ fn main() {
         .map(|v| v.as_str().expect("Expected a string in launchfile.json").to_string())
         .collect();
 
+    let shutdown = networking::ShutdownHandle::new();
+    let mut server_thread: Option<thread::JoinHandle<()>> = None;
+    let mut server_bound = None;
     if launch.contains(&"server.rs".to_string()) {
-        println!("Starting server...");
-        let server_thread: thread::JoinHandle<()> = thread::spawn(|| {
-            server::main();
+        println!("Starting server... (type \"help\" for admin commands, \"quit\" to stop it)");
+        let server_shutdown = shutdown.clone();
+        let (bound_tx, bound_rx) = std::sync::mpsc::channel();
+        let (command_tx, command_rx) = std::sync::mpsc::channel();
+        server_bound = Some(bound_rx);
+        server_thread = Some(thread::spawn(move || {
+            server::main(server_shutdown, bound_tx, command_rx);
+        }));
+
+        // Everything typed into the terminal goes to the server's admin console
+        thread::spawn(move || {
+            for line in io::stdin().lines() {
+                match line {
+                    Ok(line) if line.trim().is_empty() => {}
+                    Ok(line) => {
+                        if command_tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
         });
     }
 
     if launch.contains(&"client.rs".to_string()) {
-        //2 second timer for client so it doesn't start before server
-        println!("Will start client in 2 seconds...");
-        std::thread::sleep(std::time::Duration::from_secs(2));
-        
+        // With a local server, wait until it is listening and connect to wherever it bound
+        let server_addr = server_bound.and_then(|bound| bound.recv().ok());
+
         println!("Starting client...");
-        let client_thread: thread::JoinHandle<()> = thread::spawn(|| {
-            client::main();
+        let client_thread: thread::JoinHandle<()> = thread::spawn(move || {
+            client::main(server_addr);
         });
         client_thread.join().expect("Client thread panicked");
+
+        // The local server goes down with the player's window
+        shutdown.shutdown();
+    }
+
+    if let Some(server_thread) = server_thread {
+        server_thread.join().expect("Server thread panicked");
     }
 
     println!("Exiting...");
@@ -0,0 +1,380 @@
+use crate::networking::ShutdownHandle;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// What the server spends its time on. Every NetStream counts the bytes and messages that go
+// through it, and the game server times each message handler by the type of the ClientMessage it
+// decoded. Datagrams on the UDP channel and waits on the game state lock are recorded by
+// ClientConnections, which sends the one and takes the other. The numbers can be read through
+// ServerMetrics at any time, and `summary` lists the connections and message types that cost
+// the most.
+
+/// How often the server logs a summary, unless the METRICS_INTERVAL setting says otherwise.
+pub const DEFAULT_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+/// Connections and message types listed in a summary.
+const TOP_ENTRIES: usize = 5;
+
+/// Bytes and messages through one connection, shared by every clone of its NetStream.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+}
+
+impl Traffic {
+    pub fn read(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn wrote(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received_message(&self) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent_message(&self) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> TrafficTotals {
+        TrafficTotals {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bytes include framing and heartbeats; messages only count what reaches a handler or comes
+/// from `send`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrafficTotals {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+impl std::ops::Add for TrafficTotals {
+    type Output = TrafficTotals;
+
+    fn add(self, other: TrafficTotals) -> TrafficTotals {
+        TrafficTotals {
+            bytes_in: self.bytes_in + other.bytes_in,
+            bytes_out: self.bytes_out + other.bytes_out,
+            messages_in: self.messages_in + other.messages_in,
+            messages_out: self.messages_out + other.messages_out,
+        }
+    }
+}
+
+/// Handler time for one type of message.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MessageStats {
+    pub count: u64,
+    pub bytes: u64,
+    pub handler_time: Duration,
+    pub slowest: Duration,
+}
+
+impl MessageStats {
+    fn record(&mut self, bytes: usize, elapsed: Duration) {
+        self.count += 1;
+        self.bytes += bytes as u64;
+        self.handler_time += elapsed;
+        self.slowest = self.slowest.max(elapsed);
+    }
+
+    pub fn average(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.handler_time / count as u32,
+        }
+    }
+}
+
+/// Everything recorded for one connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub socket_id: usize,
+    pub peer: Option<SocketAddr>,
+    /// Set by the application, e.g. to the player on this connection.
+    pub label: Option<String>,
+    pub connected_at: Instant,
+    pub traffic: TrafficTotals,
+    pub handler_time: Duration,
+    pub lock_wait: Duration,
+    pub messages: HashMap<String, MessageStats>,
+}
+
+impl ConnectionStats {
+    fn name(&self) -> String {
+        let peer = self.peer.map_or_else(|| "unknown".to_string(), |peer| peer.to_string());
+        match &self.label {
+            Some(label) => format!("#{} {} ({})", self.socket_id, label, peer),
+            None => format!("#{} ({})", self.socket_id, peer),
+        }
+    }
+}
+
+struct OpenConnection {
+    traffic: Arc<Traffic>,
+    stats: ConnectionStats,
+}
+
+impl OpenConnection {
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats { traffic: self.traffic.totals(), ..self.stats.clone() }
+    }
+}
+
+#[derive(Default)]
+struct MetricsState {
+    connections: HashMap<usize, OpenConnection>,
+    total_connections: u64,
+    peak_connections: usize,
+    /// Traffic of connections that have closed.
+    closed: TrafficTotals,
+    messages: HashMap<String, MessageStats>,
+    lock_waits: u64,
+    lock_wait: Duration,
+    slowest_lock_wait: Duration,
+}
+
+/// Server-wide numbers plus one `ConnectionStats` per open connection. Cloning gives another
+/// handle to the same numbers, like ConnectionLatencies.
+#[derive(Clone)]
+pub struct ServerMetrics {
+    state: Arc<Mutex<MetricsState>>,
+    /// Everything on the UDP channel, counted as bytes and datagrams.
+    datagrams: Arc<Traffic>,
+    started: Instant,
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        ServerMetrics {
+            state: Arc::new(Mutex::new(MetricsState::default())),
+            datagrams: Arc::new(Traffic::default()),
+            started: Instant::now(),
+        }
+    }
+
+    /// Starts recording a connection whose NetStream counts into `traffic`.
+    pub fn open(&self, socket_id: usize, peer: Option<SocketAddr>, traffic: Arc<Traffic>) {
+        let mut state = self.state.lock().unwrap();
+        let stats = ConnectionStats {
+            socket_id,
+            peer,
+            label: None,
+            connected_at: Instant::now(),
+            traffic: TrafficTotals::default(),
+            handler_time: Duration::ZERO,
+            lock_wait: Duration::ZERO,
+            messages: HashMap::new(),
+        };
+        state.connections.insert(socket_id, OpenConnection { traffic, stats });
+        state.total_connections += 1;
+        state.peak_connections = state.peak_connections.max(state.connections.len());
+    }
+
+    /// Stops recording a connection. Its traffic still counts towards the server totals.
+    pub fn close(&self, socket_id: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(connection) = state.connections.remove(&socket_id) {
+            state.closed = state.closed + connection.traffic.totals();
+        }
+    }
+
+    /// Names a connection in summaries, e.g. "player 3".
+    pub fn set_label(&self, socket_id: usize, label: impl Into<String>) {
+        if let Some(connection) = self.state.lock().unwrap().connections.get_mut(&socket_id) {
+            connection.stats.label = Some(label.into());
+        }
+    }
+
+    /// Records a message of `kind` that was `bytes` long and took `elapsed` to handle.
+    pub fn record_message(&self, socket_id: usize, kind: &str, bytes: usize, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.messages.entry(kind.to_string()).or_default().record(bytes, elapsed);
+        if let Some(connection) = state.connections.get_mut(&socket_id) {
+            connection.stats.handler_time += elapsed;
+            connection.stats.messages.entry(kind.to_string()).or_default().record(bytes, elapsed);
+        }
+    }
+
+    /// Records a datagram of `bytes` that arrived on the UDP channel, whoever sent it.
+    pub fn datagram_received(&self, bytes: usize) {
+        self.datagrams.read(bytes);
+        self.datagrams.received_message();
+    }
+
+    /// Records a datagram of `bytes` sent on the UDP channel.
+    pub fn datagram_sent(&self, bytes: usize) {
+        self.datagrams.wrote(bytes);
+        self.datagrams.sent_message();
+    }
+
+    /// Records time spent waiting for the game state lock, on behalf of a connection or, with
+    /// None, of the server itself (e.g. the tick).
+    pub fn record_lock_wait(&self, socket_id: Option<usize>, wait: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.lock_waits += 1;
+        state.lock_wait += wait;
+        state.slowest_lock_wait = state.slowest_lock_wait.max(wait);
+        if let Some(connection) = socket_id.and_then(|id| state.connections.get_mut(&id)) {
+            connection.stats.lock_wait += wait;
+        }
+    }
+
+    /// Connections open right now.
+    pub fn connected(&self) -> usize {
+        self.state.lock().unwrap().connections.len()
+    }
+
+    /// Most connections open at once.
+    pub fn peak_connected(&self) -> usize {
+        self.state.lock().unwrap().peak_connections
+    }
+
+    /// Connections accepted since the server started.
+    pub fn total_connections(&self) -> u64 {
+        self.state.lock().unwrap().total_connections
+    }
+
+    pub fn connection(&self, socket_id: usize) -> Option<ConnectionStats> {
+        self.state.lock().unwrap().connections.get(&socket_id).map(OpenConnection::stats)
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        self.state.lock().unwrap().connections.values().map(OpenConnection::stats).collect()
+    }
+
+    /// Handler numbers for every message type, over all connections.
+    pub fn messages(&self) -> HashMap<String, MessageStats> {
+        self.state.lock().unwrap().messages.clone()
+    }
+
+    /// UDP traffic since the server started, with datagrams as the messages.
+    pub fn datagrams(&self) -> TrafficTotals {
+        self.datagrams.totals()
+    }
+
+    /// Traffic of every connection since the server started, open or closed.
+    pub fn traffic(&self) -> TrafficTotals {
+        let state = self.state.lock().unwrap();
+        state.connections.values().fold(state.closed, |total, c| total + c.traffic.totals())
+    }
+
+    /// A few lines on the server as a whole, the connections sending the most and the message
+    /// types costing the most handler time.
+    pub fn summary(&self) -> String {
+        let traffic = self.traffic();
+        let datagrams = self.datagrams();
+        let mut connections = self.connections();
+        connections.sort_by_key(|c| std::cmp::Reverse((c.traffic.bytes_in, c.socket_id)));
+        let mut messages: Vec<_> = self.messages().into_iter().collect();
+        messages.sort_by(|a, b| b.1.handler_time.cmp(&a.1.handler_time).then_with(|| a.0.cmp(&b.0)));
+        let state = self.state.lock().unwrap();
+
+        let mut lines = vec![format!(
+            "Server metrics after {}s: {} connected (peak {}, {} total), in {} B / {} msgs, out {} B / {} msgs",
+            self.started.elapsed().as_secs(), state.connections.len(), state.peak_connections, state.total_connections,
+            traffic.bytes_in, traffic.messages_in, traffic.bytes_out, traffic.messages_out,
+        )];
+        if datagrams != TrafficTotals::default() {
+            lines.push(format!(
+                "  udp: in {} B / {} datagrams, out {} B / {} datagrams",
+                datagrams.bytes_in, datagrams.messages_in, datagrams.bytes_out, datagrams.messages_out,
+            ));
+        }
+        for c in connections.iter().take(TOP_ENTRIES) {
+            lines.push(format!(
+                "  {} for {}s: in {} B / {} msgs, out {} B / {} msgs, handlers {}ms, game lock wait {}ms",
+                c.name(), c.connected_at.elapsed().as_secs(), c.traffic.bytes_in, c.traffic.messages_in, c.traffic.bytes_out, c.traffic.messages_out,
+                c.handler_time.as_millis(), c.lock_wait.as_millis(),
+            ));
+        }
+        for (kind, stats) in messages.iter().take(TOP_ENTRIES) {
+            lines.push(format!(
+                "  {}: {} msgs, {} B, handlers {}ms (avg {}us, max {}us)",
+                kind, stats.count, stats.bytes, stats.handler_time.as_millis(),
+                stats.average().as_micros(), stats.slowest.as_micros(),
+            ));
+        }
+        lines.push(format!(
+            "  game lock: {} waits, {}ms in total, max {}us",
+            state.lock_waits, state.lock_wait.as_millis(), state.slowest_lock_wait.as_micros(),
+        ));
+        lines.join("\n")
+    }
+
+    /// Prints `summary` every `interval` until `shutdown`.
+    pub async fn log_every(self, interval: Duration, shutdown: ShutdownHandle) {
+        while async_std::future::timeout(interval, shutdown.wait()).await.is_err() {
+            println!("{}", self.summary());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connections_and_messages_are_recorded() {
+        let metrics = ServerMetrics::new();
+        let (first, second) = (Arc::new(Traffic::default()), Arc::new(Traffic::default()));
+        metrics.open(1, None, Arc::clone(&first));
+        metrics.open(2, None, Arc::clone(&second));
+        metrics.set_label(2, "player 7");
+        first.read(100);
+        first.received_message();
+        second.read(10);
+        second.wrote(40);
+
+        metrics.record_message(1, "update_position", 100, Duration::from_millis(3));
+        metrics.record_message(1, "update_position", 100, Duration::from_millis(1));
+        metrics.record_lock_wait(Some(1), Duration::from_millis(2));
+        metrics.record_lock_wait(None, Duration::from_millis(5));
+
+        let stats = metrics.connection(1).unwrap();
+        assert_eq!(stats.traffic, TrafficTotals { bytes_in: 100, bytes_out: 0, messages_in: 1, messages_out: 0 });
+        assert_eq!(stats.handler_time, Duration::from_millis(4));
+        assert_eq!(stats.lock_wait, Duration::from_millis(2));
+        assert_eq!(metrics.messages()["update_position"].average(), Duration::from_millis(2));
+        assert_eq!(metrics.messages()["update_position"].slowest, Duration::from_millis(3));
+
+        // Closed connections leave the count but not the totals
+        metrics.close(1);
+        assert_eq!((metrics.connected(), metrics.peak_connected(), metrics.total_connections()), (1, 2, 2));
+        assert_eq!(metrics.traffic(), TrafficTotals { bytes_in: 110, bytes_out: 40, messages_in: 1, messages_out: 0 });
+        assert!(metrics.summary().contains("#2 player 7 (unknown) for 0s: in 10 B"));
+    }
+
+    #[test]
+    fn test_datagrams_are_counted_apart() {
+        let metrics = ServerMetrics::new();
+        assert!(!metrics.summary().contains("udp"));
+        metrics.datagram_received(60);
+        metrics.datagram_sent(40);
+        metrics.datagram_sent(40);
+        assert_eq!(metrics.datagrams(), TrafficTotals { bytes_in: 60, bytes_out: 80, messages_in: 1, messages_out: 2 });
+        assert_eq!(metrics.traffic(), TrafficTotals::default());
+        assert!(metrics.summary().contains("udp: in 60 B / 1 datagrams, out 80 B / 2 datagrams"));
+    }
+}
@@ -3,6 +3,9 @@ use raylib::prelude::*;
 use serde_json::Value;
 use serde_json::json;
 
+/// How fast players move, as Movement::speed. The server's MovementRules hold players to it.
+pub const PLAYER_SPEED: f32 = 5.0;
+
 pub struct Movement {
     pub position: Vector2,
     pub speed: f32,
@@ -10,23 +13,53 @@ pub struct Movement {
     pub height: i32
 }
 
+/// The keys held during one frame and how long that frame took, so a move can be replayed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MovementInput {
+    pub right: bool,
+    pub left: bool,
+    pub down: bool,
+    pub up: bool,
+    pub delta_time: f32,
+}
+
+impl MovementInput {
+    /// Reads the arrow keys and WASD.
+    pub fn read(delta_time: f32) -> Self {
+        unsafe {
+            MovementInput {
+                right: IsKeyDown(KeyboardKey::KEY_RIGHT as i32) || IsKeyDown(KeyboardKey::KEY_D as i32),
+                left: IsKeyDown(KeyboardKey::KEY_LEFT as i32) || IsKeyDown(KeyboardKey::KEY_A as i32),
+                down: IsKeyDown(KeyboardKey::KEY_DOWN as i32) || IsKeyDown(KeyboardKey::KEY_S as i32),
+                up: IsKeyDown(KeyboardKey::KEY_UP as i32) || IsKeyDown(KeyboardKey::KEY_W as i32),
+                delta_time,
+            }
+        }
+    }
+}
+
 impl Movement {
     pub fn update(&mut self, delta_time: f32) {
-        let frame_speed = self.speed * delta_time * 40.0; 
+        self.apply(&MovementInput::read(delta_time));
+    }
+
+    /// Moves by one frame of input.
+    pub fn apply(&mut self, input: &MovementInput) {
+        let frame_speed = self.speed * input.delta_time * 40.0; 
 
-        if unsafe { IsKeyDown(KeyboardKey::KEY_RIGHT as i32) || IsKeyDown(KeyboardKey::KEY_D as i32) } {
+        if input.right {
             self.position.x += frame_speed;
         }
 
-        if unsafe { IsKeyDown(KeyboardKey::KEY_LEFT as i32) || IsKeyDown(KeyboardKey::KEY_A as i32) } {
+        if input.left {
             self.position.x -= frame_speed;
         }
 
-        if unsafe { IsKeyDown(KeyboardKey::KEY_DOWN as i32) || IsKeyDown(KeyboardKey::KEY_S as i32) } {
+        if input.down {
             self.position.y += frame_speed;
         }
 
-        if unsafe { IsKeyDown(KeyboardKey::KEY_UP as i32) || IsKeyDown(KeyboardKey::KEY_W as i32) } {
+        if input.up {
             self.position.y -= frame_speed;
         }
     }
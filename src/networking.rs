@@ -1,21 +1,647 @@
-use async_std::net::{TcpListener, TcpStream};
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
 use async_std::prelude::*;
 use async_std::task;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::os::unix::io::AsRawFd; // For Unix-based systems
 #[cfg(windows)]
 use std::os::windows::io::AsRawSocket; // For Windows
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::sync::{Mutex};
+use async_std::io::{self, Read, Write, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use futures_rustls::rustls::{ClientConfig, ServerConfig};
+use futures_rustls::rustls::pki_types::ServerName;
+use crate::ratelimit::{RateLimiter, RateLimits, RateLimitStep, TokenBucket};
+use crate::metrics::{ServerMetrics, Traffic, TrafficTotals};
+use crate::protocol::ChatScope;
+
+/// Largest payload a single frame may carry. Bigger frames are rejected instead of buffered.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Writes one frame: a 4-byte big-endian length followed by the UTF-8 payload.
+pub async fn send_frame<W: Write + Unpin>(stream: &mut W, message: &str) -> async_std::io::Result<()> {
+    send_frame_bytes(stream, message.as_bytes()).await
+}
+
+/// Like `send_frame`, for a payload that is not text, e.g. one encoded by a binary `Codec`.
+pub async fn send_frame_bytes<W: Write + Unpin>(stream: &mut W, payload: &[u8]) -> async_std::io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds MAX_FRAME_SIZE ({})", payload.len(), MAX_FRAME_SIZE),
+        ));
+    }
+
+    // Build the whole frame first so it goes out in a single write.
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+/// Reads exactly one frame written by `send_frame`.
+/// Returns `Ok(None)` when the peer closed the connection cleanly between frames.
+pub async fn recv_frame<R: Read + Unpin>(stream: &mut R) -> async_std::io::Result<Option<String>> {
+    recv_frame_limited(stream, MAX_FRAME_SIZE).await
+}
+
+/// Like `recv_frame`, but rejects frames bigger than `max_size` before reading their payload.
+pub async fn recv_frame_limited<R: Read + Unpin>(stream: &mut R, max_size: usize) -> async_std::io::Result<Option<String>> {
+    match recv_frame_bytes(stream, max_size).await? {
+        Some(payload) => String::from_utf8(payload)
+            .map(Some)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+        None => Ok(None),
+    }
+}
+
+/// Reads one frame of at most `max_size` bytes without assuming it is text.
+pub async fn recv_frame_bytes<R: Read + Unpin>(stream: &mut R, max_size: usize) -> async_std::io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match stream.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Ok(None), // Connection closed.
+            0 => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-frame")),
+            n => filled += n,
+        }
+    }
+
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_size {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("incoming frame of {} bytes exceeds the limit of {}", len, max_size),
+        ));
+    }
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// Turns messages into frame payloads and back. Messages are JSON text everywhere above the
+/// wire, so a codec only decides how that JSON travels. Both sides agree on one in the
+/// hello/welcome handshake; until then every connection uses `JsonCodec`.
+pub trait Codec: Send + Sync {
+    /// Name used to offer and pick the codec in the handshake.
+    fn name(&self) -> &'static str;
+    fn encode(&self, message: &str) -> async_std::io::Result<Vec<u8>>;
+    fn decode(&self, payload: &[u8]) -> async_std::io::Result<String>;
+}
+
+/// Sends the JSON text as it is. Easy to read in a packet capture.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, message: &str) -> async_std::io::Result<Vec<u8>> {
+        Ok(message.as_bytes().to_vec())
+    }
+
+    fn decode(&self, payload: &[u8]) -> async_std::io::Result<String> {
+        String::from_utf8(payload.to_vec()).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// MessagePack, which drops the quotes, braces and number text of JSON. Messages still have to
+/// be valid JSON going in.
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, message: &str) -> async_std::io::Result<Vec<u8>> {
+        let value: serde_json::Value = serde_json::from_str(message)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        rmp_serde::to_vec(&value).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+    }
+
+    fn decode(&self, payload: &[u8]) -> async_std::io::Result<String> {
+        let value: serde_json::Value = rmp_serde::from_slice(payload)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(value.to_string())
+    }
+}
+
+/// Names of the codecs this build speaks, most compact first.
+pub const CODECS: [&str; 2] = ["msgpack", "json"];
+
+/// Looks a codec up by its handshake name.
+pub fn codec_by_name(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        "json" => Some(Arc::new(JsonCodec)),
+        "msgpack" => Some(Arc::new(MessagePackCodec)),
+        _ => None,
+    }
+}
+
+/// Encodes `message` with the stream's codec and sends it as one frame.
+async fn send_encoded(stream: &mut NetStream, message: &str) -> async_std::io::Result<()> {
+    let payload = stream.codec().encode(message)?;
+    stream.write_frame(&payload).await?;
+    stream.traffic.sent_message();
+    Ok(())
+}
+
+/// Decodes a frame read from `stream`. Heartbeat and closing frames are always plain text, and
+/// their leading NUL byte cannot start a frame from any codec, so they skip the codec.
+fn decode_frame(stream: &NetStream, payload: Vec<u8>) -> async_std::io::Result<String> {
+    if payload.first() == Some(&0) {
+        return String::from_utf8(payload).map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
+    }
+    stream.codec().decode(&payload)
+}
+
+/// Reads and decodes one frame, or `None` if the peer closed the connection.
+async fn recv_decoded(stream: &mut NetStream, max_size: usize) -> async_std::io::Result<Option<String>> {
+    match recv_frame_bytes(stream, max_size).await? {
+        Some(payload) => decode_frame(stream, payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// How long a new connection gets to finish its TLS handshake.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long one frame may take to go out, waiting for the connection's other writers included.
+/// A peer that stops reading fills its socket buffer; after this its connection is shut down.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One connection, either plain TCP or TLS over TCP. Cloning gives another handle to the same
+/// connection, like cloning a `TcpStream`, so one task can read while others write.
+#[derive(Clone)]
+pub struct NetStream {
+    tcp: TcpStream,
+    tls: Option<Arc<Mutex<TlsStream<TcpStream>>>>,
+    codec: Arc<Mutex<Arc<dyn Codec>>>,
+    traffic: Arc<Traffic>,
+    write_lock: Arc<async_std::sync::Mutex<()>>,
+}
+
+impl NetStream {
+    fn new(tcp: TcpStream, tls: Option<TlsStream<TcpStream>>) -> Self {
+        NetStream {
+            tcp,
+            tls: tls.map(|tls| Arc::new(Mutex::new(tls))),
+            codec: Arc::new(Mutex::new(Arc::new(JsonCodec))),
+            traffic: Arc::new(Traffic::default()),
+            write_lock: Arc::new(async_std::sync::Mutex::new(())),
+        }
+    }
+
+    /// Sends one frame. Every handle to the connection takes the same lock for the whole frame,
+    /// so frames from handlers, broadcasts and heartbeats never interleave on the wire.
+    async fn write_frame(&mut self, payload: &[u8]) -> async_std::io::Result<()> {
+        self.write_frame_within(payload, WRITE_TIMEOUT).await
+    }
+
+    /// Like `write_frame`, but gives up after `limit`. A frame cut off part way would garble
+    /// everything after it, so the connection is shut down then.
+    async fn write_frame_within(&mut self, payload: &[u8], limit: Duration) -> async_std::io::Result<()> {
+        let write_lock = self.write_lock.clone();
+        let mut stream = self.clone();
+        let write = async move {
+            let _writing = write_lock.lock().await;
+            send_frame_bytes(&mut stream, payload).await
+        };
+        match async_std::future::timeout(limit, write).await {
+            Ok(result) => result,
+            Err(_) => {
+                let _ = self.shutdown(std::net::Shutdown::Both);
+                Err(io::Error::new(ErrorKind::TimedOut, "peer stopped reading"))
+            }
+        }
+    }
+
+    /// The codec messages on this connection are encoded with.
+    pub fn codec(&self) -> Arc<dyn Codec> {
+        self.codec.lock().unwrap().clone()
+    }
+
+    /// Switches codecs for every handle to this connection, from the next frame on.
+    pub fn set_codec(&self, codec: Arc<dyn Codec>) {
+        *self.codec.lock().unwrap() = codec;
+    }
+
+    /// Bytes and messages through this connection so far, counted over all its handles.
+    pub fn traffic(&self) -> TrafficTotals {
+        self.traffic.totals()
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn peer_addr(&self) -> async_std::io::Result<SocketAddr> {
+        self.tcp.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> async_std::io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    /// Shuts the socket down. Over TLS this skips close_notify; the peer just sees the socket close.
+    pub fn shutdown(&self, how: std::net::Shutdown) -> async_std::io::Result<()> {
+        self.tcp.shutdown(how)
+    }
+
+    /// Runs the server side of the TLS handshake on a freshly accepted socket.
+    async fn accept_tls(tcp: TcpStream, config: Arc<ServerConfig>) -> async_std::io::Result<Self> {
+        let handshake = TlsAcceptor::from(config).accept(tcp.clone());
+        let tls = async_std::future::timeout(TLS_HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        Ok(NetStream::new(tcp, Some(TlsStream::Server(tls))))
+    }
+
+    /// Runs the client side of the TLS handshake, checking the server certificate against `server_name`.
+    async fn connect_tls(tcp: TcpStream, config: Arc<ClientConfig>, server_name: ServerName<'static>) -> async_std::io::Result<Self> {
+        let tls = TlsConnector::from(config).connect(server_name, tcp.clone()).await?;
+        Ok(NetStream::new(tcp, Some(TlsStream::Client(tls))))
+    }
+}
+
+impl From<TcpStream> for NetStream {
+    fn from(tcp: TcpStream) -> Self {
+        NetStream::new(tcp, None)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for NetStream {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.tcp.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for NetStream {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.tcp.as_raw_socket()
+    }
+}
+
+// The TLS state is only locked for the length of one poll, so a reader and a writer on
+// different clones never wait on each other for long.
+impl Read for NetStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<async_std::io::Result<usize>> {
+        let this = self.get_mut();
+        let read = match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_read(cx, buf),
+            None => Pin::new(&mut this.tcp).poll_read(cx, buf),
+        };
+        if let Poll::Ready(Ok(bytes)) = read {
+            this.traffic.read(bytes);
+        }
+        read
+    }
+}
+
+impl Write for NetStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<async_std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_write(cx, buf),
+            None => Pin::new(&mut this.tcp).poll_write(cx, buf),
+        };
+        if let Poll::Ready(Ok(bytes)) = written {
+            this.traffic.wrote(bytes);
+        }
+        written
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<async_std::io::Result<()>> {
+        let this = self.get_mut();
+        match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_flush(cx),
+            None => Pin::new(&mut this.tcp).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<async_std::io::Result<()>> {
+        let this = self.get_mut();
+        match &this.tls {
+            Some(tls) => Pin::new(&mut *tls.lock().unwrap()).poll_close(cx),
+            None => Pin::new(&mut this.tcp).poll_close(cx),
+        }
+    }
+}
+
+// Heartbeats travel as ordinary frames whose payload starts with a NUL byte, which no JSON
+// message can, so the read loops can pick them out before the message handler sees them.
+const HEARTBEAT_PREFIX: char = '\u{0}';
+
+/// Sent to every client when the server shuts down, just before their connections close.
+const CLOSING_FRAME: &str = "\u{0}closing";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Heartbeat {
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Heartbeat {
+    fn encode(&self) -> String {
+        match self {
+            Heartbeat::Ping(nonce) => format!("{}ping {}", HEARTBEAT_PREFIX, nonce),
+            Heartbeat::Pong(nonce) => format!("{}pong {}", HEARTBEAT_PREFIX, nonce),
+        }
+    }
+
+    fn parse(payload: &str) -> Option<Heartbeat> {
+        let rest = payload.strip_prefix(HEARTBEAT_PREFIX)?;
+        let (kind, nonce) = rest.split_once(' ')?;
+        let nonce = nonce.parse().ok()?;
+        match kind {
+            "ping" => Some(Heartbeat::Ping(nonce)),
+            "pong" => Some(Heartbeat::Pong(nonce)),
+            _ => None,
+        }
+    }
+}
+
+/// How often each side pings, and how many unanswered pings in a row close the connection.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(1),
+            max_missed: 5,
+        }
+    }
+}
+
+struct LatencyState {
+    smoothed_rtt: Option<Duration>,
+    next_nonce: u64,
+    outstanding: Option<(u64, Instant)>,
+    missed: u32,
+}
+
+/// Smoothed round-trip time of one connection, measured by heartbeats.
+/// Cloning gives another handle to the same measurement.
+#[derive(Clone)]
+pub struct LatencyTracker {
+    state: Arc<Mutex<LatencyState>>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        LatencyTracker {
+            state: Arc::new(Mutex::new(LatencyState {
+                smoothed_rtt: None,
+                next_nonce: 0,
+                outstanding: None,
+                missed: 0,
+            })),
+        }
+    }
+
+    /// Smoothed round-trip time, or `None` until the first pong arrives.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().smoothed_rtt
+    }
+
+    /// Pings sent in a row without an answer.
+    pub fn missed(&self) -> u32 {
+        self.state.lock().unwrap().missed
+    }
+
+    fn start_ping(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if state.outstanding.is_some() {
+            state.missed += 1;
+        }
+        state.next_nonce += 1;
+        state.outstanding = Some((state.next_nonce, Instant::now()));
+        state.next_nonce
+    }
+
+    /// Records a pong and returns the new smoothed RTT. Stale or unknown nonces are ignored.
+    fn finish_ping(&self, nonce: u64) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (expected, sent_at) = state.outstanding?;
+        if expected != nonce {
+            return None;
+        }
+        let sample = sent_at.elapsed();
+        state.outstanding = None;
+        state.missed = 0;
+        // Same 1/8 weighting TCP uses for its smoothed RTT
+        let smoothed = match state.smoothed_rtt {
+            Some(previous) => previous.mul_f64(7.0 / 8.0) + sample.mul_f64(1.0 / 8.0),
+            None => sample,
+        };
+        state.smoothed_rtt = Some(smoothed);
+        Some(smoothed)
+    }
+}
+
+/// Pings the peer every `config.interval` until the connection ends. Shuts the stream down once
+/// `config.max_missed` pings in a row went unanswered, which ends the connection's read loop.
+/// A ping that cannot even be written in that time, because the peer stopped reading, counts
+/// the same.
+async fn run_heartbeat(mut stream: NetStream, tracker: LatencyTracker, config: HeartbeatConfig) {
+    loop {
+        task::sleep(config.interval).await;
+        if tracker.missed() >= config.max_missed {
+            eprintln!("Connection timed out after {} missed heartbeats", config.max_missed);
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            break;
+        }
+        let ping = Heartbeat::Ping(tracker.start_ping());
+        let limit = config.interval * config.max_missed.saturating_sub(tracker.missed()).max(1);
+        if let Err(e) = stream.write_frame_within(ping.encode().as_bytes(), limit).await {
+            if e.kind() == ErrorKind::TimedOut {
+                eprintln!("Connection timed out after {} missed heartbeats", config.max_missed);
+            }
+            break;
+        }
+    }
+}
+
+/// Answers a ping or records a pong. Returns the new smoothed RTT when a pong was recorded.
+async fn handle_heartbeat(stream: &mut NetStream, heartbeat: Heartbeat, tracker: &LatencyTracker) -> async_std::io::Result<Option<Duration>> {
+    match heartbeat {
+        Heartbeat::Ping(nonce) => {
+            stream.write_frame(Heartbeat::Pong(nonce).encode().as_bytes()).await?;
+            Ok(None)
+        }
+        Heartbeat::Pong(nonce) => Ok(tracker.finish_ping(nonce)),
+    }
+}
+
+/// Like `recv_frame`, but answers pings and skips heartbeat frames until a real message arrives.
+async fn recv_message(stream: &mut NetStream) -> async_std::io::Result<Option<String>> {
+    loop {
+        match recv_decoded(stream, MAX_FRAME_SIZE).await? {
+            Some(payload) if payload == CLOSING_FRAME => return Ok(None),
+            Some(payload) => match Heartbeat::parse(&payload) {
+                Some(Heartbeat::Ping(nonce)) => stream.write_frame(Heartbeat::Pong(nonce).encode().as_bytes()).await?,
+                Some(Heartbeat::Pong(_)) => {}
+                None => {
+                    stream.traffic.received_message();
+                    return Ok(Some(payload));
+                }
+            },
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Latest smoothed RTT of every connection the server is handling, keyed by socket id.
+#[derive(Clone)]
+pub struct ConnectionLatencies {
+    trackers: Arc<Mutex<HashMap<usize, LatencyTracker>>>,
+}
+
+impl ConnectionLatencies {
+    fn new() -> Self {
+        ConnectionLatencies { trackers: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn get(&self, socket_id: usize) -> Option<Duration> {
+        self.trackers.lock().unwrap().get(&socket_id).and_then(|t| t.rtt())
+    }
+}
+
+/// Stops a running `AsyncTcpServer`. Clone it out with `shutdown_handle` before moving the server
+/// into its task; `shutdown` can then be called from anywhere, any number of times.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    signal: (async_std::channel::Sender<()>, async_std::channel::Receiver<()>),
+    connections: Arc<Mutex<HashMap<usize, NetStream>>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        ShutdownHandle {
+            signal: async_std::channel::bounded(1),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Asks the server to stop. `run` / `run_with_messages` stop accepting, tell every client the
+    /// server is closing, wait for in-flight handlers up to the shutdown timeout, then return.
+    pub fn shutdown(&self) {
+        // Closing the channel wakes everything waiting on it
+        let _connections = self.connections.lock().unwrap();
+        self.signal.0.close();
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.signal.0.is_closed()
+    }
+
+    /// Resolves once `shutdown` has been called.
+    pub async fn wait(&self) {
+        while self.signal.1.recv().await.is_ok() {}
+    }
+
+    /// Number of connections whose handlers are still running.
+    pub fn open_connections(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// Starts tracking a connection, or returns false if the server is already shutting down.
+    fn track(&self, socket_id: usize, stream: &NetStream) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        if self.is_shut_down() {
+            return false;
+        }
+        connections.insert(socket_id, stream.clone());
+        true
+    }
+
+    fn untrack(&self, socket_id: usize) {
+        self.connections.lock().unwrap().remove(&socket_id);
+    }
+
+    fn tracked(&self) -> Vec<NetStream> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Runs `future` to completion, or returns `None` once `shutdown` is called.
+async fn until_shutdown<F: std::future::Future>(future: F, shutdown: &ShutdownHandle) -> Option<F::Output> {
+    let mut future = std::pin::pin!(future);
+    let mut stopped = std::pin::pin!(shutdown.wait());
+    std::future::poll_fn(|cx| {
+        if stopped.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        future.as_mut().poll(cx).map(Some)
+    }).await
+}
+
+/// Waits for the next connection, or returns `None` once the server is told to shut down.
+async fn accept_until_shutdown(listener: &TcpListener, shutdown: &ShutdownHandle) -> Option<async_std::io::Result<TcpStream>> {
+    until_shutdown(listener.accept(), shutdown).await
+        .map(|accepted| accepted.map(|(stream, _)| stream))
+}
+
+/// Tells a connection the server is closing and stops reading from it, so its read loop ends
+/// once the handler it is running (if any) finishes.
+async fn send_closing(stream: &mut NetStream) {
+    let _ = stream.write_frame(CLOSING_FRAME.as_bytes()).await;
+    let _ = stream.shutdown(std::net::Shutdown::Read);
+}
 
-pub type ClientHandler = Arc<dyn Fn(TcpStream) + Send + Sync + 'static>;
+pub type ClientHandler = Arc<dyn Fn(NetStream) + Send + Sync + 'static>;
+/// Called once when a connection served by `run_with_messages` ends, for whatever reason.
+pub type DisconnectHandler = Arc<dyn Fn(NetStream) + Send + Sync + 'static>;
+/// Called with a connection's socket id and its new smoothed RTT after every heartbeat.
+pub type LatencyHandler = Arc<dyn Fn(usize, Duration) + Send + Sync + 'static>;
+/// Called when a connection goes over its rate limits, with the step it was given
+/// (never `Allow`). Runs before a `Disconnect` closes the stream, so the client can still be told why.
+pub type RateLimitHandler = Arc<dyn Fn(NetStream, RateLimitStep) + Send + Sync + 'static>;
+/// Decides whether a freshly accepted connection from this address may stay, e.g. to keep banned
+/// addresses out. Refused connections are closed before the TLS handshake or any handler runs.
+pub type AcceptFilter = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync + 'static>;
 
 pub struct AsyncTcpServer {
     address: String,
     handler: ClientHandler,
+    disconnect_handler: Option<DisconnectHandler>,
+    latency_handler: Option<LatencyHandler>,
+    heartbeat: HeartbeatConfig,
+    latencies: ConnectionLatencies,
+    metrics: ServerMetrics,
+    tls: Option<Arc<ServerConfig>>,
+    rate_limits: RateLimits,
+    rate_limit_handler: Option<RateLimitHandler>,
+    accept_filter: Option<AcceptFilter>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    listener: Mutex<Option<TcpListener>>,
+    local_addr: Mutex<Option<SocketAddr>>,
 }
 
+/// How long a shutting-down server waits for in-flight handlers before closing their sockets.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl AsyncTcpServer {
 
     
@@ -24,20 +650,182 @@ impl AsyncTcpServer {
         Self {
             address: address.to_string(),
             handler,
+            disconnect_handler: None,
+            latency_handler: None,
+            heartbeat: HeartbeatConfig::default(),
+            latencies: ConnectionLatencies::new(),
+            metrics: ServerMetrics::new(),
+            tls: None,
+            rate_limits: RateLimits::default(),
+            rate_limit_handler: None,
+            accept_filter: None,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            listener: Mutex::new(None),
+            local_addr: Mutex::new(None),
+        }
+    }
+
+    /// Binds the listening socket without accepting yet, and returns the address it got. The
+    /// address can be anything `TcpListener::bind` takes, e.g. `0.0.0.0:5766`, `[::]:5766`, or
+    /// port 0 for a free port picked by the OS. `run` / `run_with_messages` bind on their own
+    /// if this was not called first.
+    pub async fn bind(&self) -> async_std::io::Result<SocketAddr> {
+        if let Some(addr) = self.local_addr() {
+            return Ok(addr);
+        }
+        let listener = TcpListener::bind(&self.address).await?;
+        let addr = listener.local_addr()?;
+        *self.listener.lock().unwrap() = Some(listener);
+        *self.local_addr.lock().unwrap() = Some(addr);
+        Ok(addr)
+    }
+
+    /// The address the server is listening on, once it is bound.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.lock().unwrap()
+    }
+
+    async fn take_listener(&self) -> async_std::io::Result<TcpListener> {
+        let addr = self.bind().await?;
+        let listener = self.listener.lock().unwrap().take();
+        match listener {
+            Some(listener) => {
+                println!("Server listening on {}", addr);
+                Ok(listener)
+            }
+            None => Err(io::Error::new(ErrorKind::AddrInUse, "server is already running")),
+        }
+    }
+
+    /// Handle for stopping the server once it is running.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Makes the server stop when `handle` is shut down, instead of using its own handle.
+    pub fn set_shutdown_handle(&mut self, handle: ShutdownHandle) {
+        self.shutdown = handle;
+    }
+
+    /// Changes how long `shutdown` waits for in-flight handlers before cutting their connections.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Sends the closing frame to every tracked connection, then waits for their handlers to
+    /// finish, closing whatever is still open when the timeout runs out.
+    async fn drain(&self) {
+        let connections = self.shutdown.tracked();
+        let addr = self.local_addr().map_or_else(|| self.address.clone(), |addr| addr.to_string());
+        println!("Server on {} shutting down, closing {} connection(s)", addr, connections.len());
+        for mut stream in connections {
+            send_closing(&mut stream).await;
+        }
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while self.shutdown.open_connections() > 0 && Instant::now() < deadline {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        for stream in self.shutdown.tracked() {
+            eprintln!("Handler for connection {} did not finish in time, closing it", Self::get_socket_id(&stream));
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    /// Changes the per-connection message, byte and frame size limits of `run_with_messages`.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limits = limits;
+    }
+
+    /// Sets the callback that runs when a connection is warned, throttled or disconnected for flooding.
+    pub fn set_rate_limit_handler(&mut self, handler: RateLimitHandler) {
+        self.rate_limit_handler = Some(handler);
+    }
+
+    /// Sets the check every new connection's address has to pass.
+    pub fn set_accept_filter(&mut self, filter: AcceptFilter) {
+        self.accept_filter = Some(filter);
+    }
+
+    /// Whether the accept filter lets a connection in. Ones it refuses are closed right away.
+    fn admit(&self, stream: &TcpStream) -> bool {
+        let (Some(filter), Ok(peer)) = (&self.accept_filter, stream.peer_addr()) else {
+            return true;
+        };
+        if filter(peer) {
+            return true;
         }
+        println!("Refused connection from {}", peer);
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        false
+    }
+
+    /// Makes the server run a TLS handshake on every connection before handling it.
+    pub fn set_tls(&mut self, config: Arc<ServerConfig>) {
+        self.tls = Some(config);
+    }
+
+    /// Wraps an accepted socket in TLS if the server has a TLS config.
+    async fn wrap_stream(tcp: TcpStream, tls: Option<Arc<ServerConfig>>) -> async_std::io::Result<NetStream> {
+        match tls {
+            Some(config) => NetStream::accept_tls(tcp, config).await,
+            None => Ok(NetStream::from(tcp)),
+        }
+    }
+
+    /// Sets the callback that runs after a client's connection closes or fails.
+    pub fn set_disconnect_handler(&mut self, handler: DisconnectHandler) {
+        self.disconnect_handler = Some(handler);
+    }
+
+    /// Sets the callback that gets each connection's smoothed RTT as heartbeats come back.
+    pub fn set_latency_handler(&mut self, handler: LatencyHandler) {
+        self.latency_handler = Some(handler);
+    }
+
+    /// Changes how often connections are pinged and how many misses close them.
+    pub fn set_heartbeat(&mut self, config: HeartbeatConfig) {
+        self.heartbeat = config;
+    }
+
+    /// Handle for reading per-connection latency while the server runs.
+    pub fn latencies(&self) -> ConnectionLatencies {
+        self.latencies.clone()
+    }
+
+    /// Handle for reading traffic, handler and connection numbers while the server runs.
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.clone()
     }
 
     /// Starts the TCP server and listens for incoming connections.
     pub async fn run(&self) -> async_std::io::Result<()> {
-        let listener = TcpListener::bind(&self.address).await?;
-        println!("Server listening on {}", self.address);
+        let listener = self.take_listener().await?;
 
-        while let Some(stream) = listener.incoming().next().await {
+        while let Some(stream) = accept_until_shutdown(&listener, &self.shutdown).await {
             match stream {
+                Ok(stream) if !self.admit(&stream) => {}
                 Ok(stream) => {
                     let handler = Arc::clone(&self.handler);
+                    let tls = self.tls.clone();
+                    let shutdown = self.shutdown.clone();
+                    let metrics = self.metrics.clone();
                     task::spawn(async move {
-                        handler(stream);
+                        match Self::wrap_stream(stream, tls).await {
+                            Ok(mut stream) => {
+                                let socket_id = Self::get_socket_id(&stream);
+                                if !shutdown.track(socket_id, &stream) {
+                                    send_closing(&mut stream).await;
+                                    return;
+                                }
+                                metrics.open(socket_id, stream.peer_addr().ok(), Arc::clone(&stream.traffic));
+                                handler(stream);
+                                metrics.close(socket_id);
+                                shutdown.untrack(socket_id);
+                            }
+                            Err(e) => eprintln!("TLS handshake failed: {}", e),
+                        }
                     });
                 }
                 Err(e) => {
@@ -46,72 +834,160 @@ impl AsyncTcpServer {
             }
         }
 
+        drop(listener);
+        self.drain().await;
         Ok(())
     }
 
     /// Starts the TCP server with bidirectional communication.
     pub async fn run_with_messages<F, Fut>(&self, message_handler: F) -> async_std::io::Result<()>
     where
-        F: Fn(String, TcpStream) -> Fut + Send + Sync + 'static,
+        F: Fn(String, NetStream) -> Fut + Send + Sync + 'static,
         Fut: std::future::Future<Output = async_std::io::Result<()>> + Send + 'static,
     {
-        let listener = TcpListener::bind(&self.address).await?;
-        println!("Server listening on {}", self.address);
+        let listener = self.take_listener().await?;
 
         let message_handler = Arc::new(message_handler); // Wrap in Arc once
 
-        while let Some(stream) = listener.incoming().next().await {
+        while let Some(stream) = accept_until_shutdown(&listener, &self.shutdown).await {
             match stream {
+                Ok(stream) if !self.admit(&stream) => {}
                 Ok(stream) => {
                     let handler_clone = Arc::clone(&message_handler); // Clone Arc for this iteration
+                    let disconnect_handler = self.disconnect_handler.clone();
+                    let latency_handler = self.latency_handler.clone();
+                    let latencies = self.latencies.clone();
+                    let heartbeat_config = self.heartbeat;
+                    let tls = self.tls.clone();
+                    let rate_limits = self.rate_limits;
+                    let rate_limit_handler = self.rate_limit_handler.clone();
+                    let shutdown = self.shutdown.clone();
+                    let metrics = self.metrics.clone();
 
                     task::spawn(async move {
-                        let mut buffer = [0; 1024];
-                        let mut stream = stream; // Make stream mutable
+                        let mut stream = match Self::wrap_stream(stream, tls).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                eprintln!("TLS handshake failed: {}", e);
+                                return;
+                            }
+                        };
+                        let socket_id = Self::get_socket_id(&stream);
+                        if !shutdown.track(socket_id, &stream) {
+                            send_closing(&mut stream).await;
+                            return;
+                        }
+                        metrics.open(socket_id, stream.peer_addr().ok(), Arc::clone(&stream.traffic));
+                        let tracker = LatencyTracker::new();
+                        latencies.trackers.lock().unwrap().insert(socket_id, tracker.clone());
+                        let heartbeat = task::spawn(run_heartbeat(stream.clone(), tracker.clone(), heartbeat_config));
+                        let mut limiter = RateLimiter::new(rate_limits);
 
                         loop {
-                            match stream.read(&mut buffer).await {
-                                Ok(0) => break, // Connection closed.
-                                Ok(n) => {
-                                    let received = String::from_utf8_lossy(&buffer[..n]).to_string();
-                                    println!("Server received: {}", received);
+                            match recv_frame_bytes(&mut stream, rate_limits.max_frame_size).await {
+                                Ok(None) => break, // Connection closed.
+                                Ok(Some(payload)) => {
+                                    let size = payload.len();
+                                    let step = limiter.check(size);
+                                    match step {
+                                        RateLimitStep::Allow => {}
+                                        RateLimitStep::Warn => {
+                                            println!("Connection {} is over its rate limit, warning it", socket_id);
+                                        }
+                                        RateLimitStep::Throttle(wait) => {
+                                            println!("Connection {} is still over its rate limit, throttling for {}ms", socket_id, wait.as_millis());
+                                        }
+                                        RateLimitStep::Disconnect => {
+                                            println!("Connection {} went over its rate limit {} times, disconnecting", socket_id, limiter.violations());
+                                        }
+                                    }
+                                    if step != RateLimitStep::Allow {
+                                        if let Some(on_rate_limit) = &rate_limit_handler {
+                                            on_rate_limit(stream.clone(), step);
+                                        }
+                                    }
+                                    match step {
+                                        RateLimitStep::Throttle(wait) => task::sleep(wait).await,
+                                        RateLimitStep::Disconnect => break,
+                                        _ => {}
+                                    }
+                                    let received = match decode_frame(&stream, payload) {
+                                        Ok(received) => received,
+                                        Err(e) => {
+                                            println!("Connection {} sent a frame its codec cannot read, disconnecting: {}", socket_id, e);
+                                            break;
+                                        }
+                                    };
+
+                                    if let Some(beat) = Heartbeat::parse(&received) {
+                                        match handle_heartbeat(&mut stream, beat, &tracker).await {
+                                            Ok(Some(rtt)) => {
+                                                if let Some(on_latency) = &latency_handler {
+                                                    on_latency(socket_id, rtt);
+                                                }
+                                            }
+                                            Ok(None) => {}
+                                            Err(e) => {
+                                                eprintln!("Failed to answer heartbeat: {}", e);
+                                                break;
+                                            }
+                                        }
+                                        continue;
+                                    }
+
+                                    stream.traffic.received_message();
 
                                     // Call the asynchronous message handler.
-                                    if let Err(e) = handler_clone(received, stream.clone()).await {
+                                    let handled = handler_clone(received, stream.clone()).await;
+                                    if let Err(e) = handled {
                                         eprintln!("Error handling message: {}", e);
                                         break;
                                     }
                                 }
+                                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                                    println!("Connection {} sent a bad frame, disconnecting: {}", socket_id, e);
+                                    break;
+                                }
                                 Err(e) => {
                                     eprintln!("Failed to read from client: {}", e);
                                     break;
                                 }
                             }
                         }
+
+                        heartbeat.cancel().await;
+                        latencies.trackers.lock().unwrap().remove(&socket_id);
+                        metrics.close(socket_id);
+                        let _ = stream.shutdown(std::net::Shutdown::Both);
+
+                        if let Some(on_disconnect) = disconnect_handler {
+                            on_disconnect(stream);
+                        }
+                        shutdown.untrack(socket_id);
                     });
                 }
                 Err(e) => eprintln!("Failed to accept connection: {}", e),
             }
         }
 
+        drop(listener);
+        self.drain().await;
         Ok(())
     }
 
-    /// Sends a message over the given TcpStream.
-    pub async fn send(stream: &mut TcpStream, message: &str) -> async_std::io::Result<()> {
-        stream.write_all(message.as_bytes()).await?;
-        stream.flush().await
+    /// Sends a message over the given NetStream as a single frame, encoded with its codec.
+    pub async fn send(stream: &mut NetStream, message: &str) -> async_std::io::Result<()> {
+        send_encoded(stream, message).await
     }
 
-    /// Receives a message from the given TcpStream.
-    pub async fn receive(stream: &mut TcpStream) -> async_std::io::Result<String> {
-        let mut buffer = vec![0u8; 1024];
-        let n = stream.read(&mut buffer).await?;
-        Ok(String::from_utf8_lossy(&buffer[..n]).to_string())
+    /// Receives one whole message from the given NetStream, answering any heartbeats on the way.
+    pub async fn receive(stream: &mut NetStream) -> async_std::io::Result<String> {
+        recv_message(stream).await?
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "connection closed"))
     }
 
-    /// Gets the socket ID of a TcpStream.
-    pub fn get_socket_id(stream: &TcpStream) -> usize {
+    /// Gets the socket ID of a NetStream.
+    pub fn get_socket_id(stream: &NetStream) -> usize {
         #[cfg(unix)]
         {
             stream.as_raw_fd() as usize
@@ -122,19 +998,103 @@ impl AsyncTcpServer {
         }
     }
 
+    /// Closes one client's connection the way a shutdown does, so the client knows not to
+    /// reconnect. Its read loop ends and the disconnect handler runs as usual.
+    pub async fn close(stream: &mut NetStream) {
+        send_closing(stream).await;
+    }
+
     /// Sends a message to a specific client identified by socket ID
-    pub async fn send_to_socket(stream: &mut TcpStream, message: &str, target_socket_id: usize) -> async_std::io::Result<()> {
+    pub async fn send_to_socket(stream: &mut NetStream, message: &str, target_socket_id: usize) -> async_std::io::Result<()> {
         if Self::get_socket_id(stream) == target_socket_id {
-            stream.write_all(message.as_bytes()).await?;
-            stream.flush().await
+            send_encoded(stream, message).await
         } else {
             Ok(())
         }
     }
 }
 
+/// Where a client's connection to the server stands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// The connection dropped (or was never made) and `attempt` is the retry in progress, from 1.
+    Reconnecting { attempt: u32 },
+    /// Not connected and no longer trying, either before the first connect or after every retry failed.
+    Lost,
+    /// The server said it was shutting down, so there is nothing to reconnect to.
+    Closed,
+}
+
+/// Shared view of a client's `ConnectionState`. Poll it with `get` or watch it with `subscribe`.
+#[derive(Clone)]
+pub struct ConnectionStatus {
+    inner: Arc<Mutex<(ConnectionState, Vec<async_std::channel::Sender<ConnectionState>>)>>,
+}
+
+impl ConnectionStatus {
+    fn new() -> Self {
+        ConnectionStatus { inner: Arc::new(Mutex::new((ConnectionState::Lost, Vec::new()))) }
+    }
+
+    pub fn get(&self) -> ConnectionState {
+        self.inner.lock().unwrap().0
+    }
+
+    /// Gets every state change from now on.
+    pub fn subscribe(&self) -> async_std::channel::Receiver<ConnectionState> {
+        let (tx, rx) = async_std::channel::unbounded();
+        self.inner.lock().unwrap().1.push(tx);
+        rx
+    }
+
+    fn set(&self, state: ConnectionState) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.0 == state {
+            return;
+        }
+        inner.0 = state;
+        // Watchers that dropped their receiver are forgotten
+        inner.1.retain(|watcher| watcher.try_send(state).is_ok());
+    }
+}
+
+/// How `AsyncTcpClient` retries a connection: exponential backoff from `initial_delay`, doubling
+/// up to `max_delay`, giving up after `max_attempts` tries if that is set.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    /// Keeps trying for just under a minute, so a reconnect still lands inside the server's
+    /// session resume window.
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(5),
+            max_attempts: Some(15),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait before the given attempt (counting from 1).
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16);
+        self.initial_delay.saturating_mul(1 << doublings).min(self.max_delay)
+    }
+}
+
 pub struct AsyncTcpClient {
     address: String,
+    heartbeat: HeartbeatConfig,
+    latency: LatencyTracker,
+    reconnect: ReconnectPolicy,
+    status: ConnectionStatus,
+    tls: Option<(Arc<ClientConfig>, ServerName<'static>)>,
 }
 
 impl AsyncTcpClient {
@@ -142,30 +1102,147 @@ impl AsyncTcpClient {
     pub fn new(address: &str) -> Self {
         Self {
             address: address.to_string(),
+            heartbeat: HeartbeatConfig::default(),
+            latency: LatencyTracker::new(),
+            reconnect: ReconnectPolicy::default(),
+            status: ConnectionStatus::new(),
+            tls: None,
         }
     }
 
-    pub async fn connect(&self) -> async_std::io::Result<TcpStream> {
-        let stream = TcpStream::connect(&self.address).await?;
+    /// Makes `connect` wrap the socket in TLS and check the server certificate against `server_name`.
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>, server_name: ServerName<'static>) {
+        self.tls = Some((config, server_name));
+    }
+
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = policy;
+    }
+
+    /// Handle to this client's connection state.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.clone()
+    }
+
+    /// Changes how often `handle_messages` pings the server and how many misses end it.
+    pub fn set_heartbeat(&mut self, config: HeartbeatConfig) {
+        self.heartbeat = config;
+    }
+
+    /// Handle to the round-trip time measured by `handle_messages`.
+    pub fn latency(&self) -> LatencyTracker {
+        self.latency.clone()
+    }
+
+    pub async fn connect(&self) -> async_std::io::Result<NetStream> {
+        let stream = self.open().await?;
+        self.status.set(ConnectionState::Connected);
+        Ok(stream)
+    }
+
+    /// Opens the socket (and TLS) without touching the connection state.
+    async fn open(&self) -> async_std::io::Result<NetStream> {
+        let tcp = TcpStream::connect(&self.address).await?;
+        let stream = match &self.tls {
+            Some((config, server_name)) => NetStream::connect_tls(tcp, config.clone(), server_name.clone()).await?,
+            None => NetStream::from(tcp),
+        };
         println!("Connected to server at {}", self.address);
         Ok(stream)
     }
 
-    /// Sends a message over the given TcpStream.
-    pub async fn send(stream: &mut TcpStream, message: &str) -> async_std::io::Result<()> {
-        stream.write_all(message.as_bytes()).await?;
-        stream.flush().await
+    /// Connects, retrying with backoff as the reconnect policy says. The state is `Reconnecting`
+    /// while it retries and `Lost` if it gives up, in which case the last error is returned.
+    pub async fn connect_with_retry(&self) -> async_std::io::Result<NetStream> {
+        let stream = self.open_with_retry().await?;
+        self.status.set(ConnectionState::Connected);
+        Ok(stream)
+    }
+
+    /// Like `connect_with_retry`, but leaves the state at `Reconnecting` once the socket is open.
+    async fn open_with_retry(&self) -> async_std::io::Result<NetStream> {
+        let mut attempt = 0;
+        loop {
+            match self.open().await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    attempt += 1;
+                    if self.reconnect.max_attempts.is_some_and(|max| attempt > max) {
+                        eprintln!("Giving up on {} after {} retries: {}", self.address, attempt - 1, e);
+                        self.status.set(ConnectionState::Lost);
+                        return Err(e);
+                    }
+                    let delay = self.reconnect.delay_for(attempt);
+                    println!("Connection attempt {} failed ({}), retrying in {}ms...", attempt, e, delay.as_millis());
+                    self.status.set(ConnectionState::Reconnecting { attempt });
+                    task::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Runs `handle_messages` on `stream`, and every time the connection drops, reconnects and
+    /// calls `on_reconnect` with the new stream before handling messages again. `on_reconnect` is
+    /// where the caller re-joins and asks for a fresh snapshot; if it fails, the new stream is
+    /// dropped and the client reconnects again. The state only goes back to `Connected` once
+    /// `on_reconnect` has succeeded. Returns once the reconnect policy gives up, or
+    /// with `Ok` (and the state `Closed`) when the server says it is shutting down.
+    pub async fn run_with_reconnect<R, RFut, F, Fut>(&self, mut stream: NetStream, on_reconnect: R, message_handler: F) -> async_std::io::Result<()>
+    where
+        R: Fn(NetStream) -> RFut,
+        RFut: std::future::Future<Output = async_std::io::Result<()>>,
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = async_std::io::Result<()>> + Send + 'static,
+    {
+        let message_handler = Arc::new(message_handler);
+        loop {
+            let handler = message_handler.clone();
+            if let Err(e) = self.handle_messages(&mut stream, move |msg| handler(msg)).await {
+                eprintln!("Connection error: {}", e);
+            }
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            if self.status.get() == ConnectionState::Closed {
+                return Ok(());
+            }
+            self.status.set(ConnectionState::Reconnecting { attempt: 1 });
+
+            let mut failed_resyncs = 0;
+            loop {
+                stream = self.open_with_retry().await?;
+                match on_reconnect(stream.clone()).await {
+                    Ok(()) => {
+                        self.status.set(ConnectionState::Connected);
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("Resync after reconnect failed: {}", e);
+                        failed_resyncs += 1;
+                        if self.reconnect.max_attempts.is_some_and(|max| failed_resyncs >= max) {
+                            self.status.set(ConnectionState::Lost);
+                            return Err(e);
+                        }
+                        let _ = stream.shutdown(std::net::Shutdown::Both);
+                        self.status.set(ConnectionState::Reconnecting { attempt: 1 });
+                        task::sleep(self.reconnect.initial_delay).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a message over the given NetStream as a single frame, encoded with its codec.
+    pub async fn send(stream: &mut NetStream, message: &str) -> async_std::io::Result<()> {
+        send_encoded(stream, message).await
     }
 
-    /// Receives a message from the given TcpStream.
-    pub async fn receive(stream: &mut TcpStream) -> async_std::io::Result<String> {
-        let mut buffer = vec![0u8; 1024];
-        let n = stream.read(&mut buffer).await?;
-        Ok(String::from_utf8_lossy(&buffer[..n]).to_string())
+    /// Receives one whole message from the given NetStream, answering any heartbeats on the way.
+    pub async fn receive(stream: &mut NetStream) -> async_std::io::Result<String> {
+        recv_message(stream).await?
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "connection closed"))
     }
 
-    /// Gets the socket ID of a TcpStream.
-    pub fn get_socket_id(stream: &TcpStream) -> usize {
+    /// Gets the socket ID of a NetStream.
+    pub fn get_socket_id(stream: &NetStream) -> usize {
         #[cfg(unix)]
         {
             stream.as_raw_fd() as usize
@@ -176,41 +1253,54 @@ impl AsyncTcpClient {
         }
     }
 
-    /// Starts a continuous message handling loop
-    pub async fn handle_messages<F, Fut>(&self, stream: &mut TcpStream, message_handler: F) -> async_std::io::Result<()>
+    /// Starts a continuous message handling loop, pinging the server in the background.
+    pub async fn handle_messages<F, Fut>(&self, stream: &mut NetStream, message_handler: F) -> async_std::io::Result<()>
     where
         F: Fn(String) -> Fut + Send + Sync + 'static,
         Fut: std::future::Future<Output = async_std::io::Result<()>> + Send + 'static,
     {
-        let mut buffer = [0; 1024];
-        loop {
-            match stream.read(&mut buffer).await {
-                Ok(0) => break, // Connection closed
-                Ok(n) => {
-                    let received = String::from_utf8_lossy(&buffer[..n]).to_string();
-                    message_handler(received).await?;
+        let heartbeat = task::spawn(run_heartbeat(stream.clone(), self.latency.clone(), self.heartbeat));
+
+        let result = loop {
+            match recv_decoded(stream, MAX_FRAME_SIZE).await {
+                Ok(None) => break Ok(()), // Connection closed
+                Ok(Some(received)) if received == CLOSING_FRAME => {
+                    println!("Server is shutting down");
+                    self.status.set(ConnectionState::Closed);
+                    break Ok(());
+                }
+                Ok(Some(received)) => {
+                    if let Some(beat) = Heartbeat::parse(&received) {
+                        if let Err(e) = handle_heartbeat(stream, beat, &self.latency).await {
+                            break Err(e);
+                        }
+                        continue;
+                    }
+                    if let Err(e) = message_handler(received).await {
+                        break Err(e);
+                    }
                 }
                 Err(e) => {
                     eprintln!("Failed to read from server: {}", e);
-                    break;
+                    break Ok(());
                 }
             }
-        }
-        Ok(())
+        };
+
+        heartbeat.cancel().await;
+        result
     }
 
     /// Starts an interactive session with the server
-    pub async fn start_interactive_session(mut stream: TcpStream) -> async_std::io::Result<()> {
+    pub async fn start_interactive_session(mut stream: NetStream) -> async_std::io::Result<()> {
         let mut read_stream = stream.clone();
         
         // Spawn a task to handle incoming messages
         task::spawn(async move {
-            let mut buffer = [0; 1024];
             loop {
-                match read_stream.read(&mut buffer).await {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        let msg = String::from_utf8_lossy(&buffer[..n]);
+                match recv_message(&mut read_stream).await {
+                    Ok(None) => break,
+                    Ok(Some(msg)) => {
                         println!("Received: {}", msg);
                     }
                     Err(e) => {
@@ -231,7 +1321,7 @@ impl AsyncTcpClient {
             if input.trim() == "quit" {
                 break;
             }
-            if let Err(e) = Self::send(&mut stream, &input).await {
+            if let Err(e) = Self::send(&mut stream, input.trim_end()).await {
                 eprintln!("Error sending: {}", e);
                 break;
             }
@@ -241,25 +1331,600 @@ impl AsyncTcpClient {
     }
 }
 
+/// Largest datagram payload. Position updates are a few hundred bytes at most, and staying
+/// under a typical MTU keeps datagrams from being fragmented.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Unreliable channel next to the TCP connection. Datagrams can be lost, duplicated or arrive out
+/// of order, so it only suits state that the next datagram replaces anyway, like positions.
+#[derive(Clone)]
+pub struct AsyncUdpSocket {
+    socket: Arc<UdpSocket>,
+}
+
+impl AsyncUdpSocket {
+    pub async fn bind(address: &str) -> async_std::io::Result<Self> {
+        let socket = UdpSocket::bind(address).await?;
+        Ok(AsyncUdpSocket { socket: Arc::new(socket) })
+    }
+
+    pub fn local_addr(&self) -> async_std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Allows sending to broadcast addresses.
+    pub fn set_broadcast(&self, on: bool) -> async_std::io::Result<()> {
+        self.socket.set_broadcast(on)
+    }
+
+    /// Starts receiving datagrams sent to the multicast `group` on `interface`.
+    pub fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> async_std::io::Result<()> {
+        self.socket.join_multicast_v4(group, interface)
+    }
+
+    /// Sends one message as a single datagram.
+    pub async fn send_to(&self, message: &str, target: SocketAddr) -> async_std::io::Result<()> {
+        if message.len() > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("datagram of {} bytes exceeds limit of {}", message.len(), MAX_DATAGRAM_SIZE),
+            ));
+        }
+        self.socket.send_to(message.as_bytes(), target).await?;
+        Ok(())
+    }
+
+    /// Waits for the next datagram and returns its text and sender.
+    pub async fn recv_from(&self) -> async_std::io::Result<(String, SocketAddr)> {
+        // One byte of slack tells an oversized datagram (which the OS truncates) apart from a full one
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE + 1];
+        let (len, from) = self.socket.recv_from(&mut buffer).await?;
+        if len > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("oversized datagram from {}", from)));
+        }
+        let text = String::from_utf8(buffer[..len].to_vec())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok((text, from))
+    }
+
+    /// Calls `handler` for every datagram that arrives. Bad datagrams are logged and skipped,
+    /// since anyone can send to a UDP port.
+    pub async fn run_with_messages<F, Fut>(&self, handler: F) -> async_std::io::Result<()>
+    where
+        F: Fn(String, SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.run_until_shutdown(&ShutdownHandle::new(), handler).await
+    }
+
+    /// Like `run_with_messages`, but returns `Ok` once `shutdown` is called, e.g. together with
+    /// the TCP server sharing the handle.
+    pub async fn run_until_shutdown<F, Fut>(&self, shutdown: &ShutdownHandle, handler: F) -> async_std::io::Result<()>
+    where
+        F: Fn(String, SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        loop {
+            let Some(received) = until_shutdown(self.recv_from(), shutdown).await else {
+                return Ok(());
+            };
+            match received {
+                Ok((message, from)) => handler(message, from).await,
+                Err(e) if e.kind() == ErrorKind::InvalidData => eprintln!("Ignoring datagram: {}", e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Keeps the newest sequence number seen per source so late or duplicated datagrams can be
+/// dropped. Sequence numbers wrap, so "newer" means less than half the range ahead.
+pub struct SequenceFilter<K> {
+    latest: HashMap<K, u32>,
+}
+
+impl<K: Eq + Hash> SequenceFilter<K> {
+    pub fn new() -> Self {
+        SequenceFilter { latest: HashMap::new() }
+    }
+
+    /// Whether `seq` is newer than anything seen from `key` so far. Records it if so.
+    pub fn accept(&mut self, key: K, seq: u32) -> bool {
+        match self.latest.get(&key) {
+            Some(&last) if (seq.wrapping_sub(last) as i32) <= 0 => false,
+            _ => {
+                self.latest.insert(key, seq);
+                true
+            }
+        }
+    }
+
+    /// Forgets a source, e.g. when it reconnects and starts counting again.
+    pub fn forget(&mut self, key: &K) {
+        self.latest.remove(key);
+    }
+}
+
+/// Network trouble for `ConditionedProxy` to cause. The default is a perfect network.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NetworkConditions {
+    /// Added to everything, each way.
+    pub latency: Duration,
+    /// Up to this much more, picked at random per chunk or datagram.
+    pub jitter: Duration,
+    /// Bytes per second each way on every TCP connection.
+    pub bandwidth: Option<f64>,
+    /// Chance that a datagram is dropped.
+    pub udp_loss: f64,
+    /// Chance that a datagram is held back by REORDER_DELAY, so later ones overtake it.
+    pub udp_reorder: f64,
+    /// Chance, per chunk forwarded, that a TCP connection is cut.
+    pub disconnect_chance: f64,
+}
+
+/// Extra delay for datagrams picked for reordering.
+pub const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+impl NetworkConditions {
+    /// Reads a debug setting like "latency=100,jitter=20,bandwidth=50000,loss=0.05,reorder=0.1,disconnect=0.001".
+    /// Times are in milliseconds, bandwidth in bytes per second and the rest are chances from 0 to 1.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut conditions = NetworkConditions::default();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let (key, value) = part.split_once('=').ok_or_else(|| format!("expected key=value, got {:?}", part))?;
+            let number = value.trim().parse::<f64>().ok()
+                .filter(|n| n.is_finite() && *n >= 0.0)
+                .ok_or_else(|| format!("{} must be a number of at least 0, got {:?}", key.trim(), value))?;
+            let millis = Duration::from_secs_f64(number / 1000.0);
+            match key.trim() {
+                "latency" => conditions.latency = millis,
+                "jitter" => conditions.jitter = millis,
+                // A rate of 0 would hold the first chunk forever
+                "bandwidth" if number == 0.0 => return Err("bandwidth must be more than 0".to_string()),
+                "bandwidth" => conditions.bandwidth = Some(number),
+                "loss" => conditions.udp_loss = number,
+                "reorder" => conditions.udp_reorder = number,
+                "disconnect" => conditions.disconnect_chance = number,
+                other => return Err(format!("unknown network condition {:?}", other)),
+            }
+        }
+        Ok(conditions)
+    }
+
+    /// How long to hold one chunk or datagram: the latency plus a random part of the jitter.
+    fn delay(&self) -> Duration {
+        self.latency + self.jitter.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// A local proxy that forwards TCP and UDP on one port to a server under `NetworkConditions`,
+/// for reproducing lag bugs on loopback. Point a client at `local_addr` instead of the server.
+/// It forwards raw bytes, so framing, codecs and TLS pass through untouched.
+pub struct ConditionedProxy {
+    local_addr: SocketAddr,
+    stop: ShutdownHandle,
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl ConditionedProxy {
+    /// Listens on `bind` (use port 0 for a free one) and forwards to `target`, which takes TCP
+    /// and UDP on the same port like AsyncTcpServer with its UDP channel.
+    pub async fn start(bind: &str, target: SocketAddr, conditions: NetworkConditions) -> io::Result<Self> {
+        let listener = TcpListener::bind(bind).await?;
+        let local_addr = listener.local_addr()?;
+        let udp = Arc::new(UdpSocket::bind(local_addr).await?);
+        let stop = ShutdownHandle::new();
+        let connections: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let accept_stop = stop.clone();
+        let accept_connections = Arc::clone(&connections);
+        task::spawn(async move {
+            while let Some(accepted) = accept_until_shutdown(&listener, &accept_stop).await {
+                let client = match accepted {
+                    Ok(client) => client,
+                    Err(e) => {
+                        eprintln!("Proxy accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let server = match TcpStream::connect(target).await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        eprintln!("Proxy could not reach {}: {}", target, e);
+                        continue;
+                    }
+                };
+                accept_connections.lock().unwrap().extend([client.clone(), server.clone()]);
+                task::spawn(proxy_pump(client.clone(), server.clone(), conditions));
+                task::spawn(proxy_pump(server, client, conditions));
+            }
+        });
+        task::spawn(relay_datagrams(udp, target, conditions, stop.clone()));
+
+        Ok(ConditionedProxy { local_addr, stop, connections })
+    }
+
+    /// Where clients should connect, over TCP and UDP alike.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting, cuts every connection going through the proxy and stops relaying datagrams.
+    pub fn stop(&self) {
+        self.stop.shutdown();
+        for stream in self.connections.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// Copies bytes one way under `conditions`. Chunks keep their order as TCP would, so jitter only
+/// ever adds delay. Ends when either side closes or a simulated disconnect cuts both.
+async fn proxy_pump(mut from: TcpStream, mut to: TcpStream, conditions: NetworkConditions) {
+    let (queue, queued) = async_std::channel::unbounded::<(Instant, Vec<u8>)>();
+    let cut = to.clone();
+    let reader = async move {
+        let mut buffer = vec![0u8; 16 * 1024];
+        let mut last = Instant::now();
+        loop {
+            let read = match from.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            if rand::random::<f64>() < conditions.disconnect_chance {
+                let _ = from.shutdown(std::net::Shutdown::Both);
+                let _ = cut.shutdown(std::net::Shutdown::Both);
+                break;
+            }
+            let deliver_at = (Instant::now() + conditions.delay()).max(last);
+            last = deliver_at;
+            if queue.send((deliver_at, buffer[..read].to_vec())).await.is_err() {
+                break;
+            }
+        }
+    };
+    let writer = task::spawn(async move {
+        let mut bandwidth = conditions.bandwidth.map(|rate| TokenBucket::new(rate, rate));
+        while let Ok((deliver_at, bytes)) = queued.recv().await {
+            task::sleep(deliver_at.saturating_duration_since(Instant::now())).await;
+            if let Some(bucket) = bandwidth.as_mut() {
+                task::sleep(bucket.time_until(bytes.len() as f64, Instant::now())).await;
+                bucket.take(bytes.len() as f64, Instant::now());
+            }
+            if to.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown(std::net::Shutdown::Write);
+    });
+    reader.await;
+    writer.await;
+}
+
+/// Forwards datagrams between clients and `target`, with one upstream socket per client so the
+/// answers find their way back. Each datagram is delayed, dropped or reordered on its own.
+async fn relay_datagrams(socket: Arc<UdpSocket>, target: SocketAddr, conditions: NetworkConditions, stop: ShutdownHandle) {
+    let mut upstreams: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let (length, client) = match until_shutdown(socket.recv_from(&mut buffer), &stop).await {
+            None => break,
+            Some(Ok(received)) => received,
+            Some(Err(_)) => continue,
+        };
+        let upstream = match upstreams.get(&client) {
+            Some(upstream) => Arc::clone(upstream),
+            None => {
+                let bind = if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+                let upstream = match UdpSocket::bind(bind).await {
+                    Ok(upstream) => Arc::new(upstream),
+                    Err(e) => {
+                        eprintln!("Proxy could not open a UDP socket: {}", e);
+                        continue;
+                    }
+                };
+                // Answers from the server go back to this client until the proxy stops
+                let (from_server, to_client, upstream_stop) = (Arc::clone(&upstream), Arc::clone(&socket), stop.clone());
+                task::spawn(async move {
+                    let mut buffer = vec![0u8; 64 * 1024];
+                    while let Some(Ok((length, _))) = until_shutdown(from_server.recv_from(&mut buffer), &upstream_stop).await {
+                        deliver_datagram(Arc::clone(&to_client), buffer[..length].to_vec(), client, conditions);
+                    }
+                });
+                upstreams.insert(client, Arc::clone(&upstream));
+                upstream
+            }
+        };
+        deliver_datagram(upstream, buffer[..length].to_vec(), target, conditions);
+    }
+}
+
+fn deliver_datagram(socket: Arc<UdpSocket>, datagram: Vec<u8>, to: SocketAddr, conditions: NetworkConditions) {
+    if rand::random::<f64>() < conditions.udp_loss {
+        return;
+    }
+    let mut delay = conditions.delay();
+    if rand::random::<f64>() < conditions.udp_reorder {
+        delay += REORDER_DELAY;
+    }
+    task::spawn(async move {
+        task::sleep(delay).await;
+        let _ = socket.send_to(&datagram, to).await;
+    });
+}
+
+/// A message for one client, put together while the game is locked and sent once it no longer
+/// is. `datagram` goes over UDP when the client has a UDP address; otherwise `message` goes over TCP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outgoing {
+    pub to: u32,
+    pub message: String,
+    pub datagram: Option<String>,
+}
+
+enum Frame {
+    Tcp(NetStream, String),
+    Udp(AsyncUdpSocket, SocketAddr, String),
+}
+
+/// Messages with the connections to send them on, taken from `ClientConnections::outbox` so they
+/// can be sent without holding it locked.
+pub struct Outbox {
+    frames: Vec<(u32, Vec<Frame>)>,
+    metrics: ServerMetrics,
+}
+
+impl Outbox {
+    /// Sends everything. Each client gets its messages in order, and clients are written to side
+    /// by side, so one that stopped reading holds the others up for at most `WRITE_TIMEOUT`.
+    /// Returns the clients whose stream failed to write or timed out.
+    pub async fn send(self) -> Vec<u32> {
+        let sends: Vec<_> = self.frames.into_iter()
+            .map(|(id, frames)| {
+                let metrics = self.metrics.clone();
+                (id, task::spawn(async move {
+                    for frame in frames {
+                        match frame {
+                            Frame::Tcp(mut stream, message) => send_encoded(&mut stream, &message).await?,
+                            Frame::Udp(udp, peer, datagram) => match udp.send_to(&datagram, peer).await {
+                                Ok(()) => metrics.datagram_sent(datagram.len()),
+                                Err(e) => eprintln!("UDP send to client {} failed: {}", id, e),
+                            },
+                        }
+                    }
+                    async_std::io::Result::Ok(())
+                }))
+            })
+            .collect();
+        let mut dropped = Vec::new();
+        for (id, send) in sends {
+            if let Err(e) = send.await {
+                eprintln!("Dropping client {}: {}", id, e);
+                dropped.push(id);
+            }
+        }
+        dropped
+    }
+}
+
 // Add this new struct
 pub struct ClientConnections {
-    connections: HashMap<u32, TcpStream>,
+    connections: HashMap<u32, NetStream>,
+    udp: Option<AsyncUdpSocket>,
+    udp_peers: HashMap<u32, SocketAddr>,
+    udp_sequences: SequenceFilter<u32>,
+    metrics: ServerMetrics,
 }
 
 impl ClientConnections {
     pub fn new() -> Self {
         ClientConnections {
-            connections: HashMap::new()
+            connections: HashMap::new(),
+            udp: None,
+            udp_peers: HashMap::new(),
+            udp_sequences: SequenceFilter::new(),
+            metrics: ServerMetrics::new(),
+        }
+    }
+
+    /// Where `lock_game` records its waits and the UDP channel its datagrams, normally the
+    /// server's `metrics()`.
+    pub fn set_metrics(&mut self, metrics: ServerMetrics) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    /// Locks the game state, recording how long that took against `id`'s connection, or
+    /// against the server when there is no player to blame (or it has already gone).
+    pub fn lock_game<'a>(&self, id: Option<u32>, game: &'a Mutex<serde_json::Value>) -> std::sync::MutexGuard<'a, serde_json::Value> {
+        let started = Instant::now();
+        let guard = game.lock().unwrap();
+        let socket_id = id.and_then(|id| self.connections.get(&id)).map(AsyncTcpServer::get_socket_id);
+        self.metrics.record_lock_wait(socket_id, started.elapsed());
+        guard
+    }
+
+    /// Lets the fan-out helpers below reach clients over UDP once they have sent a datagram.
+    pub fn set_udp_socket(&mut self, socket: AsyncUdpSocket) {
+        self.udp = Some(socket);
+    }
+
+    pub fn udp_socket(&self) -> Option<&AsyncUdpSocket> {
+        self.udp.as_ref()
+    }
+
+    /// The address a client's datagrams come from, once it has sent one.
+    pub fn udp_peer(&self, id: u32) -> Option<SocketAddr> {
+        self.udp_peers.get(&id).copied()
+    }
+
+    /// Records a datagram from an authenticated client. Returns false if it is older than one
+    /// already handled. The client's UDP address follows whatever its newest datagram came from,
+    /// so a NAT rebinding does not cut it off.
+    pub fn accept_datagram(&mut self, id: u32, from: SocketAddr, seq: u32) -> bool {
+        if !self.udp_sequences.accept(id, seq) {
+            return false;
         }
+        self.udp_peers.insert(id, from);
+        true
     }
 
-    pub fn add_client(&mut self, id: u32, stream: TcpStream) {
+    pub fn add_client(&mut self, id: u32, stream: NetStream) {
         self.connections.insert(id, stream);
     }
 
-    pub fn get_client(&mut self, id: u32) -> Option<&mut TcpStream> {
+    pub fn get_client(&mut self, id: u32) -> Option<&mut NetStream> {
         self.connections.get_mut(&id)
     }
+
+    pub fn remove_client(&mut self, id: u32) -> Option<NetStream> {
+        self.udp_peers.remove(&id);
+        self.udp_sequences.forget(&id);
+        self.connections.remove(&id)
+    }
+
+    pub fn client_ids(&self) -> Vec<u32> {
+        self.connections.keys().copied().collect()
+    }
+
+    /// Ids of the players listed in the room whose `roomID` matches, taken from the server game state.
+    pub fn room_members(game: &serde_json::Value, room_id: i32) -> Vec<u32> {
+        game.as_object()
+            .into_iter()
+            .flat_map(|rooms| rooms.values())
+            .filter(|room| room["roomID"].as_i64() == Some(room_id as i64))
+            .filter_map(|room| room["players"].as_array())
+            .flatten()
+            .filter_map(|player| player["id"].as_u64().map(|id| id as u32))
+            .collect()
+    }
+
+    /// The `roomID` of the room the player is currently in, if any.
+    pub fn room_of(game: &serde_json::Value, player_id: u32) -> Option<i32> {
+        game.as_object()?
+            .values()
+            .find(|room| {
+                room["players"].as_array()
+                    .map_or(false, |players| players.iter().any(|p| p["id"] == player_id))
+            })
+            .and_then(|room| room["roomID"].as_i64())
+            .map(|room_id| room_id as i32)
+    }
+
+    /// Picks the connection each message goes out on. Messages for clients that are not
+    /// connected are left out.
+    pub fn outbox(&self, outgoing: Vec<Outgoing>) -> Outbox {
+        let mut frames: Vec<(u32, Vec<Frame>)> = Vec::new();
+        for Outgoing { to, message, datagram } in outgoing {
+            let frame = match (&self.udp, self.udp_peers.get(&to), datagram) {
+                (Some(udp), Some(peer), Some(datagram)) => Frame::Udp(udp.clone(), *peer, datagram),
+                _ => match self.connections.get(&to) {
+                    Some(stream) => Frame::Tcp(stream.clone(), message),
+                    None => continue,
+                },
+            };
+            match frames.iter_mut().find(|(id, _)| *id == to) {
+                Some((_, queued)) => queued.push(frame),
+                None => frames.push((to, vec![frame])),
+            }
+        }
+        Outbox { frames, metrics: self.metrics.clone() }
+    }
+
+    /// Sends to every id in `targets` that is connected, as an `outbox`. A stream that fails to
+    /// write or times out is dropped and the fan-out carries on; the ids that were dropped are
+    /// returned.
+    pub async fn send_to_many(&mut self, targets: Vec<u32>, message: &str) -> Vec<u32> {
+        let outgoing = targets.into_iter()
+            .map(|to| Outgoing { to, message: message.to_string(), datagram: None })
+            .collect();
+        self.send_outbox(outgoing).await
+    }
+
+    async fn send_outbox(&mut self, outgoing: Vec<Outgoing>) -> Vec<u32> {
+        let dropped = self.outbox(outgoing).send().await;
+        for id in &dropped {
+            self.remove_client(*id);
+        }
+        dropped
+    }
+
+    /// Sends a message to every connected client.
+    pub async fn broadcast(&mut self, message: &str) -> Vec<u32> {
+        let targets = self.client_ids();
+        self.send_to_many(targets, message).await
+    }
+
+    /// Sends a message to every connected client except `sender`.
+    pub async fn broadcast_except(&mut self, sender: u32, message: &str) -> Vec<u32> {
+        let targets = self.client_ids().into_iter().filter(|id| *id != sender).collect();
+        self.send_to_many(targets, message).await
+    }
+
+    /// Sends a message to every connected player in the given room.
+    pub async fn send_to_room(&mut self, game: &serde_json::Value, room_id: i32, message: &str) -> Vec<u32> {
+        let targets = Self::room_members(game, room_id);
+        self.send_to_many(targets, message).await
+    }
+
+    /// Sends a message to every connected player in the given room except `sender`.
+    pub async fn send_to_room_except(&mut self, game: &serde_json::Value, room_id: i32, sender: u32, message: &str) -> Vec<u32> {
+        let targets = Self::room_members(game, room_id).into_iter().filter(|id| *id != sender).collect();
+        self.send_to_many(targets, message).await
+    }
+
+    /// Like `send_to_room_except`, but sends `datagram` over UDP to players who have a UDP
+    /// address. The rest get `fallback` over TCP. Lost datagrams are not retried.
+    pub async fn send_datagram_to_room_except(&mut self, game: &serde_json::Value, room_id: i32, sender: u32, datagram: &str, fallback: &str) -> Vec<u32> {
+        let targets = Self::room_members(game, room_id).into_iter().filter(|id| *id != sender).collect();
+        self.send_datagram_to_many(targets, datagram, fallback).await
+    }
+
+    /// Sends a message to one client.
+    pub async fn send_to(&mut self, id: u32, message: &str) -> Vec<u32> {
+        self.send_to_many(vec![id], message).await
+    }
+
+    /// Delivers a chat message from `from`: to its room, to everyone, or to the player `to` with
+    /// a copy back to the sender. Fails if a whisper has no one to go to.
+    pub async fn route_chat(&mut self, game: &serde_json::Value, from: u32, scope: ChatScope, to: Option<u32>, message: &str) -> Result<Vec<u32>, String> {
+        match scope {
+            ChatScope::Room => {
+                let room_id = Self::room_of(game, from).ok_or_else(|| "You are not in a room".to_string())?;
+                Ok(self.send_to_room(game, room_id, message).await)
+            }
+            ChatScope::Global => Ok(self.broadcast(message).await),
+            ChatScope::Whisper => {
+                let to = to.ok_or_else(|| "Whisper to whom?".to_string())?;
+                if !self.connections.contains_key(&to) {
+                    return Err(format!("Player {} is not online", to));
+                }
+                let targets = if to == from { vec![from] } else { vec![to, from] };
+                Ok(self.send_to_many(targets, message).await)
+            }
+        }
+    }
+
+    /// Like `send_to`, but over UDP where possible, as in `send_datagram_to_room_except`.
+    pub async fn send_datagram_to(&mut self, id: u32, datagram: &str, fallback: &str) -> Vec<u32> {
+        self.send_datagram_to_many(vec![id], datagram, fallback).await
+    }
+
+    /// Like `send_to_room`, but over UDP where possible, as in `send_datagram_to_room_except`.
+    pub async fn send_datagram_to_room(&mut self, game: &serde_json::Value, room_id: i32, datagram: &str, fallback: &str) -> Vec<u32> {
+        let targets = Self::room_members(game, room_id);
+        self.send_datagram_to_many(targets, datagram, fallback).await
+    }
+
+    async fn send_datagram_to_many(&mut self, targets: Vec<u32>, datagram: &str, fallback: &str) -> Vec<u32> {
+        let outgoing = targets.into_iter()
+            .map(|to| Outgoing { to, message: fallback.to_string(), datagram: Some(datagram.to_string()) })
+            .collect();
+        self.send_outbox(outgoing).await
+    }
 }
 
 //basic other functions
@@ -324,12 +1989,15 @@ mod tests {
     #[async_std::test]
     async fn test_basic_server_client_connection() -> async_std::io::Result<()> {
         // Setup server
-        let server = AsyncTcpServer::new("127.0.0.1:8080", Arc::new(|_stream| {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {
             println!("Client connected!");
         }));
 
+        let shutdown = server.shutdown_handle();
+
         // Start server in background
-        task::spawn(async move {
+        let addr = server.bind().await?.to_string();
+        let running = task::spawn(async move {
             server.run().await.expect("Server failed to run");
         });
 
@@ -337,20 +2005,25 @@ mod tests {
         task::sleep(Duration::from_millis(100)).await;
 
         // Connect client
-        let client = AsyncTcpClient::new("127.0.0.1:8080");
+        let client = AsyncTcpClient::new(&addr);
         let result = client.connect().await;
         assert!(result.is_ok());
 
+        shutdown.shutdown();
+        running.await;
         Ok(())
     }
 
     #[async_std::test]
     async fn test_bidirectional_communication() -> async_std::io::Result<()> {
         // Setup server with message handler
-        let server = AsyncTcpServer::new("127.0.0.1:8081", Arc::new(|_stream| {}));
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+
+        let shutdown = server.shutdown_handle();
 
         // Start server with message handling
-        task::spawn(async move {
+        let addr = server.bind().await?.to_string();
+        let running = task::spawn(async move {
             server.run_with_messages(|msg, mut stream| async move {
                 // Echo the message back
                 AsyncTcpClient::send(&mut stream, &format!("Echo: {}", msg)).await?;
@@ -361,7 +2034,7 @@ mod tests {
         task::sleep(Duration::from_millis(100)).await;
 
         // Connect client
-        let client = AsyncTcpClient::new("127.0.0.1:8081");
+        let client = AsyncTcpClient::new(&addr);
         let mut stream = client.connect().await?;
 
         // Send message
@@ -372,23 +2045,27 @@ mod tests {
         let response = AsyncTcpClient::receive(&mut stream).await?;
         assert_eq!(response, format!("Echo: {}", test_message));
 
+        shutdown.shutdown();
+        running.await;
         Ok(())
     }
 
     #[async_std::test]
     async fn test_multiple_clients() -> async_std::io::Result<()> {
-        let server = AsyncTcpServer::new("127.0.0.1:8082", Arc::new(|_stream| {}));
-        
-        task::spawn(async move {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        let shutdown = server.shutdown_handle();
+
+        let addr = server.bind().await?.to_string();
+        let running = task::spawn(async move {
             server.run().await.expect("Server failed to run");
         });
 
         task::sleep(Duration::from_millis(100)).await;
 
         // Connect multiple clients
-        let client1 = AsyncTcpClient::new("127.0.0.1:8082");
-        let client2 = AsyncTcpClient::new("127.0.0.1:8082");
-        let client3 = AsyncTcpClient::new("127.0.0.1:8082");
+        let client1 = AsyncTcpClient::new(&addr);
+        let client2 = AsyncTcpClient::new(&addr);
+        let client3 = AsyncTcpClient::new(&addr);
 
         let result1 = client1.connect().await;
         let result2 = client2.connect().await;
@@ -398,16 +2075,21 @@ mod tests {
         assert!(result2.is_ok());
         assert!(result3.is_ok());
 
+        shutdown.shutdown();
+        running.await;
         Ok(())
     }
 
     #[async_std::test]
     async fn test_get_socket_id() -> async_std::io::Result<()> {
         // Setup server
-        let server = AsyncTcpServer::new("127.0.0.1:8083", Arc::new(|_stream| {}));
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+
+        let shutdown = server.shutdown_handle();
 
         // Start server in background
-        task::spawn(async move {
+        let addr = server.bind().await?.to_string();
+        let running = task::spawn(async move {
             server.run().await.unwrap();
         });
 
@@ -415,7 +2097,7 @@ mod tests {
         task::sleep(Duration::from_millis(100)).await;
 
         // Connect client
-        let client = AsyncTcpClient::new("127.0.0.1:8083");
+        let client = AsyncTcpClient::new(&addr);
         let stream = client.connect().await?;
 
         // Get socket ID
@@ -424,6 +2106,8 @@ mod tests {
 
         assert!(socket_id > 0);
 
+        shutdown.shutdown();
+        running.await;
         Ok(())
     }
 
@@ -433,12 +2117,15 @@ mod tests {
         use std::sync::Arc;
 
         // Setup server
-        let server = AsyncTcpServer::new("127.0.0.1:8084", Arc::new(|_stream| {}));
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
         let message_count = Arc::new(AtomicUsize::new(0));
         let message_count_clone = message_count.clone();
 
+        let shutdown = server.shutdown_handle();
+
         // Start server with continuous message handling
-        task::spawn(async move {
+        let addr = server.bind().await?.to_string();
+        let running = task::spawn(async move {
             server.run_with_messages(move |msg, mut stream| {
                 let message_count_clone = Arc::clone(&message_count_clone);
                 async move {
@@ -453,7 +2140,7 @@ mod tests {
         task::sleep(Duration::from_millis(100)).await;
 
         // Connect client
-        let client = AsyncTcpClient::new("127.0.0.1:8084");
+        let client = AsyncTcpClient::new(&addr);
         let mut stream = client.connect().await?;
 
         // Send multiple messages
@@ -464,6 +2151,854 @@ mod tests {
         }
 
         assert_eq!(message_count.load(Ordering::SeqCst), 5);
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_bind_reports_the_os_assigned_port() -> async_std::io::Result<()> {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        assert_eq!(server.local_addr(), None);
+
+        let addr = server.bind().await?;
+        assert_ne!(addr.port(), 0);
+        assert_eq!(server.local_addr(), Some(addr));
+        // Binding again keeps the same socket
+        assert_eq!(server.bind().await?, addr);
+
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move { server.run().await });
+        let client = AsyncTcpClient::new(&addr.to_string());
+        assert!(client.connect().await.is_ok());
+
+        shutdown.shutdown();
+        running.await
+    }
+
+    #[test]
+    fn test_codecs_round_trip() {
+        let snapshot = r#"{"type":"game","game":{"room1":{"players":[{"id":1,"x":400.5,"y":250,"name":"Player"}],"roomID":1}}}"#;
+        for name in CODECS {
+            let codec = codec_by_name(name).unwrap();
+            assert_eq!(codec.name(), name);
+            let payload = codec.encode(snapshot).unwrap();
+            // Nothing a codec sends can be mistaken for a heartbeat or closing frame
+            assert_ne!(payload[0], 0);
+            let decoded: serde_json::Value = serde_json::from_str(&codec.decode(&payload).unwrap()).unwrap();
+            assert_eq!(decoded, serde_json::from_str::<serde_json::Value>(snapshot).unwrap());
+        }
+
+        let json = JsonCodec.encode(snapshot).unwrap();
+        let binary = MessagePackCodec.encode(snapshot).unwrap();
+        assert!(binary.len() < json.len());
+        assert_eq!(MessagePackCodec.encode("not json").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(MessagePackCodec.decode(&[0xc1]).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(codec_by_name("xml").is_none());
+    }
+
+    #[async_std::test]
+    async fn test_switching_codecs_mid_connection() -> async_std::io::Result<()> {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|msg, mut stream| async move {
+                AsyncTcpServer::send(&mut stream, &msg).await?;
+                if msg.contains("msgpack") {
+                    stream.set_codec(Arc::new(MessagePackCodec));
+                }
+                Ok(())
+            }).await
+        });
+
+        let client = AsyncTcpClient::new(&addr);
+        let mut stream = client.connect().await?;
+        AsyncTcpClient::send(&mut stream, r#"{"codec":"msgpack"}"#).await?;
+        assert_eq!(AsyncTcpClient::receive(&mut stream).await?, r#"{"codec":"msgpack"}"#);
+        stream.set_codec(Arc::new(MessagePackCodec));
+
+        for i in 0..3 {
+            let message = format!(r#"{{"seq":{}}}"#, i);
+            AsyncTcpClient::send(&mut stream, &message).await?;
+            assert_eq!(AsyncTcpClient::receive(&mut stream).await?, message);
+        }
+
+        shutdown.shutdown();
+        running.await
+    }
+
+    #[async_std::test]
+    async fn test_frames_are_not_merged_or_split() -> async_std::io::Result<()> {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|msg, mut stream| async move {
+                AsyncTcpServer::send(&mut stream, &format!("Echo: {}", msg)).await
+            }).await.expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        let client = AsyncTcpClient::new(&addr);
+        let mut stream = client.connect().await?;
+
+        // Two frames in a single write must still arrive as two messages.
+        let mut burst = Vec::new();
+        for message in ["first", "second"] {
+            burst.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            burst.extend_from_slice(message.as_bytes());
+        }
+        stream.write_all(&burst).await?;
+        assert_eq!(AsyncTcpClient::receive(&mut stream).await?, "Echo: first");
+        assert_eq!(AsyncTcpClient::receive(&mut stream).await?, "Echo: second");
+
+        // A message far bigger than one socket read must arrive whole.
+        let large = "x".repeat(64 * 1024);
+        AsyncTcpClient::send(&mut stream, &large).await?;
+        assert_eq!(AsyncTcpClient::receive(&mut stream).await?, format!("Echo: {}", large));
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_writers_on_clones_do_not_interleave_frames() -> async_std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut reader = TcpStream::connect(listener.local_addr()?).await?;
+        let (server_side, _) = listener.accept().await?;
+        let stream: NetStream = server_side.into();
+
+        // Frames far bigger than the socket buffer, so each one takes many partial writes
+        let writers: Vec<_> = (b'a'..=b'd').map(|letter| {
+            let mut stream = stream.clone();
+            task::spawn(async move {
+                let payload = vec![letter; 512 * 1024];
+                for _ in 0..5 {
+                    stream.write_frame(&payload).await?;
+                    stream.write_frame(Heartbeat::Ping(u64::from(letter)).encode().as_bytes()).await?;
+                }
+                async_std::io::Result::Ok(())
+            })
+        }).collect();
+
+        // Let every writer fill the socket buffer and stall part way through a frame
+        task::sleep(Duration::from_millis(100)).await;
+        for _ in 0..40 {
+            let payload = recv_frame_bytes(&mut reader, MAX_FRAME_SIZE).await?.expect("frame");
+            if payload.first() != Some(&0) {
+                assert_eq!(payload.len(), 512 * 1024);
+                assert!(payload.iter().all(|byte| *byte == payload[0]));
+            }
+        }
+        for writer in writers {
+            writer.await?;
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_disconnect_handler_runs_when_client_leaves() -> async_std::io::Result<()> {
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        let (tx, rx) = async_std::channel::bounded(1);
+        server.set_disconnect_handler(Arc::new(move |stream| {
+            tx.try_send(AsyncTcpServer::get_socket_id(&stream)).unwrap();
+        }));
+
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|_msg, _stream| async move { Ok(()) })
+                .await
+                .expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        let client = AsyncTcpClient::new(&addr);
+        let mut stream = client.connect().await?;
+        AsyncTcpClient::send(&mut stream, "hello").await?;
+        drop(stream);
+
+        let socket_id = async_std::future::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("disconnect handler was not called")
+            .unwrap();
+        assert!(socket_id > 0);
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_broadcast_and_room_fan_out() -> async_std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mut clients = Vec::new();
+        let mut connections = ClientConnections::new();
+        for id in 1..=3 {
+            clients.push(TcpStream::connect(addr).await?.into());
+            let (server_side, _) = listener.accept().await?;
+            connections.add_client(id, server_side.into());
+        }
+
+        // Client 2's stream is dead; fan-out must drop it and keep going.
+        connections.get_client(2).unwrap().shutdown(std::net::Shutdown::Write)?;
+
+        let game = serde_json::json!({
+            "room1": {"players": [{"id": 1}, {"id": 2}], "roomID": 1},
+            "room2": {"players": [{"id": 3}], "roomID": 2},
+        });
+        assert_eq!(ClientConnections::room_of(&game, 3), Some(2));
+        assert_eq!(ClientConnections::room_members(&game, 1), vec![1, 2]);
+
+        assert_eq!(connections.broadcast_except(1, "from 1").await, vec![2]);
+        assert!(connections.get_client(2).is_none());
+        assert_eq!(AsyncTcpClient::receive(&mut clients[2]).await?, "from 1");
+
+        assert!(connections.send_to_room(&game, 1, "room 1 only").await.is_empty());
+        assert_eq!(AsyncTcpClient::receive(&mut clients[0]).await?, "room 1 only");
+
+        assert!(connections.broadcast("everyone").await.is_empty());
+        assert_eq!(AsyncTcpClient::receive(&mut clients[0]).await?, "everyone");
+        assert_eq!(AsyncTcpClient::receive(&mut clients[2]).await?, "everyone");
+
+        // Client 1 stops reading until its socket buffers are full; it times out and is dropped
+        let mut stalled = connections.get_client(1).unwrap().clone();
+        let filling = task::spawn(async move {
+            let large = "x".repeat(512 * 1024);
+            while send_encoded(&mut stalled, &large).await.is_ok() {}
+        });
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(connections.broadcast("still here").await, vec![1]);
+        assert_eq!(AsyncTcpClient::receive(&mut clients[2]).await?, "still here");
+        filling.await;
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_chat_routing_by_scope() -> async_std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mut clients = Vec::new();
+        let mut connections = ClientConnections::new();
+        for id in 1..=3 {
+            clients.push(TcpStream::connect(addr).await?.into());
+            let (server_side, _) = listener.accept().await?;
+            connections.add_client(id, server_side.into());
+        }
+        let game = serde_json::json!({
+            "room1": {"players": [{"id": 1}, {"id": 2}], "roomID": 1},
+            "room2": {"players": [{"id": 3}], "roomID": 2},
+        });
+
+        assert_eq!(connections.route_chat(&game, 1, ChatScope::Room, None, "room").await, Ok(vec![]));
+        assert_eq!(AsyncTcpClient::receive(&mut clients[0]).await?, "room");
+        assert_eq!(AsyncTcpClient::receive(&mut clients[1]).await?, "room");
+
+        // A whisper reaches its target and comes back to the sender, and no one else
+        assert_eq!(connections.route_chat(&game, 1, ChatScope::Whisper, Some(3), "psst").await, Ok(vec![]));
+        assert_eq!(AsyncTcpClient::receive(&mut clients[2]).await?, "psst");
+        assert_eq!(AsyncTcpClient::receive(&mut clients[0]).await?, "psst");
+        assert!(connections.route_chat(&game, 1, ChatScope::Whisper, Some(9), "psst").await.is_err());
+        assert!(connections.route_chat(&game, 1, ChatScope::Whisper, None, "psst").await.is_err());
+
+        assert_eq!(connections.route_chat(&game, 3, ChatScope::Global, None, "all").await, Ok(vec![]));
+        for client in clients.iter_mut() {
+            assert_eq!(AsyncTcpClient::receive(client).await?, "all");
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_heartbeat_measures_rtt() -> async_std::io::Result<()> {
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        server.set_heartbeat(HeartbeatConfig { interval: Duration::from_millis(50), max_missed: 5 });
+        let (tx, rx) = async_std::channel::bounded(16);
+        server.set_latency_handler(Arc::new(move |socket_id, rtt| {
+            let _ = tx.try_send((socket_id, rtt));
+        }));
+        let latencies = server.latencies();
+
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|_msg, _stream| async move { Ok(()) })
+                .await
+                .expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        let mut client = AsyncTcpClient::new(&addr);
+        client.set_heartbeat(HeartbeatConfig { interval: Duration::from_millis(50), max_missed: 5 });
+        let latency = client.latency();
+        let mut stream = client.connect().await?;
+        task::spawn(async move {
+            let _ = client.handle_messages(&mut stream, |_msg| async { Ok(()) }).await;
+        });
+
+        let (socket_id, rtt) = async_std::future::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("server never measured a round trip")
+            .unwrap();
+        assert!(rtt < Duration::from_secs(1));
+        assert!(latencies.get(socket_id).is_some());
+
+        task::sleep(Duration::from_millis(200)).await;
+        assert!(latency.rtt().is_some());
+        assert_eq!(latency.missed(), 0);
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_silent_client_times_out() -> async_std::io::Result<()> {
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        server.set_heartbeat(HeartbeatConfig { interval: Duration::from_millis(50), max_missed: 3 });
+        let (tx, rx) = async_std::channel::bounded(1);
+        server.set_disconnect_handler(Arc::new(move |_stream| {
+            let _ = tx.try_send(());
+        }));
+
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|_msg, _stream| async move { Ok(()) })
+                .await
+                .expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        // Connects but never reads, so no ping is ever answered
+        let client = AsyncTcpClient::new(&addr);
+        let _stream = client.connect().await?;
+
+        async_std::future::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("silent client was never timed out")
+            .unwrap();
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_client_that_stops_reading_times_out() -> async_std::io::Result<()> {
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        server.set_heartbeat(HeartbeatConfig { interval: Duration::from_millis(50), max_missed: 3 });
+        let (tx, rx) = async_std::channel::bounded(1);
+        server.set_disconnect_handler(Arc::new(move |_stream| {
+            let _ = tx.try_send(());
+        }));
+
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            // Keeps writing until the socket buffers fill up and the writes stall
+            server.run_with_messages(|_msg, mut stream| async move {
+                let large = "x".repeat(512 * 1024);
+                loop {
+                    AsyncTcpServer::send(&mut stream, &large).await?;
+                }
+            }).await.expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        // Asks for the flood, then never reads
+        let client = AsyncTcpClient::new(&addr);
+        let mut stream = client.connect().await?;
+        AsyncTcpClient::send(&mut stream, "hello").await?;
+
+        // Well before WRITE_TIMEOUT: the pings behind the stalled write still count as missed
+        async_std::future::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("client that stopped reading was never timed out")
+            .unwrap();
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_udp_datagrams_reach_the_handler() -> async_std::io::Result<()> {
+        let server = AsyncUdpSocket::bind("127.0.0.1:0").await?;
+        let (tx, rx) = async_std::channel::bounded(4);
+        let server_clone = server.clone();
+        let shutdown = ShutdownHandle::new();
+        let stop = shutdown.clone();
+        let running = task::spawn(async move {
+            server_clone.run_until_shutdown(&stop, move |message, from| {
+                let tx = tx.clone();
+                async move {
+                    let _ = tx.send((message, from)).await;
+                }
+            }).await.expect("UDP socket failed");
+        });
+
+        let client = AsyncUdpSocket::bind("127.0.0.1:0").await?;
+        client.send_to("first", server.local_addr()?).await?;
+        let (message, from) = async_std::future::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("datagram never arrived")
+            .unwrap();
+        assert_eq!(message, "first");
+        assert_eq!(from, client.local_addr()?);
+
+        // Replies go straight back to the address the datagram came from
+        server.send_to("back", from).await?;
+        let (reply, _) = client.recv_from().await?;
+        assert_eq!(reply, "back");
+
+        let too_big = "x".repeat(MAX_DATAGRAM_SIZE + 1);
+        let err = client.send_to(&too_big, server.local_addr()?).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_tls_loopback() -> async_std::io::Result<()> {
+        let (cert_pem, server_config) = crate::tls::self_signed_server_config(&["localhost".to_string()])?;
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        server.set_tls(server_config);
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|msg, mut stream| async move {
+                assert!(stream.is_tls());
+                AsyncTcpServer::send(&mut stream, &format!("Echo: {}", msg)).await
+            }).await.expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        let mut client = AsyncTcpClient::new(&addr);
+        let name = ServerName::try_from("localhost").unwrap();
+        client.set_tls(crate::tls::client_config(Some(&cert_pem))?, name);
+        let mut stream = client.connect().await?;
+        assert!(stream.is_tls());
+
+        AsyncTcpClient::send(&mut stream, "secret").await?;
+        assert_eq!(AsyncTcpClient::receive(&mut stream).await?, "Echo: secret");
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_tls_rejects_untrusted_certificate() -> async_std::io::Result<()> {
+        let (_, server_config) = crate::tls::self_signed_server_config(&["localhost".to_string()])?;
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        server.set_tls(server_config);
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|_msg, _stream| async move { Ok(()) })
+                .await
+                .expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        // Only the public roots are trusted, and they never signed a self-signed certificate
+        let mut client = AsyncTcpClient::new(&addr);
+        client.set_tls(crate::tls::client_config(None)?, ServerName::try_from("localhost").unwrap());
+        assert!(client.connect().await.is_err());
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_flooding_client_is_disconnected() -> async_std::io::Result<()> {
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        server.set_rate_limits(RateLimits {
+            messages_per_second: 5.0,
+            message_burst: 5.0,
+            bytes_per_second: 10_000.0,
+            byte_burst: 10_000.0,
+            max_frame_size: 64,
+            warnings: 2,
+            max_violations: 4,
+            forgive_after: Duration::from_secs(10),
+        });
+        let steps = Arc::new(Mutex::new(Vec::new()));
+        let seen_steps = steps.clone();
+        server.set_rate_limit_handler(Arc::new(move |_stream, step| {
+            seen_steps.lock().unwrap().push(step);
+        }));
+        let (tx, rx) = async_std::channel::bounded(2);
+        server.set_disconnect_handler(Arc::new(move |_stream| {
+            let _ = tx.try_send(());
+        }));
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|_msg, _stream| async move { Ok(()) })
+                .await
+                .expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        let client = AsyncTcpClient::new(&addr);
+        let mut flooder = client.connect().await?;
+        for i in 0..20 {
+            if AsyncTcpClient::send(&mut flooder, &format!("spam {}", i)).await.is_err() {
+                break;
+            }
+        }
+        async_std::future::timeout(Duration::from_secs(3), rx.recv())
+            .await
+            .expect("flooding client was never disconnected")
+            .unwrap();
+        let steps = steps.lock().unwrap().clone();
+        assert_eq!(&steps[..2], &[RateLimitStep::Warn, RateLimitStep::Warn]);
+        assert!(matches!(steps[2], RateLimitStep::Throttle(_)));
+        assert_eq!(steps.last(), Some(&RateLimitStep::Disconnect));
+
+        // A frame over the size limit is refused outright
+        let mut big_sender = client.connect().await?;
+        AsyncTcpClient::send(&mut big_sender, &"x".repeat(65)).await?;
+        async_std::future::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("oversized frame did not close the connection")
+            .unwrap();
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_shutdown_notifies_clients_and_waits_for_handlers() -> async_std::io::Result<()> {
+        let mut server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        server.set_shutdown_timeout(Duration::from_secs(2));
+        let shutdown = server.shutdown_handle();
+        let finished = Arc::new(Mutex::new(false));
+        let handler_finished = finished.clone();
+        let addr = server.bind().await?.to_string();
+        let running = task::spawn(async move {
+            server.run_with_messages(move |_msg, _stream| {
+                let handler_finished = handler_finished.clone();
+                async move {
+                    // A slow handler that is still running when the shutdown starts
+                    task::sleep(Duration::from_millis(300)).await;
+                    *handler_finished.lock().unwrap() = true;
+                    Ok(())
+                }
+            }).await
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        let client = AsyncTcpClient::new(&addr);
+        let mut stream = client.connect().await?;
+        AsyncTcpClient::send(&mut stream, "slow").await?;
+        task::sleep(Duration::from_millis(50)).await;
+
+        shutdown.shutdown();
+        let mut reader = stream.clone();
+        let client_done = task::spawn(async move {
+            client.handle_messages(&mut reader, |_msg| async { Ok(()) }).await.map(|_| client.status().get())
+        });
+
+        async_std::future::timeout(Duration::from_secs(2), running)
+            .await
+            .expect("run_with_messages did not return after shutdown")?;
+        assert!(*finished.lock().unwrap(), "shutdown did not wait for the in-flight handler");
+        assert_eq!(client_done.await?, ConnectionState::Closed);
+
+        // The port is free again and new connections are refused
+        assert!(TcpStream::connect(&addr).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconnect_backoff_doubles_up_to_the_cap() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay_for(1), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(2), Duration::from_millis(200));
+        assert_eq!(policy.delay_for(4), Duration::from_millis(800));
+        assert_eq!(policy.delay_for(5), Duration::from_millis(1000));
+        assert_eq!(policy.delay_for(500), Duration::from_millis(1000));
+    }
+
+    #[async_std::test]
+    async fn test_client_reconnects_and_resyncs() -> async_std::io::Result<()> {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        let (tx, rx) = async_std::channel::bounded(4);
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(move |msg, stream| {
+                let tx = tx.clone();
+                async move {
+                    if msg == "drop me" {
+                        stream.shutdown(std::net::Shutdown::Both)?;
+                    } else {
+                        let _ = tx.send(msg).await;
+                    }
+                    Ok(())
+                }
+            }).await.expect("Server failed to run with messages");
+        });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        let mut client = AsyncTcpClient::new(&addr);
+        client.set_reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(100),
+            max_attempts: Some(5),
+        });
+        let status = client.status();
+        let changes = status.subscribe();
+        let mut stream = client.connect_with_retry().await?;
+        assert_eq!(status.get(), ConnectionState::Connected);
+
+        AsyncTcpClient::send(&mut stream, "drop me").await?;
+        let resync_status = status.clone();
+        task::spawn(async move {
+            // Still reconnecting while the caller re-joins
+            let on_reconnect = |mut stream: NetStream| {
+                let state = resync_status.get();
+                async move { AsyncTcpClient::send(&mut stream, &format!("resync {:?}", state)).await }
+            };
+            let _ = client.run_with_reconnect(stream, on_reconnect, |_msg| async { Ok(()) }).await;
+        });
+
+        let resync = async_std::future::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("client never reconnected")
+            .unwrap();
+        assert_eq!(resync, "resync Reconnecting { attempt: 1 }");
+        task::sleep(Duration::from_millis(50)).await;
+        assert_eq!(status.get(), ConnectionState::Connected);
+
+        let mut seen = Vec::new();
+        while let Ok(state) = changes.try_recv() {
+            seen.push(state);
+        }
+        assert_eq!(seen, vec![
+            ConnectionState::Connected,
+            ConnectionState::Reconnecting { attempt: 1 },
+            ConnectionState::Connected,
+        ]);
+
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_client_gives_up_after_max_attempts() {
+        // Nothing listens on a port the OS just handed out and took back
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let mut client = AsyncTcpClient::new(&addr);
+        client.set_reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            max_attempts: Some(2),
+        });
+        let changes = client.status().subscribe();
+
+        assert!(client.connect_with_retry().await.is_err());
+        assert_eq!(client.status().get(), ConnectionState::Lost);
+        assert_eq!(changes.try_recv(), Ok(ConnectionState::Reconnecting { attempt: 1 }));
+        assert_eq!(changes.try_recv(), Ok(ConnectionState::Reconnecting { attempt: 2 }));
+        assert_eq!(changes.try_recv(), Ok(ConnectionState::Lost));
+    }
+
+    #[test]
+    fn test_sequence_filter_drops_late_datagrams() {
+        let mut filter = SequenceFilter::new();
+        assert!(filter.accept(1, 5));
+        assert!(!filter.accept(1, 5));
+        assert!(!filter.accept(1, 3));
+        assert!(filter.accept(1, 6));
+        assert!(filter.accept(2, 1));
+
+        // Counting past u32::MAX wraps around to small numbers that are still newer
+        assert!(filter.accept(3, u32::MAX - 1));
+        assert!(filter.accept(3, 2));
+        assert!(!filter.accept(3, u32::MAX));
+
+        filter.forget(&1);
+        assert!(filter.accept(1, 1));
+    }
+
+    #[test]
+    fn test_heartbeat_frames_do_not_look_like_messages() {
+        assert_eq!(Heartbeat::parse(&Heartbeat::Ping(7).encode()), Some(Heartbeat::Ping(7)));
+        assert_eq!(Heartbeat::parse(&Heartbeat::Pong(9).encode()), Some(Heartbeat::Pong(9)));
+        assert_eq!(Heartbeat::parse("ping 7"), None);
+        assert_eq!(Heartbeat::parse(r#"{"type":"get_game"}"#), None);
+    }
+
+    #[async_std::test]
+    async fn test_oversized_frame_is_rejected() {
+        let header = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        let mut reader = async_std::io::Cursor::new(header.to_vec());
+        let err = recv_frame(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut writer = async_std::io::Cursor::new(Vec::new());
+        let err = send_frame(&mut writer, &"x".repeat(MAX_FRAME_SIZE + 1)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(writer.into_inner().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_recv_frame_reports_clean_close() {
+        let mut reader = async_std::io::Cursor::new(Vec::new());
+        assert!(recv_frame(&mut reader).await.unwrap().is_none());
+
+        let mut truncated = async_std::io::Cursor::new(vec![0, 0, 0, 5, b'a']);
+        let err = recv_frame(&mut truncated).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[async_std::test]
+    async fn test_conditioned_proxy_delays_traffic() -> async_std::io::Result<()> {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        let addr = server.bind().await?;
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|msg, mut stream| async move {
+                AsyncTcpServer::send(&mut stream, &format!("Echo: {}", msg)).await
+            }).await.expect("Server failed to run with messages");
+        });
+
+        let latency = Duration::from_millis(100);
+        let conditions = NetworkConditions { latency, ..NetworkConditions::default() };
+        let proxy = ConditionedProxy::start("127.0.0.1:0", addr, conditions).await?;
+
+        let client = AsyncTcpClient::new(&proxy.local_addr().to_string());
+        let mut stream = client.connect().await?;
+        let sent = Instant::now();
+        AsyncTcpClient::send(&mut stream, "slow").await?;
+        assert_eq!(AsyncTcpClient::receive(&mut stream).await?, "Echo: slow");
+        // Once on the way there and once on the way back
+        assert!(sent.elapsed() >= latency * 2, "{:?}", sent.elapsed());
+
+        proxy.stop();
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_conditioned_proxy_disconnects() -> async_std::io::Result<()> {
+        assert_eq!(
+            NetworkConditions::parse("latency=100, jitter=20,bandwidth=50000,loss=0.05,reorder=0.1,disconnect=0.001"),
+            Ok(NetworkConditions {
+                latency: Duration::from_millis(100),
+                jitter: Duration::from_millis(20),
+                bandwidth: Some(50000.0),
+                udp_loss: 0.05,
+                udp_reorder: 0.1,
+                disconnect_chance: 0.001,
+            })
+        );
+        assert!(NetworkConditions::parse("latency=-5").is_err());
+        assert!(NetworkConditions::parse("lag=5").is_err());
+        assert!(NetworkConditions::parse("bandwidth=0").is_err());
+
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        let addr = server.bind().await?;
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(|_msg, _stream| async move { Ok(()) })
+                .await
+                .expect("Server failed to run with messages");
+        });
+
+        let conditions = NetworkConditions::parse("disconnect=1").unwrap();
+        let proxy = ConditionedProxy::start("127.0.0.1:0", addr, conditions).await?;
+        let client = AsyncTcpClient::new(&proxy.local_addr().to_string());
+        let mut stream = client.connect().await?;
+        AsyncTcpClient::send(&mut stream, "cut").await?;
+        let closed = async_std::future::timeout(Duration::from_secs(2), AsyncTcpClient::receive(&mut stream))
+            .await
+            .expect("the proxy never cut the connection");
+        assert!(closed.is_err());
+
+        proxy.stop();
+        shutdown.shutdown();
+        running.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_server_metrics_follow_connections() -> async_std::io::Result<()> {
+        let server = AsyncTcpServer::new("127.0.0.1:0", Arc::new(|_stream| {}));
+        let metrics = server.metrics();
+        let handler_metrics = metrics.clone();
+        let addr = server.bind().await?.to_string();
+        let shutdown = server.shutdown_handle();
+        let running = task::spawn(async move {
+            server.run_with_messages(move |msg, mut stream| {
+                let metrics = handler_metrics.clone();
+                async move {
+                    // Handlers time themselves, by the type of message they decoded
+                    let started = Instant::now();
+                    let kind = crate::protocol::ClientMessage::from_json(&msg).map_or("unknown", |m| m.kind());
+                    AsyncTcpServer::send(&mut stream, &msg).await?;
+                    metrics.record_message(AsyncTcpServer::get_socket_id(&stream), kind, msg.len(), started.elapsed());
+                    Ok(())
+                }
+            }).await.expect("Server failed to run with messages");
+        });
+
+        let client = AsyncTcpClient::new(&addr);
+        let mut stream = client.connect().await?;
+        let message = r#"{"type":"get_game"}"#;
+        for _ in 0..3 {
+            AsyncTcpClient::send(&mut stream, message).await?;
+            assert_eq!(AsyncTcpClient::receive(&mut stream).await?, message);
+        }
+        // The server counts its answer and handler time only after the answer is on its way
+        task::sleep(Duration::from_millis(50)).await;
+
+        let connections = metrics.connections();
+        assert_eq!(connections.len(), 1);
+        let frame = (message.len() + 4) as u64;
+        assert_eq!(connections[0].traffic.messages_in, 3);
+        assert_eq!(connections[0].traffic.messages_out, 3);
+        assert!(connections[0].traffic.bytes_in >= 3 * frame);
+        assert_eq!(metrics.messages()["get_game"].count, 3);
+        // The client counts the same messages the other way round
+        assert_eq!((stream.traffic().messages_out, stream.traffic().messages_in), (3, 3));
+
+        stream.shutdown(std::net::Shutdown::Both)?;
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!((metrics.connected(), metrics.total_connections()), (0, 1));
+        assert!(metrics.traffic().bytes_out >= 3 * frame);
+
+        shutdown.shutdown();
+        running.await;
         Ok(())
     }
 }
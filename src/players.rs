@@ -0,0 +1,168 @@
+use crate::chat::ChatFilter;
+use crate::interest::{Interest, InterestChange, DEFAULT_INTEREST_RADIUS};
+use crate::protocol::{PlayerId, ServerMessage};
+use crate::replication::ServerReplica;
+use crate::simulation::QueuedInput;
+use crate::validation::{MotionCheck, MovementRules};
+use serde_json::Value;
+use std::collections::HashMap;
+
+// The game side of every joined player, kept apart from its connection in ClientConnections: what
+// it has of the game state for delta sync, its movement checks, what it can see, its chat flood
+// bucket, and the moves waiting for the next tick. The server shares one of these between its
+// handlers and the tick thread, locked after ClientConnections and before the game state.
+
+pub struct Players {
+    replicas: HashMap<PlayerId, ServerReplica>,
+    motions: HashMap<PlayerId, MotionCheck>,
+    inputs: Vec<QueuedInput>,
+    interests: HashMap<PlayerId, Interest>,
+    interest_radius: f32,
+    chat: ChatFilter,
+}
+
+impl Default for Players {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Players {
+    pub fn new() -> Self {
+        Players {
+            replicas: HashMap::new(),
+            motions: HashMap::new(),
+            inputs: Vec::new(),
+            interests: HashMap::new(),
+            interest_radius: DEFAULT_INTEREST_RADIUS,
+            chat: ChatFilter::new(),
+        }
+    }
+
+    /// Starts tracking a player that just joined. A flagged player keeps its movement checks.
+    pub fn add(&mut self, id: PlayerId) {
+        self.replicas.insert(id, ServerReplica::new());
+        self.motions.entry(id).or_insert_with(|| MotionCheck::new(MovementRules::default()));
+        self.interests.insert(id, Interest::new(self.interest_radius));
+    }
+
+    /// Forgets a player that left, except the movement checks of a flagged one.
+    pub fn remove(&mut self, id: PlayerId) {
+        self.replicas.remove(&id);
+        self.interests.remove(&id);
+        self.chat.remove_client(id);
+        if self.motions.get(&id).is_some_and(|motion| !motion.is_flagged()) {
+            self.motions.remove(&id);
+        }
+    }
+
+    /// The length, flood and word checks applied to chat messages.
+    pub fn chat_filter(&mut self) -> &mut ChatFilter {
+        &mut self.chat
+    }
+
+    /// Movement checks for a player. Flagged players keep theirs across reconnects.
+    pub fn motion(&mut self, id: PlayerId) -> Option<&mut MotionCheck> {
+        self.motions.get_mut(&id)
+    }
+
+    /// Ids of the players flagged for impossible movement.
+    pub fn flagged(&self) -> Vec<PlayerId> {
+        self.motions.iter().filter(|(_, motion)| motion.is_flagged()).map(|(id, _)| *id).collect()
+    }
+
+    /// How far players see. Applies to players added from now on.
+    pub fn set_interest_radius(&mut self, radius: f32) {
+        self.interest_radius = radius;
+    }
+
+    /// Whether a player currently sees an entity.
+    pub fn can_see(&self, id: PlayerId, npc: bool, entity: PlayerId) -> bool {
+        self.interests.get(&id).is_some_and(|interest| interest.is_visible(npc, entity))
+    }
+
+    /// The part of the game a player sees. Players without an interest see all of it.
+    pub fn view(&self, id: PlayerId, game: &Value) -> Value {
+        match self.interests.get(&id) {
+            Some(interest) => interest.filter(game),
+            None => game.clone(),
+        }
+    }
+
+    /// Works out what every player sees now, and returns what changed for each.
+    pub fn update_interests(&mut self, game: &Value) -> Vec<(PlayerId, InterestChange)> {
+        let mut changes: Vec<(PlayerId, InterestChange)> = self.interests.iter_mut()
+            .map(|(id, interest)| (*id, interest.update(game, *id)))
+            .filter(|(_, change)| !change.is_empty())
+            .collect();
+        changes.sort_unstable_by_key(|(id, _)| *id);
+        changes
+    }
+
+    /// Keeps a move for the next server tick.
+    pub fn queue_input(&mut self, input: QueuedInput) {
+        self.inputs.push(input);
+    }
+
+    /// Every move queued since the last tick, in the order they arrived.
+    pub fn drain_inputs(&mut self) -> Vec<QueuedInput> {
+        std::mem::take(&mut self.inputs)
+    }
+
+    /// What a player has of the game state, for delta sync.
+    pub fn replica(&mut self, id: PlayerId) -> Option<&mut ServerReplica> {
+        self.replicas.get_mut(&id)
+    }
+
+    /// The changes in what each player can see since the last snapshot it acknowledged, for the
+    /// players that have any.
+    pub fn sync_updates(&mut self, game: &Value) -> Vec<(PlayerId, ServerMessage)> {
+        let mut updates = Vec::new();
+        for (id, replica) in self.replicas.iter_mut() {
+            let view = match self.interests.get(id) {
+                Some(interest) => interest.filter(game),
+                None => game.clone(),
+            };
+            if let Some(update) = replica.update(&view) {
+                updates.push((*id, update));
+            }
+        }
+        updates.sort_unstable_by_key(|(id, _)| *id);
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::FLAG_THRESHOLD;
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_leaving_forgets_all_but_flags() {
+        let floor = [json!({"x": 0, "y": 0, "width": 1000, "height": 1000, "id": 0})];
+        let mut players = Players::new();
+        players.add(1);
+        players.add(2);
+        assert!(players.replica(1).is_some());
+
+        // Player 2 teleports until it is flagged
+        let start = Instant::now();
+        for i in 0..FLAG_THRESHOLD {
+            let now = start + Duration::from_millis(i as u64 * 10);
+            players.motion(2).unwrap().check(Some((0.0, 0.0)), (900.0, 900.0), (None, None), &floor, now);
+        }
+        assert_eq!(players.flagged(), vec![2]);
+
+        players.remove(1);
+        players.remove(2);
+        assert!(players.replica(1).is_none() && players.motion(1).is_none());
+        assert!(players.replica(2).is_none());
+        assert_eq!(players.flagged(), vec![2]);
+
+        // Coming back does not wipe the flag
+        players.add(2);
+        assert_eq!(players.flagged(), vec![2]);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::collision;
+use crate::movement::{Movement, MovementInput};
+use crate::protocol::PlayerId;
+use serde_json::Value;
+use std::collections::VecDeque;
+
+// Client-side prediction for the local player. Every frame of input is applied straight away and
+// kept, numbered, until the server confirms it. When the server's position for the player arrives
+// with the number of the newest input it includes, the player is put back there and the inputs the
+// server has not seen yet are played again on top, each followed by the same collision step as
+// when it was first applied.
+
+/// Inputs kept while waiting for the server. At 60 FPS this is two seconds; anything older is
+/// dropped, so a server that stops answering costs at most that much replay.
+pub const MAX_PENDING_INPUTS: usize = 120;
+
+/// Where the server says the player is, after the input numbered `input`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Authoritative {
+    pub input: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Authoritative {
+    /// Finds the player in a game state, if the server has recorded one of its inputs yet.
+    pub fn from_game(game: &Value, player_id: PlayerId) -> Option<Self> {
+        game.as_object()?.values()
+            .filter_map(|room| room["players"].as_array())
+            .flatten()
+            .find(|player| player["id"] == player_id)
+            .and_then(|player| Some(Authoritative {
+                input: player["input"].as_u64()? as u32,
+                x: player["x"].as_f64()? as f32,
+                y: player["y"].as_f64()? as f32,
+            }))
+    }
+}
+
+/// The local player's inputs that the server has not confirmed yet.
+#[derive(Debug, Clone, Default)]
+pub struct Prediction {
+    next_input: u32,
+    pending: VecDeque<(u32, MovementInput)>,
+    confirmed: Option<u32>,
+}
+
+impl Prediction {
+    pub fn new() -> Self {
+        Prediction::default()
+    }
+
+    /// Keeps an input that has just been applied locally and returns its number, which goes out
+    /// with the position update it produced.
+    pub fn record(&mut self, input: MovementInput) -> u32 {
+        self.next_input += 1;
+        self.pending.push_back((self.next_input, input));
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.next_input
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops the inputs still waiting, e.g. after a reconnect, when the server will never see
+    /// them. Numbering carries on, so confirmations from before cannot match newer inputs.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Rewinds `movement` to the server's position and replays the inputs it has not seen,
+    /// colliding with the room's `objects` after each as the frame loop does. Positions older
+    /// than one already reconciled against are ignored. Returns whether the player was moved back.
+    pub fn reconcile(&mut self, movement: &mut Movement, server: Authoritative, objects: &[Value]) -> bool {
+        if self.confirmed.is_some_and(|confirmed| server.input <= confirmed) || server.input > self.next_input {
+            return false;
+        }
+        self.confirmed = Some(server.input);
+        while self.pending.front().is_some_and(|(input, _)| *input <= server.input) {
+            self.pending.pop_front();
+        }
+        movement.position.x = server.x;
+        movement.position.y = server.y;
+        for (_, input) in &self.pending {
+            movement.apply(input);
+            collision::collide_with_room(movement, objects);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raylib::prelude::Vector2;
+    use serde_json::json;
+
+    fn right(delta_time: f32) -> MovementInput {
+        MovementInput { right: true, delta_time, ..MovementInput::default() }
+    }
+
+    #[test]
+    fn test_reconcile_replays_unconfirmed_inputs() {
+        let mut movement = Movement { position: Vector2::new(0.0, 0.0), speed: crate::movement::PLAYER_SPEED, width: 50, height: 50 };
+        let mut prediction = Prediction::new();
+        // Each input moves 5 * 0.25 * 40 = 50 units to the right
+        for _ in 0..3 {
+            let input = right(0.25);
+            movement.apply(&input);
+            prediction.record(input);
+        }
+        assert_eq!(movement.position.x, 150.0);
+
+        // The server only got the first input, and held the player back to 4 on it
+        assert!(prediction.reconcile(&mut movement, Authoritative { input: 1, x: 4.0, y: 0.0 }, &[]));
+        assert_eq!(movement.position.x, 104.0);
+        assert_eq!(prediction.pending(), 2);
+
+        // A late, older confirmation changes nothing
+        assert!(!prediction.reconcile(&mut movement, Authoritative { input: 1, x: 0.0, y: 0.0 }, &[]));
+        assert_eq!(movement.position.x, 104.0);
+
+        // Once everything is confirmed the server's position stands as is
+        assert!(prediction.reconcile(&mut movement, Authoritative { input: 3, x: 104.0, y: 0.0 }, &[]));
+        assert_eq!(movement.position.x, 104.0);
+        assert_eq!(prediction.pending(), 0);
+    }
+
+    #[test]
+    fn test_replay_collides_with_the_room() {
+        // A floor 200 wide, so the player stops at x 150
+        let objects = [json!({"x": 0, "y": 0, "width": 200, "height": 200, "id": 0})];
+        let mut movement = Movement { position: Vector2::new(0.0, 0.0), speed: crate::movement::PLAYER_SPEED, width: 50, height: 50 };
+        let mut prediction = Prediction::new();
+        for _ in 0..4 {
+            let input = right(0.25);
+            movement.apply(&input);
+            collision::collide_with_room(&mut movement, &objects);
+            prediction.record(input);
+        }
+        assert_eq!(movement.position.x, 150.0);
+
+        // Replaying against the wall ends where the frames did, not past it
+        assert!(prediction.reconcile(&mut movement, Authoritative { input: 1, x: 50.0, y: 0.0 }, &objects));
+        assert_eq!(movement.position.x, 150.0);
+
+        // After a reconnect nothing is left to replay
+        prediction.clear();
+        assert_eq!(prediction.pending(), 0);
+        assert!(prediction.reconcile(&mut movement, Authoritative { input: 2, x: 120.0, y: 0.0 }, &objects));
+        assert_eq!(movement.position.x, 120.0);
+    }
+
+    #[test]
+    fn test_authoritative_position_from_game() {
+        let game = json!({
+            "room1": {"players": [{"id": 1, "x": 5.0, "y": 6.0}]},
+            "room2": {"players": [{"id": 2, "x": 7.0, "y": 8.0, "input": 9}]},
+        });
+        assert_eq!(Authoritative::from_game(&game, 2), Some(Authoritative { input: 9, x: 7.0, y: 8.0 }));
+        // No input recorded yet, so there is nothing to reconcile against
+        assert_eq!(Authoritative::from_game(&game, 1), None);
+    }
+}
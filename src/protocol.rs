@@ -0,0 +1,483 @@
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::networking::CODECS;
+
+// Wire protocol shared by client and server.
+// Every frame is one JSON object with a "type" tag, e.g. {"type":"get_game"}.
+// Position updates can also travel as UDP datagrams, which wrap a message with a sequence number.
+
+pub type PlayerId = u32;
+
+/// Bumped whenever a message changes shape in a way older builds cannot read.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client protocol version this server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Longest player name the server accepts, same as the settings screen allows.
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Optional features, offered by the client in `Hello` and confirmed by the server in `Welcome`.
+/// Either side may ignore ones it does not know.
+pub mod capability {
+    /// Position updates over the UDP channel.
+    pub const UDP: &str = "udp";
+}
+
+/// Capabilities this build supports.
+pub fn capabilities() -> Vec<String> {
+    vec![capability::UDP.to_string()]
+}
+
+/// Position fields the client reports for its own player.
+/// The server already knows which connection sent it, so there is no id here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PositionUpdate {
+    pub room: i32,
+    pub x: f32,
+    pub y: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sprite_state: Option<i32>,
+    /// Input sequence number of the movement that led here, counting up from 1 per client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<u32>,
+}
+
+/// Position of any entity (player or npc) as the server sees it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntityPosition {
+    pub id: PlayerId,
+    pub x: f32,
+    pub y: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sprite_state: Option<i32>,
+    /// Newest input of the player's own that this position includes, so its client can reconcile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<u32>,
+}
+
+/// What changed in one room between two snapshots. Entity lists ("players", "npcs", "objects")
+/// are diffed entity by entity using their "id"; any other room field is sent whole.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RoomDelta {
+    /// Room fields that changed, with null for ones that went away.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+    /// Whole entities that are new, per list.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub added: BTreeMap<String, Vec<Value>>,
+    /// The "id" and changed fields of entities that were already there, per list. Null removes a field.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub changed: BTreeMap<String, Vec<Value>>,
+    /// Ids of entities that are gone, per list.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub removed: BTreeMap<String, Vec<Value>>,
+}
+
+/// What changed in the game state between two snapshots. See `replication`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct StateDelta {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rooms: BTreeMap<String, RoomDelta>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub new_rooms: Map<String, Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_rooms: Vec<String>,
+}
+
+impl StateDelta {
+    pub fn is_empty(&self) -> bool {
+        self.new_rooms.is_empty()
+            && self.removed_rooms.is_empty()
+            && self.rooms.values().all(|room| *room == RoomDelta::default())
+    }
+}
+
+/// Who a chat message is for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatScope {
+    /// Everyone in the sender's room.
+    Room,
+    /// Everyone on the server.
+    Global,
+    /// One player, given by `to`.
+    Whisper,
+}
+
+/// Everything a client can send to the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// First message on every connection, before `Join`.
+    Hello {
+        version: u32,
+        name: String,
+        #[serde(default)]
+        skin: i32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        /// Codecs the client can read after the handshake. Empty means JSON only.
+        #[serde(default)]
+        codecs: Vec<String>,
+    },
+    /// Sent after `Welcome`. Pass the token from an earlier `Joined` to get the same player back.
+    Join {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// The player's PREFERRED_LATENCY setting, so the server can tell when it is far off.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        preferred_latency_ms: Option<u32>,
+    },
+    /// Ask for a full snapshot of the game state.
+    GetGame,
+    /// The client has the snapshot numbered `seq`, from a `Game` or `Delta`.
+    Ack { seq: u32 },
+    UpdatePosition(PositionUpdate),
+    /// Something to say. `to` is the player id for a whisper.
+    Chat {
+        scope: ChatScope,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<PlayerId>,
+        text: String,
+    },
+}
+
+/// Everything the server can send to a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Answer to a compatible `Hello`, with the capabilities both sides support.
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+        /// Codec both sides switch to right after this message.
+        #[serde(default = "default_codec")]
+        codec: String,
+        /// Key for this connection's `ClientDatagram`s, when both sides can use UDP.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        udp_key: Option<String>,
+    },
+    /// The server will not talk to this client; it closes the connection after sending this.
+    Rejected { reason: String },
+    /// Answer to `Join`: the server-assigned player id and the token to resume it with.
+    Joined {
+        player_id: PlayerId,
+        token: String,
+        /// The player entity as it was left, when an earlier session was resumed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        player: Option<Value>,
+        /// UDP port to send `ClientDatagram`s to, if the server has one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        udp_port: Option<u16>,
+    },
+    /// The server got a datagram from this client, so position updates may go over UDP from now on.
+    UdpBound,
+    /// Full snapshot of every room. `seq` numbers it for `Ack` and later deltas.
+    Game {
+        game: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u32>,
+    },
+    /// Changes since snapshot `base`, which make snapshot `seq`.
+    Delta { base: u32, seq: u32, changes: StateDelta },
+    /// A whole player entity, added or replaced.
+    Player { player: Value },
+    UpdatePosition(EntityPosition),
+    UpdateNpcPosition(EntityPosition),
+    /// Everything that moved in one room during server tick `tick`, sent once per tick.
+    RoomUpdate {
+        tick: u64,
+        room: i32,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        players: Vec<EntityPosition>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        npcs: Vec<EntityPosition>,
+    },
+    /// A player (`npc` false) or NPC came into view, whole.
+    Spawn { room: i32, npc: bool, entity: Value },
+    /// A player or NPC went out of view, or left the room.
+    Despawn { room: i32, npc: bool, id: PlayerId },
+    /// A player disconnected and was removed from its room.
+    PlayerLeft { id: PlayerId },
+    /// The server could not handle the last message.
+    Error { reason: String },
+    /// Something the server's admin wants every player to read.
+    Announcement { text: String },
+    /// A chat message from player `from`, after the server's filters. Whispers also go back to
+    /// their sender, so it sees what was sent.
+    Chat {
+        scope: ChatScope,
+        from: PlayerId,
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<PlayerId>,
+        text: String,
+    },
+}
+
+/// A client message sent over UDP. The `udp_key` from the connection's `Welcome` ties it to a
+/// joined player; the session token never goes over UDP, since anyone on the path could read it
+/// there and resume the session. `seq` goes up by one per datagram so late ones can be dropped.
+/// Only position updates are accepted this way; joins, room changes and everything else that must
+/// arrive stay on TCP.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientDatagram {
+    pub key: String,
+    pub seq: u32,
+    pub message: ClientMessage,
+}
+
+/// A server message sent over UDP. `seq` is the sequence number of the client datagram that caused
+/// it, so it only orders updates about the same entity.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerDatagram {
+    pub seq: u32,
+    pub message: ServerMessage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Not JSON, or JSON with missing/mistyped fields.
+    Malformed(String),
+    /// Valid JSON whose "type" this side does not know about.
+    UnknownType(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            ProtocolError::UnknownType(kind) => write!(f, "unknown message type: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Codec every connection starts with, and the one used when nothing better is agreed on.
+pub const DEFAULT_CODEC: &str = "json";
+
+fn default_codec() -> String {
+    DEFAULT_CODEC.to_string()
+}
+
+/// Checks a client's `Hello` against this server. Returns the `Welcome` to send back, or the
+/// reason to reject the client with. The server's `preferred_codec` is used if the client
+/// offered it, JSON otherwise.
+pub fn negotiate(version: u32, name: &str, capabilities: &[String], codecs: &[String], preferred_codec: &str) -> Result<ServerMessage, String> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Your game is out of date (protocol {}, server needs {} to {}). Please update.",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    if version > PROTOCOL_VERSION {
+        return Err(format!(
+            "The server is out of date (protocol {}, your game uses {}).",
+            PROTOCOL_VERSION, version
+        ));
+    }
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(format!("Names must be 1 to {} characters long.", MAX_NAME_LENGTH));
+    }
+    let ours = self::capabilities();
+    let shared = capabilities.iter().filter(|c| ours.contains(c)).cloned().collect();
+    let codec = if codecs.iter().any(|c| c == preferred_codec) && CODECS.contains(&preferred_codec) {
+        preferred_codec.to_string()
+    } else {
+        default_codec()
+    };
+    Ok(ServerMessage::Welcome { version: PROTOCOL_VERSION, capabilities: shared, codec, udp_key: None })
+}
+
+fn decode<T: DeserializeOwned>(text: &str) -> Result<T, ProtocolError> {
+    let value: Value = serde_json::from_str(text)
+        .map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    let kind = match value.get("type").and_then(|t| t.as_str()) {
+        Some(kind) => kind.to_string(),
+        None => return Err(ProtocolError::Malformed("missing \"type\" field".to_string())),
+    };
+
+    serde_json::from_value(value).map_err(|e| {
+        // serde reports an unrecognised tag as "unknown variant `...`"
+        if e.to_string().starts_with("unknown variant") {
+            ProtocolError::UnknownType(kind)
+        } else {
+            ProtocolError::Malformed(e.to_string())
+        }
+    })
+}
+
+fn encode<T: Serialize>(message: &T) -> String {
+    // Every message type is plain data, so serialising cannot fail.
+    serde_json::to_string(message).expect("protocol messages always serialize")
+}
+
+fn decode_datagram<T: DeserializeOwned>(text: &str) -> Result<T, ProtocolError> {
+    serde_json::from_str(text).map_err(|e| ProtocolError::Malformed(e.to_string()))
+}
+
+impl ClientDatagram {
+    pub fn from_json(text: &str) -> Result<Self, ProtocolError> {
+        decode_datagram(text)
+    }
+
+    pub fn to_json(&self) -> String {
+        encode(self)
+    }
+}
+
+impl ServerDatagram {
+    pub fn from_json(text: &str) -> Result<Self, ProtocolError> {
+        decode_datagram(text)
+    }
+
+    pub fn to_json(&self) -> String {
+        encode(self)
+    }
+}
+
+impl ClientMessage {
+    pub fn from_json(text: &str) -> Result<Self, ProtocolError> {
+        decode(text)
+    }
+
+    pub fn to_json(&self) -> String {
+        encode(self)
+    }
+
+    /// The message's "type" on the wire, which the server's metrics group handler time by.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Hello { .. } => "hello",
+            ClientMessage::Join { .. } => "join",
+            ClientMessage::GetGame => "get_game",
+            ClientMessage::Ack { .. } => "ack",
+            ClientMessage::UpdatePosition(_) => "update_position",
+            ClientMessage::Chat { .. } => "chat",
+        }
+    }
+}
+
+impl ServerMessage {
+    pub fn from_json(text: &str) -> Result<Self, ProtocolError> {
+        decode(text)
+    }
+
+    pub fn to_json(&self) -> String {
+        encode(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_messages_round_trip() {
+        let message = ClientMessage::UpdatePosition(PositionUpdate {
+            room: 1,
+            x: 400.0,
+            y: 250.0,
+            width: Some(50),
+            height: None,
+            sprite_state: Some(3),
+            input: Some(12),
+        });
+        let text = message.to_json();
+        let value: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["type"], "update_position");
+        assert_eq!(message.kind(), "update_position");
+        assert!(value.get("height").is_none());
+        assert_eq!(ClientMessage::from_json(&text).unwrap(), message);
+
+        assert_eq!(ClientMessage::GetGame.to_json(), r#"{"type":"get_game"}"#);
+
+        let whisper = ClientMessage::Chat { scope: ChatScope::Whisper, to: Some(2), text: "hi".to_string() };
+        assert_eq!(whisper.to_json(), r#"{"type":"chat","scope":"whisper","to":2,"text":"hi"}"#);
+        assert_eq!(whisper.kind(), "chat");
+        assert_eq!(ClientMessage::from_json(&whisper.to_json()).unwrap(), whisper);
+
+        let snapshot = ServerMessage::Game { game: json!({"room1": {"players": []}}), seq: Some(3) };
+        assert_eq!(ServerMessage::from_json(&snapshot.to_json()).unwrap(), snapshot);
+        // Snapshots from servers without delta sync have no seq
+        assert_eq!(
+            ServerMessage::from_json(r#"{"type":"game","game":{}}"#).unwrap(),
+            ServerMessage::Game { game: json!({}), seq: None }
+        );
+
+        let mut changes = StateDelta::default();
+        changes.rooms.insert("room1".to_string(), RoomDelta {
+            changed: BTreeMap::from([("players".to_string(), vec![json!({"id": 1, "x": 5.0})])]),
+            ..RoomDelta::default()
+        });
+        let delta = ServerMessage::Delta { base: 3, seq: 4, changes };
+        assert_eq!(
+            delta.to_json(),
+            r#"{"type":"delta","base":3,"seq":4,"changes":{"rooms":{"room1":{"changed":{"players":[{"id":1,"x":5.0}]}}}}}"#
+        );
+        assert_eq!(ServerMessage::from_json(&delta.to_json()).unwrap(), delta);
+
+        let datagram = ClientDatagram { key: "abc".to_string(), seq: 7, message };
+        let value: Value = serde_json::from_str(&datagram.to_json()).unwrap();
+        assert_eq!(value["message"]["type"], "update_position");
+        assert_eq!(ClientDatagram::from_json(&datagram.to_json()).unwrap(), datagram);
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let offered = vec![capability::UDP.to_string(), "teleport".to_string()];
+        let json_only = vec!["json".to_string()];
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION, "Player", &offered, &json_only, "json"),
+            Ok(ServerMessage::Welcome { version: PROTOCOL_VERSION, capabilities: vec![capability::UDP.to_string()], codec: "json".to_string(), udp_key: None })
+        );
+        assert!(negotiate(MIN_PROTOCOL_VERSION - 1, "Player", &offered, &json_only, "json").unwrap_err().contains("out of date"));
+        assert!(negotiate(PROTOCOL_VERSION + 1, "Player", &offered, &json_only, "json").unwrap_err().contains("server is out of date"));
+        assert!(negotiate(PROTOCOL_VERSION, "   ", &offered, &json_only, "json").is_err());
+        assert!(negotiate(PROTOCOL_VERSION, &"x".repeat(MAX_NAME_LENGTH + 1), &offered, &json_only, "json").is_err());
+
+        // The server's preferred codec wins only if the client offered it
+        let codec_of = |codecs: &[String], preferred: &str| match negotiate(PROTOCOL_VERSION, "Player", &[], codecs, preferred) {
+            Ok(ServerMessage::Welcome { codec, .. }) => codec,
+            other => panic!("expected welcome, got {:?}", other),
+        };
+        let both = vec!["msgpack".to_string(), "json".to_string()];
+        assert_eq!(codec_of(&both, "msgpack"), "msgpack");
+        assert_eq!(codec_of(&both, "json"), "json");
+        assert_eq!(codec_of(&json_only, "msgpack"), "json");
+        assert_eq!(codec_of(&[], "msgpack"), "json");
+
+        // Fields missing from an older client's hello fall back to defaults
+        let hello = ClientMessage::from_json(r#"{"type":"hello","version":1,"name":"Old"}"#).unwrap();
+        assert_eq!(hello, ClientMessage::Hello { version: 1, name: "Old".to_string(), skin: 0, capabilities: vec![], codecs: vec![] });
+        let welcome = ServerMessage::from_json(r#"{"type":"welcome","version":1,"capabilities":[]}"#).unwrap();
+        assert_eq!(welcome, ServerMessage::Welcome { version: 1, capabilities: vec![], codec: "json".to_string(), udp_key: None });
+    }
+
+    #[test]
+    fn test_unknown_and_malformed_messages() {
+        assert_eq!(
+            ClientMessage::from_json(r#"{"type":"teleport"}"#),
+            Err(ProtocolError::UnknownType("teleport".to_string()))
+        );
+        assert!(matches!(ClientMessage::from_json("not json"), Err(ProtocolError::Malformed(_))));
+        assert!(matches!(ClientMessage::from_json(r#"{"x": 4}"#), Err(ProtocolError::Malformed(_))));
+        assert!(matches!(
+            ClientMessage::from_json(r#"{"type":"update_position","x":"left"}"#),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+}
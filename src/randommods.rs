@@ -25,7 +25,9 @@ pub fn get_socket_id(stream: &AsyncTcpStream) -> usize {
 
 use serde_json::{json, Value};
 
-fn find_changes(old_value: &Value, new_value: &Value) -> Value {
+/// Describes how `new_value` differs from `old_value`. For two objects, "changed" holds every
+/// field that changed or appeared, with null for fields that were removed.
+pub fn find_changes(old_value: &Value, new_value: &Value) -> Value {
     // Handle integer comparison
     if let (Some(old_int), Some(new_int)) = (old_value.as_i64(), new_value.as_i64()) {
         let difference = new_int - old_int;
@@ -0,0 +1,209 @@
+use std::time::{Duration, Instant};
+
+// Per-connection flood protection for AsyncTcpServer. Every frame a client sends costs one
+// message token and one byte token per payload byte. A client that runs out is first warned,
+// then throttled (its reads are delayed until the buckets refill), then disconnected.
+
+/// A bucket that holds up to `capacity` tokens and refills at `rate` tokens per second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        TokenBucket { capacity, rate, tokens: capacity, last_refill: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Whether `amount` tokens are available right now, without taking them.
+    pub fn has(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount
+    }
+
+    /// Takes `amount` tokens, going into debt if there are not enough. The debt is paid off by
+    /// later refills, which is what makes a throttled client wait.
+    pub fn take(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount;
+    }
+
+    /// How long until the bucket has `amount` tokens again.
+    pub fn time_until(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = (amount.min(self.capacity) - self.tokens).max(0.0);
+        if self.rate <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64(missing / self.rate)
+    }
+}
+
+/// Limits applied to every connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    pub messages_per_second: f64,
+    /// How many messages may arrive at once after a quiet spell.
+    pub message_burst: f64,
+    pub bytes_per_second: f64,
+    pub byte_burst: f64,
+    /// Bigger frames close the connection straight away.
+    pub max_frame_size: usize,
+    /// Frames over the limit that only earn a warning.
+    pub warnings: u32,
+    /// Frames over the limit, counting the warnings, after which the client is disconnected.
+    pub max_violations: u32,
+    /// A client that stays within its limits this long has its violations forgiven.
+    pub forgive_after: Duration,
+}
+
+impl Default for RateLimits {
+    /// Roomy enough for a client sending a position every frame at 60 FPS plus heartbeats and chat.
+    fn default() -> Self {
+        RateLimits {
+            messages_per_second: 120.0,
+            message_burst: 240.0,
+            bytes_per_second: 64.0 * 1024.0,
+            byte_burst: 128.0 * 1024.0,
+            max_frame_size: 64 * 1024,
+            warnings: 3,
+            max_violations: 50,
+            forgive_after: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What to do with a frame that just arrived.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitStep {
+    Allow,
+    /// Over the limit, but still handled. The client should be told to slow down.
+    Warn,
+    /// Over the limit again: wait this long before handling the frame.
+    Throttle(Duration),
+    /// Too many violations; close the connection.
+    Disconnect,
+}
+
+/// Token buckets and violation count for one connection.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            messages: TokenBucket::new(limits.messages_per_second, limits.message_burst),
+            bytes: TokenBucket::new(limits.bytes_per_second, limits.byte_burst),
+            violations: 0,
+            last_violation: None,
+        }
+    }
+
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+
+    /// Charges a frame of `len` bytes and decides what happens to it.
+    pub fn check(&mut self, len: usize) -> RateLimitStep {
+        self.check_at(len, Instant::now())
+    }
+
+    fn check_at(&mut self, len: usize, now: Instant) -> RateLimitStep {
+        let len = len as f64;
+        let within = self.messages.has(1.0, now) && self.bytes.has(len, now);
+        self.messages.take(1.0, now);
+        self.bytes.take(len, now);
+
+        if within {
+            if self.last_violation.is_some_and(|at| now.duration_since(at) >= self.limits.forgive_after) {
+                self.violations = 0;
+                self.last_violation = None;
+            }
+            return RateLimitStep::Allow;
+        }
+
+        self.violations += 1;
+        self.last_violation = Some(now);
+        if self.violations >= self.limits.max_violations {
+            RateLimitStep::Disconnect
+        } else if self.violations <= self.limits.warnings {
+            RateLimitStep::Warn
+        } else {
+            let wait = self.messages.time_until(1.0, now).max(self.bytes.time_until(len, now));
+            RateLimitStep::Throttle(wait)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flooding_escalates_from_warning_to_disconnect() {
+        let limits = RateLimits {
+            messages_per_second: 10.0,
+            message_burst: 2.0,
+            bytes_per_second: 1000.0,
+            byte_burst: 1000.0,
+            max_frame_size: 100,
+            warnings: 1,
+            max_violations: 3,
+            forgive_after: Duration::from_secs(1),
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check_at(10, start), RateLimitStep::Allow);
+        assert_eq!(limiter.check_at(10, start), RateLimitStep::Allow);
+        assert_eq!(limiter.check_at(10, start), RateLimitStep::Warn);
+        match limiter.check_at(10, start) {
+            // Two messages in debt at 10 per second
+            RateLimitStep::Throttle(wait) => assert!(wait >= Duration::from_millis(250) && wait <= Duration::from_millis(350)),
+            other => panic!("expected throttle, got {:?}", other),
+        }
+        assert_eq!(limiter.check_at(10, start), RateLimitStep::Disconnect);
+    }
+
+    #[test]
+    fn test_byte_limit_and_forgiveness() {
+        let limits = RateLimits {
+            messages_per_second: 100.0,
+            message_burst: 100.0,
+            bytes_per_second: 100.0,
+            byte_burst: 100.0,
+            max_frame_size: 100,
+            warnings: 5,
+            max_violations: 10,
+            forgive_after: Duration::from_secs(1),
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check_at(80, start), RateLimitStep::Allow);
+        assert_eq!(limiter.check_at(80, start), RateLimitStep::Warn);
+        assert_eq!(limiter.violations(), 1);
+
+        // Two quiet seconds refill the buckets and clear the record
+        let later = start + Duration::from_secs(2);
+        assert_eq!(limiter.check_at(10, later), RateLimitStep::Allow);
+        assert_eq!(limiter.violations(), 0);
+    }
+}
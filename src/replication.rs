@@ -0,0 +1,484 @@
+use crate::protocol::{PlayerId, RoomDelta, ServerMessage, StateDelta};
+use crate::randommods::find_changes;
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+// Delta-compressed replication of the game state. The server remembers the last snapshot each
+// client acknowledged and sends only what changed since then; the client keeps the snapshots it
+// was sent so it can apply a delta on top of whichever one the server based it on.
+
+/// Snapshots a server keeps per client while waiting for acks. A client that falls further
+/// behind than this gets deltas against its last ack, which are just bigger.
+pub const MAX_PENDING_SNAPSHOTS: usize = 64;
+
+/// Whether a room field holds entities that can be diffed one by one: an array of objects that
+/// all have an "id".
+fn is_entity_list(value: &Value) -> bool {
+    value.as_array().is_some_and(|list| list.iter().all(|entity| entity.get("id").is_some()))
+}
+
+/// Shallow changes between two objects, as `find_changes` reports them: the new value of every
+/// field that changed or appeared, and null for every field that went away.
+fn changed_fields(old: &Value, new: &Value) -> Map<String, Value> {
+    match find_changes(old, new).get_mut("changed").map(Value::take) {
+        Some(Value::Object(changed)) => changed,
+        _ => Map::new(),
+    }
+}
+
+fn diff_entities(old: &[Value], new: &[Value], delta: &mut RoomDelta, list: &str) {
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    for entity in new {
+        match old.iter().find(|o| o["id"] == entity["id"]) {
+            Some(previous) => {
+                let mut fields = changed_fields(previous, entity);
+                if !fields.is_empty() {
+                    fields.insert("id".to_string(), entity["id"].clone());
+                    changed.push(Value::Object(fields));
+                }
+            }
+            None => added.push(entity.clone()),
+        }
+    }
+    let removed: Vec<Value> = old.iter()
+        .filter(|o| !new.iter().any(|entity| entity["id"] == o["id"]))
+        .map(|o| o["id"].clone())
+        .collect();
+
+    if !added.is_empty() {
+        delta.added.insert(list.to_string(), added);
+    }
+    if !changed.is_empty() {
+        delta.changed.insert(list.to_string(), changed);
+    }
+    if !removed.is_empty() {
+        delta.removed.insert(list.to_string(), removed);
+    }
+}
+
+fn diff_room(old: &Value, new: &Value) -> RoomDelta {
+    let mut delta = RoomDelta::default();
+    for (key, value) in changed_fields(old, new) {
+        let previous = &old[&key];
+        if is_entity_list(previous) && is_entity_list(&value) {
+            diff_entities(previous.as_array().unwrap(), value.as_array().unwrap(), &mut delta, &key);
+        } else {
+            delta.fields.insert(key, value);
+        }
+    }
+    delta
+}
+
+/// Everything that changed between two game states, room by room and entity by entity.
+pub fn diff(old: &Value, new: &Value) -> StateDelta {
+    let mut delta = StateDelta::default();
+    for (name, room) in changed_fields(old, new) {
+        match (&old[&name], room) {
+            (_, Value::Null) => delta.removed_rooms.push(name),
+            (Value::Null, room) => {
+                delta.new_rooms.insert(name, room);
+            }
+            (previous, room) => {
+                delta.rooms.insert(name, diff_room(previous, &room));
+            }
+        }
+    }
+    delta
+}
+
+/// Sets or (for null) removes each field on an object.
+fn apply_fields(target: &mut Value, fields: &Map<String, Value>) {
+    if let Value::Object(target) = target {
+        for (key, value) in fields {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn apply_room(room: &mut Value, delta: &RoomDelta) {
+    apply_fields(room, &delta.fields);
+    for (list, ids) in &delta.removed {
+        if let Some(entities) = room.get_mut(list).and_then(Value::as_array_mut) {
+            entities.retain(|entity| !ids.contains(&entity["id"]));
+        }
+    }
+    for (list, changes) in &delta.changed {
+        if let Some(entities) = room.get_mut(list).and_then(Value::as_array_mut) {
+            for change in changes {
+                if let (Some(entity), Some(fields)) = (entities.iter_mut().find(|e| e["id"] == change["id"]), change.as_object()) {
+                    apply_fields(entity, fields);
+                }
+            }
+        }
+    }
+    for (list, added) in &delta.added {
+        if room.get(list).is_none_or(|entities| !entities.is_array()) {
+            room[list.as_str()] = Value::Array(Vec::new());
+        }
+        let entities = room[list.as_str()].as_array_mut().unwrap();
+        for entity in added {
+            // The entity may already be there from an earlier message, e.g. a `player`
+            match entities.iter_mut().find(|e| e["id"] == entity["id"]) {
+                Some(existing) => *existing = entity.clone(),
+                None => entities.push(entity.clone()),
+            }
+        }
+    }
+}
+
+/// Applies a delta made by `diff` to a game state. Applied to the state `diff` started from, the
+/// result is the state it ended at; rooms or entities the delta mentions but `game` lacks are skipped.
+pub fn apply(game: &mut Value, delta: &StateDelta) {
+    if !game.is_object() {
+        *game = Value::Object(Map::new());
+    }
+    let rooms = game.as_object_mut().unwrap();
+    for name in &delta.removed_rooms {
+        rooms.remove(name);
+    }
+    for (name, room) in &delta.new_rooms {
+        rooms.insert(name.clone(), room.clone());
+    }
+    for (name, room_delta) in &delta.rooms {
+        if let Some(room) = rooms.get_mut(name) {
+            apply_room(room, room_delta);
+        }
+    }
+}
+
+/// A snapshot the server sent and is waiting to hear back about.
+enum Pending {
+    /// A full snapshot, which deltas start again from.
+    Full(Arc<Value>),
+    /// What changed since the snapshot sent just before it.
+    Changes(StateDelta),
+}
+
+/// What the server knows about one client's copy of the game state. Only two whole states are
+/// kept, the last one acknowledged and the last one sent; snapshots in between are kept as the
+/// changes from one to the next.
+pub struct ServerReplica {
+    next_seq: u32,
+    acked: Option<(u32, Arc<Value>)>,
+    sent: Option<Arc<Value>>,
+    /// Snapshots sent but not acknowledged yet, oldest first.
+    pending: VecDeque<(u32, Pending)>,
+}
+
+impl Default for ServerReplica {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerReplica {
+    pub fn new() -> Self {
+        ServerReplica { next_seq: 1, acked: None, sent: None, pending: VecDeque::new() }
+    }
+
+    fn remember(&mut self, game: Arc<Value>, snapshot: Pending) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.pending.push_back((seq, snapshot));
+        self.sent = Some(game);
+        seq
+    }
+
+    /// A full snapshot, for a client that just joined or lost track. Deltas start again from
+    /// this snapshot once the client acknowledges it.
+    pub fn full(&mut self, game: &Value) -> ServerMessage {
+        self.acked = None;
+        self.pending.clear();
+        let game = Arc::new(game.clone());
+        let seq = self.remember(game.clone(), Pending::Full(game.clone()));
+        ServerMessage::Game { game: Value::clone(&game), seq: Some(seq) }
+    }
+
+    /// The update to send this tick: a delta against the last acknowledged snapshot, or `None`
+    /// when nothing changed since then or the client has not acknowledged any snapshot yet.
+    pub fn update(&mut self, game: &Value) -> Option<ServerMessage> {
+        let (base, acked) = self.acked.as_ref()?;
+        let base = *base;
+        let changes = diff(acked, game);
+        if changes.is_empty() {
+            return None;
+        }
+        // A client this far behind gets its later snapshots chained from the acked one instead,
+        // and acks for the ones dropped here are ignored
+        if self.pending.len() >= MAX_PENDING_SNAPSHOTS {
+            self.pending.clear();
+            self.sent = Some(acked.clone());
+        }
+        let step = match &self.sent {
+            Some(sent) => diff(sent, game),
+            None => changes.clone(),
+        };
+        let seq = self.remember(Arc::new(game.clone()), Pending::Changes(step));
+        Some(ServerMessage::Delta { base, seq, changes })
+    }
+
+    /// Records that the client has the snapshot numbered `seq`. Unknown or stale acks are ignored.
+    pub fn ack(&mut self, seq: u32) {
+        let Some(index) = self.pending.iter().position(|(pending, _)| *pending == seq) else {
+            return;
+        };
+        let newest = index + 1 == self.pending.len();
+        let mut state = self.acked.as_ref().map(|(_, acked)| acked.clone());
+        for (_, snapshot) in self.pending.drain(..=index) {
+            match snapshot {
+                Pending::Full(game) => state = Some(game),
+                Pending::Changes(step) => {
+                    if let Some(state) = &mut state {
+                        apply(Arc::make_mut(state), &step);
+                    }
+                }
+            }
+        }
+        // The newest snapshot is the one already kept as sent
+        let state = if newest { self.sent.clone() } else { state };
+        self.acked = state.map(|state| (seq, state));
+    }
+}
+
+/// What happened to a delta the client received.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeltaOutcome {
+    /// Applied; acknowledge it.
+    Applied,
+    /// Based on a snapshot the client does not have; ask for a full one.
+    Desync,
+    /// Dropped while a full snapshot is on its way.
+    Waiting,
+}
+
+/// The snapshots a client was sent, so deltas against any of them can be applied.
+pub struct ClientReplica {
+    history: VecDeque<(u32, Value)>,
+    awaiting_snapshot: bool,
+}
+
+impl Default for ClientReplica {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientReplica {
+    pub fn new() -> Self {
+        ClientReplica { history: VecDeque::new(), awaiting_snapshot: true }
+    }
+
+    /// Starts over from a full snapshot.
+    pub fn snapshot(&mut self, seq: u32, game: &Value) {
+        self.history.clear();
+        self.history.push_back((seq, game.clone()));
+        self.awaiting_snapshot = false;
+    }
+
+    /// Applies a delta on top of the snapshot it was based on.
+    pub fn delta(&mut self, base: u32, seq: u32, changes: &StateDelta) -> DeltaOutcome {
+        if self.awaiting_snapshot {
+            return DeltaOutcome::Waiting;
+        }
+        let index = match self.history.iter().position(|(kept, _)| *kept == base) {
+            Some(index) => index,
+            None => {
+                self.awaiting_snapshot = true;
+                return DeltaOutcome::Desync;
+            }
+        };
+        let mut game = self.history[index].1.clone();
+        apply(&mut game, changes);
+        // The server has our ack for `base`, so it never goes back further than that
+        self.history.drain(..index);
+        self.history.push_back((seq, game));
+        if self.history.len() > MAX_PENDING_SNAPSHOTS {
+            self.history.pop_front();
+        }
+        DeltaOutcome::Applied
+    }
+
+    /// The newest state the server sent.
+    pub fn latest(&self) -> Option<&Value> {
+        self.history.back().map(|(_, game)| game)
+    }
+}
+
+/// Replaces a client's game state with the newest one the server sent, which is what its deltas
+/// are built on, but keeps the local player's position: the client moves it ahead of the server.
+pub fn adopt_latest(game: &mut Value, latest: &Value, own_id: PlayerId) {
+    let own_position = |game: &Value| {
+        game.as_object()?.values()
+            .filter_map(|room| room["players"].as_array())
+            .flatten()
+            .find(|player| player["id"] == own_id)
+            .map(|player| (player["x"].clone(), player["y"].clone()))
+    };
+    let local = own_position(game);
+    *game = latest.clone();
+    if let (Some((x, y)), Some(rooms)) = (local, game.as_object_mut()) {
+        let own = rooms.values_mut()
+            .filter_map(|room| room.get_mut("players").and_then(Value::as_array_mut))
+            .flatten()
+            .find(|player| player["id"] == own_id);
+        if let Some(player) = own {
+            player["x"] = x;
+            player["y"] = y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn game() -> Value {
+        json!({
+            "room1": {
+                "objects": [{"x": 0, "y": 0, "width": 1000, "height": 1000, "id": 0}],
+                "players": [{"id": 1, "x": 10.0, "y": 20.0}, {"id": 2, "x": 30.0, "y": 40.0}],
+                "npcs": [],
+                "roomID": 1
+            },
+        })
+    }
+
+    #[test]
+    fn test_diff_sends_only_changed_fields() {
+        let old = game();
+        let mut new = game();
+        new["room1"]["players"][0]["x"] = json!(15.0);
+        new["room1"]["players"].as_array_mut().unwrap().remove(1);
+        new["room1"]["players"].as_array_mut().unwrap().push(json!({"id": 3, "x": 0.0, "y": 0.0}));
+        new["room2"] = json!({"players": [], "roomID": 2});
+
+        let delta = diff(&old, &new);
+        let room = &delta.rooms["room1"];
+        assert_eq!(room.changed["players"], vec![json!({"id": 1, "x": 15.0})]);
+        assert_eq!(room.added["players"], vec![json!({"id": 3, "x": 0.0, "y": 0.0})]);
+        assert_eq!(room.removed["players"], vec![json!(2)]);
+        assert!(room.fields.is_empty());
+        assert_eq!(delta.new_rooms["room2"], new["room2"]);
+
+        let mut applied = old.clone();
+        apply(&mut applied, &delta);
+        assert_eq!(applied, new);
+
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_server_deltas_against_the_last_ack() {
+        let mut server = ServerReplica::new();
+        let mut client = ClientReplica::new();
+        let mut state = game();
+
+        // Nothing goes out until the client has a snapshot
+        assert_eq!(server.update(&state), None);
+        let seq = match server.full(&state) {
+            ServerMessage::Game { game, seq: Some(seq) } => {
+                client.snapshot(seq, &game);
+                seq
+            }
+            other => panic!("expected a snapshot, got {:?}", other),
+        };
+        assert_eq!(server.update(&state), None);
+        server.ack(seq);
+        assert_eq!(server.update(&state), None);
+
+        // Two ticks go out before the first ack comes back; both are based on the same snapshot
+        state["room1"]["players"][0]["x"] = json!(11.0);
+        let first = server.update(&state).unwrap();
+        state["room1"]["players"][1]["y"] = json!(41.0);
+        let second = server.update(&state).unwrap();
+        for message in [&first, &second] {
+            match message {
+                ServerMessage::Delta { base, seq, changes } => {
+                    assert_eq!(*base, 1);
+                    assert_eq!(client.delta(*base, *seq, changes), DeltaOutcome::Applied);
+                    server.ack(*seq);
+                }
+                other => panic!("expected a delta, got {:?}", other),
+            }
+        }
+        assert_eq!(client.latest(), Some(&state));
+
+        // The next delta is only what changed since the newest ack
+        state["room1"]["roomID"] = json!(7);
+        match server.update(&state).unwrap() {
+            ServerMessage::Delta { base, changes, .. } => {
+                assert_eq!(base, 3);
+                assert_eq!(changes.rooms["room1"].fields, json!({"roomID": 7}).as_object().unwrap().clone());
+                assert!(changes.rooms["room1"].changed.is_empty());
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_acks_rebuild_snapshots_from_the_changes_kept() {
+        let mut server = ServerReplica::new();
+        let mut state = game();
+        server.full(&state);
+        server.ack(1);
+
+        // 2 and 3 both go out against 1; 2 is acked before 3, and 3 reverts part of 2
+        state["room1"]["players"][0]["x"] = json!(11.0);
+        server.update(&state).unwrap();
+        state["room1"]["players"][0]["x"] = json!(10.0);
+        state["room1"]["players"][1]["y"] = json!(41.0);
+        server.update(&state).unwrap();
+        server.ack(2);
+        server.ack(3);
+        assert_eq!(server.update(&state), None);
+
+        // Far behind: the chain starts over from the last ack, and later acks still work
+        for step in 0..MAX_PENDING_SNAPSHOTS + 3 {
+            state["room1"]["players"][0]["y"] = json!(step);
+            server.update(&state).unwrap();
+        }
+        assert!(server.pending.len() < MAX_PENDING_SNAPSHOTS);
+        let newest = server.next_seq - 1;
+        server.ack(newest - 1);
+        match server.update(&state).unwrap() {
+            ServerMessage::Delta { base, changes, .. } => {
+                assert_eq!(base, newest - 1);
+                assert_eq!(changes.rooms["room1"].changed["players"], vec![json!({"id": 1, "y": MAX_PENDING_SNAPSHOTS + 2})]);
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_adopting_the_latest_state_keeps_the_local_position() {
+        let mut live = game();
+        live["room1"]["players"][0]["x"] = json!(99.0);
+        live["room1"]["players"][1]["x"] = json!(77.0);
+        let mut latest = game();
+        latest["room1"]["npcs"] = json!([{"id": 5, "x": 1.0, "y": 1.0}]);
+
+        adopt_latest(&mut live, &latest, 1);
+        let mut expected = latest.clone();
+        expected["room1"]["players"][0]["x"] = json!(99.0);
+        assert_eq!(live, expected);
+    }
+
+    #[test]
+    fn test_client_detects_desync_once() {
+        let mut client = ClientReplica::new();
+        assert_eq!(client.delta(1, 2, &StateDelta::default()), DeltaOutcome::Waiting);
+        client.snapshot(5, &game());
+        assert_eq!(client.delta(4, 6, &StateDelta::default()), DeltaOutcome::Desync);
+        // Further deltas wait for the snapshot that was asked for
+        assert_eq!(client.delta(5, 7, &StateDelta::default()), DeltaOutcome::Waiting);
+        client.snapshot(8, &game());
+        assert_eq!(client.delta(8, 9, &StateDelta::default()), DeltaOutcome::Applied);
+    }
+}
@@ -1,5 +1,7 @@
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 use async_std::task;
 use serde_json::Value;
@@ -9,24 +11,65 @@ use serde_json::from_str;
 use serde_json::to_string;
 use std::sync::{Arc, Mutex};
 
-use crate::networking::AsyncTcpServer;
+use crate::networking::{AsyncTcpServer, AsyncUdpSocket};
 use crate::networking;
 use crate::handle_read::*;
-use crate::networking::ClientConnections;
+use crate::networking::{ClientConnections, ShutdownHandle};
+use crate::session::Sessions;
+use crate::players::Players;
+use crate::protocol::{self, ClientMessage, ServerMessage};
+use crate::ratelimit::{RateLimits, RateLimitStep};
+use crate::tls::TlsSettings;
+use crate::discovery::{self, ServerInfo};
+use crate::simulation::{self, Simulation};
+use crate::metrics;
+use crate::admin::{AdminConsole, BanList};
 
-pub fn main() {
+/// Runs the game server until `shutdown` is triggered. The address it ends up listening on is
+/// sent to `bound` as soon as the socket is bound. Lines from `commands` go to the admin console.
+pub fn main(shutdown: ShutdownHandle, bound: Sender<SocketAddr>, commands: Receiver<String>) {
     let data_json = std::fs::read_to_string("data.json").expect("Failed to read data.json");
     let data: Value = serde_json::from_str(&data_json).expect("Failed to parse data.json");
     let settings = data["settings"].clone();
     let port_str = settings["PORT"].as_str().expect("Expected a string for PORT").to_string();
     let port = from_str::<u16>(&port_str).expect("Failed to parse PORT as u16");
-    let ip_addr = randommods::get_external_ipv4().expect("Failed to get local IP");
-    let server = AsyncTcpServer::new(&format!("{}:{}", ip_addr, port), std::sync::Arc::new(|_| {}));
-    
-    println!("Server starting on {}:{}", ip_addr, port);
+    // Bind to the IP setting, which can be a wildcard like 0.0.0.0 or ::. Left empty or "auto", the
+    // server uses the machine's first external IPv4 address. PORT 0 lets the OS pick a free port.
+    let ip_addr: IpAddr = match settings["IP"].as_str().unwrap_or("").trim() {
+        "" | "auto" => IpAddr::V4(randommods::get_external_ipv4().expect("Failed to get local IP")),
+        ip => ip.parse().expect("Failed to parse IP as an address"),
+    };
+    let mut server = AsyncTcpServer::new(&SocketAddr::new(ip_addr, port).to_string(), std::sync::Arc::new(|_| {}));
+    server.set_shutdown_handle(shutdown);
+    // Banned addresses are turned away before anything else happens on their connection
+    let bans = BanList::new();
+    let accept_bans = bans.clone();
+    server.set_accept_filter(Arc::new(move |peer| !accept_bans.is_banned(peer.ip())));
+
+    let tls_settings = TlsSettings::from_settings(&settings).expect("Invalid TLS settings");
+    let mut dev_hosts = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    if ip_addr.is_unspecified() {
+        dev_hosts.extend(randommods::get_external_ipv4().map(|ip| ip.to_string()));
+    } else {
+        dev_hosts.push(ip_addr.to_string());
+    }
+    let tls_config = tls_settings.server_config(&dev_hosts).expect("Failed to set up TLS");
+    let use_tls = tls_config.is_some();
+    if let Some(config) = tls_config {
+        server.set_tls(config);
+    }
+
+    let local_addr = task::block_on(server.bind()).expect("Failed to bind server");
+    println!("Server starting on {}{}", local_addr, if use_tls { " (TLS)" } else { "" });
+    let _ = bound.send(local_addr);
     
     // Create a game state that can be shared between connections
     let clients = Arc::new(Mutex::new(ClientConnections::new()));
+    let metrics = server.metrics();
+    clients.lock().unwrap().set_metrics(metrics.clone());
+    let sessions = Arc::new(Mutex::new(Sessions::new()));
+    // Locked in this order: sessions, clients, players, game state
+    let players = Arc::new(Mutex::new(Players::new()));
     let game_state = Arc::new(Mutex::new(json!({
         "room1": {
             "objects": [
@@ -34,7 +77,13 @@ pub fn main() {
                 {"x": 0, "y": 0, "width": 1000, "height": 1000, "id": 0},
             ],
             "players": [],
-            "npcs": [],
+            //where players join the room
+            "spawn": {"x": 400, "y": 250},
+            //walks a loop around the room on its own
+            "npcs": [
+                {"id": 1, "x": 100, "y": 100, "width": 40, "height": 40, "speed": 60,
+                 "waypoints": [[100, 100], [800, 100], [800, 800], [100, 800]]},
+            ],
             "roomID": 1
         },
         "room2": {
@@ -48,17 +97,196 @@ pub fn main() {
         },
     })));
 
+    // Forget the connection as soon as the socket closes, but park the player so its token can resume it
+    let disconnect_game_state = game_state.clone();
+    let disconnect_clients = clients.clone();
+    let disconnect_players = players.clone();
+    let disconnect_sessions = sessions.clone();
+    server.set_disconnect_handler(Arc::new(move |stream| {
+        let connection_id = AsyncTcpServer::get_socket_id(&stream);
+        let mut sessions = disconnect_sessions.lock().unwrap();
+        let left = sessions.player_for_connection(connection_id).and_then(|player_id| {
+            handle_disconnect_server(disconnect_game_state.clone(), player_id, &mut disconnect_clients.lock().unwrap(), &mut disconnect_players.lock().unwrap())
+        });
+        sessions.disconnect(connection_id, left);
+    }));
+
+    // Optional overrides for the flood limits, e.g. "MAX_MESSAGES_PER_SECOND": "200"
+    let mut limits = RateLimits::default();
+    let setting = |key: &str| settings[key].as_str().and_then(|v| v.parse::<f64>().ok());
+    if let Some(rate) = setting("MAX_MESSAGES_PER_SECOND") {
+        limits.messages_per_second = rate;
+        limits.message_burst = rate * 2.0;
+    }
+    if let Some(rate) = setting("MAX_BYTES_PER_SECOND") {
+        limits.bytes_per_second = rate;
+        limits.byte_burst = rate * 2.0;
+    }
+    if let Some(size) = setting("MAX_FRAME_SIZE") {
+        limits.max_frame_size = size as usize;
+    }
+    server.set_rate_limits(limits);
+    server.set_rate_limit_handler(Arc::new(|mut stream, step| {
+        let reason = match step {
+            RateLimitStep::Warn => "You are sending too fast, slow down",
+            RateLimitStep::Disconnect => "Disconnected for sending too fast",
+            // Answering every throttled frame would only add to the flood
+            RateLimitStep::Throttle(_) | RateLimitStep::Allow => return,
+        };
+        let notice = ServerMessage::Error { reason: reason.to_string() };
+        task::block_on(AsyncTcpServer::send(&mut stream, &notice.to_json()))
+            .unwrap_or_else(|e| eprintln!("Send error: {}", e));
+    }));
+
+    // Warn when a player's ping climbs well past the PREFERRED_LATENCY they joined with
+    let latency_sessions = sessions.clone();
+    server.set_latency_handler(Arc::new(move |connection_id, rtt| {
+        if let Some((player_id, preferred)) = latency_sessions.lock().unwrap().check_latency(connection_id, rtt) {
+            println!("Player {} latency is {}ms, preferred {}ms", player_id, rtt.as_millis(), preferred.as_millis());
+        }
+    }));
+
+    // Position updates can also come in over UDP on the same port number. Datagrams are not
+    // encrypted, so there is no UDP channel when the connection is meant to be private.
+    if !use_tls {
+        let udp = task::block_on(AsyncUdpSocket::bind(&local_addr.to_string())).expect("Failed to bind UDP socket");
+        clients.lock().unwrap().set_udp_socket(udp.clone());
+        let udp_game_state = game_state.clone();
+        let udp_clients = clients.clone();
+        let udp_players = players.clone();
+        let udp_sessions = sessions.clone();
+        let udp_shutdown = server.shutdown_handle();
+        task::spawn(async move {
+            udp.run_until_shutdown(&udp_shutdown, move |datagram, from| {
+                let sessions = udp_sessions.lock().unwrap();
+                handle_datagram_server(&datagram, from, udp_game_state.clone(), &sessions, &mut udp_clients.lock().unwrap(), &mut udp_players.lock().unwrap());
+                async {}
+            }).await.unwrap_or_else(|e| eprintln!("UDP socket failed: {}", e));
+        });
+    }
+
+    // Answer LAN discovery probes, unless the server only listens on loopback where nobody
+    // else on the network could join it anyway
+    if !ip_addr.is_loopback() {
+        let server_name = settings["SERVER_NAME"].as_str().unwrap_or("RustSandbox").to_string();
+        let discovery_port = discovery::port_setting(&settings);
+        match task::block_on(discovery::bind_responder(&format!("0.0.0.0:{}", discovery_port))) {
+            Ok(responder) => {
+                let discovery_game_state = game_state.clone();
+                let game_port = local_addr.port();
+                task::spawn(discovery::respond(responder, server.shutdown_handle(), move || {
+                    ServerInfo::from_game(&server_name, &discovery_game_state.lock().unwrap(), game_port)
+                }));
+            }
+            Err(e) => eprintln!("LAN discovery is off, could not bind port {}: {}", discovery_port, e),
+        }
+    }
+
+    // "CODEC": "msgpack" switches clients that support it to binary frames after the handshake
+    let codec = match settings["CODEC"].as_str() {
+        Some(name) if networking::codec_by_name(name).is_some() => name.to_string(),
+        Some(name) => {
+            eprintln!("Unknown CODEC {:?}, using {}", name, protocol::DEFAULT_CODEC);
+            protocol::DEFAULT_CODEC.to_string()
+        }
+        None => protocol::DEFAULT_CODEC.to_string(),
+    };
+
+    // The simulation runs TICK_RATE times a second (default 20): queued moves, NPCs, one update
+    // per room, and every SYNC_INTERVAL the delta sync
+    let tick_rate = settings["TICK_RATE"].as_str()
+        .and_then(|rate| rate.parse().ok())
+        .unwrap_or(simulation::DEFAULT_TICK_RATE);
+    let mut simulation = Simulation::new(tick_rate);
+    // Players only hear about entities within INTEREST_RADIUS of them (default 600)
+    if let Some(radius) = settings["INTEREST_RADIUS"].as_str().and_then(|r| r.parse().ok()) {
+        players.lock().unwrap().set_interest_radius(radius);
+    }
+    // CHAT_BLOCKED_WORDS, comma separated, are masked in chat on top of the built-in list
+    for word in settings["CHAT_BLOCKED_WORDS"].as_str().unwrap_or("").split(',') {
+        players.lock().unwrap().chat_filter().block(word);
+    }
+    let tick_clients = clients.clone();
+    let tick_players = players.clone();
+    let tick_game_state = game_state.clone();
+    let tick_shutdown = server.shutdown_handle();
+    thread::spawn(move || {
+        simulation::run_fixed_rate(simulation.interval(), &tick_shutdown, || {
+            let outbox = {
+                let clients = tick_clients.lock().unwrap();
+                let mut players = tick_players.lock().unwrap();
+                let mut game = clients.lock_game(None, &tick_game_state);
+                let outgoing = simulation.tick(&mut game, &mut players, std::time::Instant::now());
+                clients.outbox(outgoing)
+            };
+            // Written with nothing locked, so a client that stopped reading holds up no handler
+            for id in task::block_on(outbox.send()) {
+                tick_clients.lock().unwrap().remove_client(id);
+            }
+        });
+    });
+
+    let console = AdminConsole {
+        game: game_state.clone(),
+        clients: clients.clone(),
+        players: players.clone(),
+        sessions: sessions.clone(),
+        bans,
+        metrics: metrics.clone(),
+        shutdown: server.shutdown_handle(),
+    };
+    thread::spawn(move || {
+        for line in commands {
+            println!("{}", console.run(&line));
+        }
+    });
+
+    // Every METRICS_INTERVAL seconds (default 60, 0 for never) log who is sending the most and
+    // which messages take the longest to handle
+    let metrics_interval = settings["METRICS_INTERVAL"].as_str()
+        .and_then(|secs| secs.parse().ok())
+        .map_or(metrics::DEFAULT_SUMMARY_INTERVAL, std::time::Duration::from_secs);
+    if !metrics_interval.is_zero() {
+        task::spawn(metrics.clone().log_every(metrics_interval, server.shutdown_handle()));
+    }
+
     task::block_on(async move {
         server.run_with_messages(move |msg, stream| {
+            let codec = codec.clone();
+            let metrics = metrics.clone();
             let game_state = game_state.clone();
             let clients = clients.clone();
+            let players = players.clone();
+            let sessions = sessions.clone();
             async move {
-                let client_id = AsyncTcpServer::get_socket_id(&stream);
-                clients.lock().unwrap().add_client(client_id as u32, stream.clone());
-                
-                handle_read_server(&msg, game_state.clone(), client_id as u32, &mut clients.lock().unwrap());
+                let connection_id = AsyncTcpServer::get_socket_id(&stream);
+                let player_id = sessions.lock().unwrap().player_for_connection(connection_id);
+                // Handler time is grouped by the type of message, decoded once for the handler
+                let started = std::time::Instant::now();
+                let message = ClientMessage::from_json(&msg);
+                let kind = message.as_ref().map_or("unknown", ClientMessage::kind);
+
+                match player_id {
+                    Some(player_id) => {
+                        let sessions = sessions.lock().unwrap();
+                        handle_read_server(message, game_state.clone(), player_id, &sessions, &mut clients.lock().unwrap(), &mut players.lock().unwrap());
+                    }
+                    None => {
+                        let mut sessions = sessions.lock().unwrap();
+                        if sessions.is_greeted(connection_id) {
+                            handle_join_server(message, stream, connection_id, game_state.clone(), &mut sessions, &mut clients.lock().unwrap(), &mut players.lock().unwrap());
+                            if let Some(player_id) = sessions.player_for_connection(connection_id) {
+                                metrics.set_label(connection_id, format!("player {}", player_id));
+                            }
+                        } else {
+                            handle_hello_server(message, stream, connection_id, &mut sessions, &codec);
+                        }
+                    }
+                }
+                metrics.record_message(connection_id, kind, msg.len(), started.elapsed());
                 Ok(())
             }
         }).await.expect("Server failed to run");
     });
+    println!("Server stopped.");
 }
\ No newline at end of file
@@ -0,0 +1,282 @@
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use crate::protocol::PlayerId;
+
+/// How long a disconnected player's entity is kept around for its session token to resume it.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(60);
+
+/// How far above its preferred latency a player's RTT may go before the server warns about it.
+pub const LATENCY_TOLERANCE: f64 = 2.0;
+
+/// A player whose connection dropped, waiting to be resumed.
+struct ParkedPlayer {
+    room_id: i32,
+    entity: Value,
+    since: Instant,
+}
+
+/// What a client said about itself in its `Hello`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerProfile {
+    pub name: String,
+    pub skin: i32,
+    /// Capabilities both the client and the server support.
+    pub capabilities: Vec<String>,
+}
+
+impl PlayerProfile {
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Result of a join handshake.
+pub struct JoinOutcome {
+    pub player_id: PlayerId,
+    pub token: String,
+    /// Room and entity to put back into the game when an earlier session was resumed.
+    pub resumed: Option<(i32, Value)>,
+}
+
+/// Server-side bookkeeping that maps connections to stable player ids.
+/// Connections are identified by their socket id, which is only unique while the socket is open,
+/// so nothing outside this struct should treat it as a player id.
+pub struct Sessions {
+    next_player_id: PlayerId,
+    tokens: HashMap<String, PlayerId>,
+    connections: HashMap<usize, PlayerId>,
+    parked: HashMap<PlayerId, ParkedPlayer>,
+    preferred_latency: HashMap<PlayerId, Duration>,
+    over_latency: HashSet<PlayerId>,
+    /// Connections that finished `Hello` but have not joined yet.
+    greeted: HashMap<usize, PlayerProfile>,
+    profiles: HashMap<PlayerId, PlayerProfile>,
+    /// Keys datagrams are sent with, and the connection each one was handed to.
+    udp_keys: HashMap<String, usize>,
+}
+
+fn new_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Sessions {
+            next_player_id: 1,
+            tokens: HashMap::new(),
+            connections: HashMap::new(),
+            parked: HashMap::new(),
+            preferred_latency: HashMap::new(),
+            over_latency: HashSet::new(),
+            greeted: HashMap::new(),
+            profiles: HashMap::new(),
+            udp_keys: HashMap::new(),
+        }
+    }
+
+    /// The player a connection joined as, if it has finished the handshake.
+    pub fn player_for_connection(&self, connection_id: usize) -> Option<PlayerId> {
+        self.connections.get(&connection_id).copied()
+    }
+
+    /// Records a connection's accepted `Hello`. It may `join` after this.
+    pub fn greet(&mut self, connection_id: usize, profile: PlayerProfile) {
+        self.greeted.insert(connection_id, profile);
+    }
+
+    pub fn is_greeted(&self, connection_id: usize) -> bool {
+        self.greeted.contains_key(&connection_id)
+    }
+
+    /// Name, skin and capabilities a player joined with.
+    pub fn profile(&self, player_id: PlayerId) -> Option<&PlayerProfile> {
+        self.profiles.get(&player_id)
+    }
+
+    /// Binds a connection to a player. A known token gets its old player id (and parked entity)
+    /// back; anything else gets a fresh id and token.
+    pub fn join(&mut self, connection_id: usize, token: Option<&str>) -> JoinOutcome {
+        self.expire_parked();
+
+        if let Some((token, player_id)) = token.and_then(|t| self.tokens.get_key_value(t)) {
+            let (token, player_id) = (token.clone(), *player_id);
+            if let Some(profile) = self.greeted.remove(&connection_id) {
+                self.profiles.insert(player_id, profile);
+            }
+            // A reconnect can beat the server noticing the old socket died, so take the player over
+            self.connections.retain(|_, id| *id != player_id);
+            self.connections.insert(connection_id, player_id);
+            let resumed = self.parked.remove(&player_id).map(|p| (p.room_id, p.entity));
+            return JoinOutcome { player_id, token, resumed };
+        }
+
+        let player_id = self.next_player_id;
+        self.next_player_id += 1;
+        let token = new_token();
+        self.tokens.insert(token.clone(), player_id);
+        self.connections.insert(connection_id, player_id);
+        if let Some(profile) = self.greeted.remove(&connection_id) {
+            self.profiles.insert(player_id, profile);
+        }
+        JoinOutcome { player_id, token, resumed: None }
+    }
+
+    /// Hands a connection a fresh key for its datagrams, to send in its `Welcome`. Unlike the
+    /// session token it only lasts as long as the connection.
+    pub fn new_udp_key(&mut self, connection_id: usize) -> String {
+        let key = new_token();
+        self.udp_keys.insert(key.clone(), connection_id);
+        key
+    }
+
+    /// The player on the connection `key` was handed to, if that connection has joined and is
+    /// still open.
+    pub fn player_for_udp_key(&self, key: &str) -> Option<PlayerId> {
+        self.player_for_connection(*self.udp_keys.get(key)?)
+    }
+
+    /// Remembers the latency a player asked for in its `Join`.
+    pub fn set_preferred_latency(&mut self, player_id: PlayerId, latency: Duration) {
+        self.preferred_latency.insert(player_id, latency);
+    }
+
+    /// Records a connection's latest RTT. Returns the player and its preferred latency when the RTT
+    /// has just gone more than `LATENCY_TOLERANCE` times above it, so the warning fires once per spike.
+    pub fn check_latency(&mut self, connection_id: usize, rtt: Duration) -> Option<(PlayerId, Duration)> {
+        let player_id = self.player_for_connection(connection_id)?;
+        let preferred = *self.preferred_latency.get(&player_id)?;
+        if rtt.as_secs_f64() > preferred.as_secs_f64() * LATENCY_TOLERANCE {
+            if self.over_latency.insert(player_id) {
+                return Some((player_id, preferred));
+            }
+        } else {
+            self.over_latency.remove(&player_id);
+        }
+        None
+    }
+
+    /// Unbinds a closed connection and returns the player it was playing as. `left` is the room
+    /// and entity the player was taken out of; those are kept so its token can resume it within
+    /// `RESUME_WINDOW`. A player that was in no room, or whose token was revoked, is forgotten.
+    pub fn disconnect(&mut self, connection_id: usize, left: Option<(i32, Value)>) -> Option<PlayerId> {
+        self.greeted.remove(&connection_id);
+        self.udp_keys.retain(|_, id| *id != connection_id);
+        let player_id = self.connections.remove(&connection_id)?;
+        match left {
+            Some((room_id, entity)) if self.tokens.values().any(|id| *id == player_id) => {
+                self.parked.insert(player_id, ParkedPlayer { room_id, entity, since: Instant::now() });
+            }
+            _ => self.forget(player_id),
+        }
+        Some(player_id)
+    }
+
+    /// Forgets a player's token, e.g. when it is kicked, so its session cannot be resumed.
+    pub fn revoke(&mut self, player_id: PlayerId) {
+        self.forget(player_id);
+    }
+
+    fn expire_parked(&mut self) {
+        let expired: Vec<PlayerId> = self.parked.iter()
+            .filter(|(_, parked)| parked.since.elapsed() > RESUME_WINDOW)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.forget(id);
+        }
+    }
+
+    /// Drops everything kept for a player that will not be resumed.
+    fn forget(&mut self, player_id: PlayerId) {
+        self.tokens.retain(|_, id| *id != player_id);
+        self.parked.remove(&player_id);
+        self.preferred_latency.remove(&player_id);
+        self.over_latency.remove(&player_id);
+        self.profiles.remove(&player_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_join_and_resume() {
+        let mut sessions = Sessions::new();
+        let first = sessions.join(10, None);
+        let second = sessions.join(11, None);
+        assert_ne!(first.player_id, second.player_id);
+        assert_ne!(first.token, second.token);
+        assert_eq!(sessions.player_for_connection(10), Some(first.player_id));
+
+        // The socket id gets reused by the OS, but the player id does not follow it
+        assert_eq!(sessions.disconnect(10, Some((1, json!({"id": first.player_id, "x": 5})))), Some(first.player_id));
+        let stranger = sessions.join(10, None);
+        assert_ne!(stranger.player_id, first.player_id);
+
+        let resumed = sessions.join(12, Some(&first.token));
+        assert_eq!(resumed.player_id, first.player_id);
+        assert_eq!(resumed.resumed, Some((1, json!({"id": first.player_id, "x": 5}))));
+
+        let unknown = sessions.join(13, Some("not-a-token"));
+        assert!(unknown.resumed.is_none());
+        assert_ne!(unknown.player_id, first.player_id);
+
+        // UDP keys belong to the connection, not the session: the token is no key, and a
+        // reconnect gets a new one
+        let key = sessions.new_udp_key(11);
+        assert_eq!(sessions.player_for_udp_key(&key), Some(second.player_id));
+        assert_eq!(sessions.player_for_udp_key(&second.token), None);
+        sessions.disconnect(11, Some((1, json!({"id": second.player_id}))));
+        assert_eq!(sessions.player_for_udp_key(&key), None);
+        let new_key = sessions.new_udp_key(14);
+        assert_eq!(sessions.join(14, Some(&second.token)).player_id, second.player_id);
+        assert_ne!(new_key, key);
+        assert_eq!(sessions.player_for_udp_key(&new_key), Some(second.player_id));
+
+        // Leaving from outside a room, or being kicked, leaves nothing to resume
+        sessions.disconnect(12, None);
+        assert_ne!(sessions.join(15, Some(&first.token)).player_id, first.player_id);
+        sessions.revoke(second.player_id);
+        sessions.disconnect(14, Some((1, json!({"id": second.player_id}))));
+        assert_ne!(sessions.join(16, Some(&second.token)).player_id, second.player_id);
+    }
+
+    #[test]
+    fn test_profile_follows_the_connection_into_the_player() {
+        let mut sessions = Sessions::new();
+        let profile = PlayerProfile { name: "Ada".to_string(), skin: 2, capabilities: vec!["udp".to_string()] };
+        sessions.greet(30, profile.clone());
+        assert!(sessions.is_greeted(30));
+
+        let joined = sessions.join(30, None);
+        assert!(!sessions.is_greeted(30));
+        assert_eq!(sessions.profile(joined.player_id), Some(&profile));
+        assert!(profile.has_capability("udp"));
+
+        sessions.greet(31, profile);
+        sessions.disconnect(31, None);
+        assert!(!sessions.is_greeted(31));
+
+        // A player that is not parked takes its profile along
+        sessions.disconnect(30, None);
+        assert_eq!(sessions.profile(joined.player_id), None);
+    }
+
+    #[test]
+    fn test_latency_warning_fires_once_per_spike() {
+        let mut sessions = Sessions::new();
+        let player = sessions.join(20, None).player_id;
+        // No preference sent, nothing to compare against
+        assert_eq!(sessions.check_latency(20, Duration::from_secs(5)), None);
+
+        sessions.set_preferred_latency(player, Duration::from_millis(40));
+        assert_eq!(sessions.check_latency(20, Duration::from_millis(60)), None);
+        assert_eq!(sessions.check_latency(20, Duration::from_millis(200)), Some((player, Duration::from_millis(40))));
+        assert_eq!(sessions.check_latency(20, Duration::from_millis(250)), None);
+        assert_eq!(sessions.check_latency(20, Duration::from_millis(30)), None);
+        assert!(sessions.check_latency(20, Duration::from_millis(200)).is_some());
+    }
+}
@@ -5,6 +5,9 @@ use serde_json::json;
 use serde_json::Value;
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::time::Duration;
+use async_std::task;
+use crate::discovery::{self, DiscoveredServer};
 
 fn get_settings_path() -> PathBuf {
     Path::new(env!("CARGO_MANIFEST_DIR")).join("src").join("data.json")
@@ -21,7 +24,8 @@ fn ensure_settings_exists() {
                 "PORT": "5766",
                 "NAME": "Player",
                 "IP": "127.0.0.1",
-                "PREFERRED_LATENCY": "40"
+                "PREFERRED_LATENCY": "40",
+                "TLS": "off"
             }
         });
         fs::write(&path, serde_json::to_string_pretty(&default_settings).unwrap())
@@ -46,7 +50,15 @@ fn read_settings() -> Value {
 fn write_settings(settings: &Value) -> Result<(), std::io::Error> {
     let path = get_settings_path();
     let mut data = read_settings();
-    data["settings"] = settings.clone();
+    // Only overwrite the keys the settings screen edits, so hand-set ones like TLS_CERT survive
+    if !data["settings"].is_object() {
+        data["settings"] = json!({});
+    }
+    if let (Some(current), Some(edited)) = (data["settings"].as_object_mut(), settings.as_object()) {
+        for (key, value) in edited {
+            current.insert(key.clone(), value.clone());
+        }
+    }
     
     fs::write(&path, serde_json::to_string_pretty(&data)?)
 }
@@ -81,6 +93,13 @@ pub fn main() {
     let mut name_field = raylib_interactive::textfield::TextField::new(250.0, 300.0, 200.0, 30.0, 32);
     let mut ip_field = raylib_interactive::textfield::TextField::new(250.0, 350.0, 200.0, 30.0, 32);
     let mut latency_field = raylib_interactive::textfield::TextField::new(250.0, 400.0, 100.0, 30.0, 1);
+    let mut find_button = raylib_interactive::button::Button::new(460.0, 350.0, 150.0, 30.0, "Find LAN Servers");
+    find_button.set_colors(Color::GRAY, Color::DARKGRAY, Color::LIGHTGRAY, Color::BLACK, Color::WHITE);
+    find_button.set_font_size(10);
+    // Servers found by the last search, each with a button that fills in IP and Port
+    let mut found_servers: Option<Vec<(DiscoveredServer, raylib_interactive::button::Button)>> = None;
+    // The search runs in the background and hands its result over here, so the window keeps drawing
+    let mut searching: Option<async_std::channel::Receiver<Vec<DiscoveredServer>>> = None;
     let mut more_button = raylib_interactive::button::Button::new(10.0, 10.0, 100.0, 50.0, "More");
     more_button.set_colors(Color::GRAY, Color::DARKGRAY, Color::LIGHTGRAY, Color::BLACK, Color::WHITE);
     more_button.set_font_size(20);
@@ -149,6 +168,42 @@ pub fn main() {
         latency_field.update(&mut rl);
         skin_field.update(&mut rl);
         more_button.update(&mut rl);
+        find_button.update(&mut rl);
+        if find_button.is_clicked(&mut rl) && searching.is_none() {
+            let targets = discovery::default_targets(discovery::port_setting(&read_settings()["settings"]));
+            let (tx, rx) = async_std::channel::bounded(1);
+            task::spawn(async move {
+                let servers = discovery::discover(&targets, Duration::from_millis(500)).await
+                    .unwrap_or_else(|e| {
+                        println!("LAN search failed: {}", e);
+                        Vec::new()
+                    });
+                let _ = tx.send(servers).await;
+            });
+            searching = Some(rx);
+        }
+        let finished = searching.as_ref().and_then(|rx| match rx.try_recv() {
+            Ok(servers) => Some(servers),
+            Err(async_std::channel::TryRecvError::Empty) => None,
+            Err(async_std::channel::TryRecvError::Closed) => Some(Vec::new()),
+        });
+        if let Some(servers) = finished {
+            searching = None;
+            found_servers = Some(servers.into_iter().enumerate().map(|(i, server)| {
+                let label = format!("{} ({} players) {}", server.info.name, server.info.players, server.addr);
+                let mut button = raylib_interactive::button::Button::new(620.0, 100.0 + 40.0 * i as f32, 350.0, 30.0, &label);
+                button.set_colors(Color::GRAY, Color::DARKGRAY, Color::LIGHTGRAY, Color::BLACK, Color::WHITE);
+                button.set_font_size(10);
+                (server, button)
+            }).collect());
+        }
+        for (server, button) in found_servers.iter_mut().flatten() {
+            button.update(&mut rl);
+            if button.is_clicked(&mut rl) {
+                ip_field.set_value(&server.addr.ip().to_string());
+                port_field.set_value(&server.addr.port().to_string());
+            }
+        }
         if more_button.is_clicked(&mut rl) {
             // Open skin redemption page
             while !rl.window_should_close() {
@@ -182,6 +237,7 @@ pub fn main() {
                 "FPS": fps_field.get_text(),
                 "PORT": port_field.get_text(),
                 "SKIN": skin_field.get_text(),
+                "NAME": name_field.get_text(),
                 "IP": ip_field.get_text(),
                 "PREFERRED_LATENCY": latency_field.get_text(),
             });
@@ -210,6 +266,18 @@ pub fn main() {
         name_field.draw(&mut d);
         d.draw_text("IP:", 100, 355, 20, Color::BLACK);
         ip_field.draw(&mut d);
+        find_button.draw(&mut d);
+        match &found_servers {
+            _ if searching.is_some() => d.draw_text("Searching...", 620, 105, 20, Color::BLACK),
+            Some(servers) if servers.is_empty() => d.draw_text("No servers found", 620, 105, 20, Color::BLACK),
+            Some(servers) => {
+                d.draw_text("LAN servers:", 620, 65, 20, Color::BLACK);
+                for (_, button) in servers {
+                    button.draw(&mut d);
+                }
+            }
+            None => {}
+        }
         d.draw_text("Preferred Latency:", 30, 405, 20, Color::BLACK);
         latency_field.draw(&mut d);
         d.draw_text("Skin:", 100, 455, 20, Color::BLACK);
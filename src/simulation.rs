@@ -0,0 +1,336 @@
+use crate::handle_read::{apply_input_server, entity_position};
+use crate::networking::{ClientConnections, Outgoing, ShutdownHandle};
+use crate::players::Players;
+use crate::protocol::{EntityPosition, PlayerId, PositionUpdate, ServerDatagram, ServerMessage};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The server's fixed-rate loop. Client moves are queued as they arrive and applied together at the
+// start of each tick, then NPCs take their step, and every room gets a single RoomUpdate with
+// everything in it that moved, cut down per client to what that client can see (see interest.rs).
+// Delta sync runs from the same loop every SYNC_INTERVAL.
+
+/// Ticks per second, unless the TICK_RATE setting says otherwise.
+pub const DEFAULT_TICK_RATE: u32 = 20;
+pub const MAX_TICK_RATE: u32 = 120;
+/// How often clients are sent a delta of the game state.
+pub const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+/// Ticks the loop may fall behind before it gives up catching up and starts over from now.
+const MAX_CATCH_UP: u32 = 5;
+
+/// A move a client sent, waiting for the next tick.
+#[derive(Debug, Clone)]
+pub struct QueuedInput {
+    pub player: PlayerId,
+    pub update: PositionUpdate,
+    /// When it arrived, which is what movement validation measures speed against.
+    pub received: Instant,
+}
+
+/// What one tick changed.
+#[derive(Debug, Default)]
+pub struct TickOutput {
+    /// One RoomUpdate per `roomID` that had anything move.
+    pub updates: Vec<(i32, ServerMessage)>,
+    /// Moves that could not be applied, and why.
+    pub errors: Vec<(PlayerId, String)>,
+}
+
+pub struct Simulation {
+    tick: u64,
+    interval: Duration,
+    last_sync: Option<Instant>,
+}
+
+impl Simulation {
+    /// A simulation that runs `rate` ticks per second, between 1 and MAX_TICK_RATE.
+    pub fn new(rate: u32) -> Self {
+        let rate = rate.clamp(1, MAX_TICK_RATE);
+        Simulation { tick: 0, interval: Duration::from_secs(1) / rate, last_sync: None }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Ticks run so far.
+    pub fn tick_count(&self) -> u64 {
+        self.tick
+    }
+
+    /// Advances the game by one tick: applies the queued moves in the order they arrived, moves
+    /// the NPCs, and collects what changed per room.
+    pub fn step(&mut self, game: &mut Value, players: &mut Players) -> TickOutput {
+        self.tick += 1;
+        let mut output = TickOutput::default();
+
+        // Only the last position of a player that moved several times this tick is sent
+        let mut moved_players: BTreeMap<i32, BTreeMap<PlayerId, EntityPosition>> = BTreeMap::new();
+        for input in players.drain_inputs() {
+            match apply_input_server(game, players, &input) {
+                Ok((player, room_id)) => {
+                    moved_players.entry(room_id).or_default().insert(input.player, entity_position(input.player, &player));
+                }
+                Err(reason) => output.errors.push((input.player, reason)),
+            }
+        }
+
+        let mut npcs: BTreeMap<i32, Vec<EntityPosition>> = BTreeMap::new();
+        for (room_id, position) in advance_npcs(game, self.interval.as_secs_f32()) {
+            npcs.entry(room_id).or_default().push(position);
+        }
+
+        let mut rooms: Vec<i32> = moved_players.keys().chain(npcs.keys()).copied().collect();
+        rooms.sort_unstable();
+        rooms.dedup();
+        for room in rooms {
+            let update = ServerMessage::RoomUpdate {
+                tick: self.tick,
+                room,
+                players: moved_players.remove(&room).map(|moved| moved.into_values().collect()).unwrap_or_default(),
+                npcs: npcs.remove(&room).unwrap_or_default(),
+            };
+            output.updates.push((room, update));
+        }
+        output
+    }
+
+    /// Runs one tick and returns what to send. Entities that came into or went out of a client's
+    /// view are spawned or despawned for it, then each room's RoomUpdate goes to everyone in the
+    /// room, over UDP where the client has it, cut down to what each of them can see. Every
+    /// SYNC_INTERVAL this also adds the delta sync. Nothing is sent here, so the caller can let go
+    /// of the game before any client is written to.
+    pub fn tick(&mut self, game: &mut Value, players: &mut Players, now: Instant) -> Vec<Outgoing> {
+        let output = self.step(game, players);
+        let mut outgoing = Vec::new();
+
+        for (viewer, change) in players.update_interests(game) {
+            for (room, npc, id) in change.despawned {
+                outgoing.push(Outgoing { to: viewer, message: ServerMessage::Despawn { room, npc, id }.to_json(), datagram: None });
+            }
+            for (room, npc, entity) in change.spawned {
+                outgoing.push(Outgoing { to: viewer, message: ServerMessage::Spawn { room, npc, entity }.to_json(), datagram: None });
+            }
+        }
+        for (room_id, update) in output.updates {
+            for viewer in ClientConnections::room_members(game, room_id) {
+                if let Some(update) = visible_part(players, viewer, &update) {
+                    let datagram = ServerDatagram { seq: self.tick as u32, message: update.clone() };
+                    outgoing.push(Outgoing { to: viewer, message: update.to_json(), datagram: Some(datagram.to_json()) });
+                }
+            }
+        }
+        for (player_id, reason) in output.errors {
+            outgoing.push(Outgoing { to: player_id, message: ServerMessage::Error { reason }.to_json(), datagram: None });
+        }
+
+        if self.last_sync.is_none_or(|last| now.saturating_duration_since(last) >= SYNC_INTERVAL) {
+            for (id, update) in players.sync_updates(game) {
+                outgoing.push(Outgoing { to: id, message: update.to_json(), datagram: None });
+            }
+            self.last_sync = Some(now);
+        }
+        outgoing
+    }
+}
+
+/// The part of a RoomUpdate that `viewer` can see, or None if that is nothing.
+fn visible_part(state: &Players, viewer: PlayerId, update: &ServerMessage) -> Option<ServerMessage> {
+    match update {
+        ServerMessage::RoomUpdate { tick, room, players, npcs } => {
+            let players: Vec<EntityPosition> = players.iter().filter(|p| state.can_see(viewer, false, p.id)).cloned().collect();
+            let npcs: Vec<EntityPosition> = npcs.iter().filter(|n| state.can_see(viewer, true, n.id)).cloned().collect();
+            if players.is_empty() && npcs.is_empty() {
+                return None;
+            }
+            Some(ServerMessage::RoomUpdate { tick: *tick, room: *room, players, npcs })
+        }
+        other => Some(other.clone()),
+    }
+}
+
+/// Walks every NPC that has "waypoints" towards the current one (its "waypoint" index) at its
+/// "speed" in units per second, moving on to the next waypoint when it gets there and starting
+/// over after the last. Returns the `roomID` and new position of every NPC that moved.
+pub fn advance_npcs(game: &mut Value, dt: f32) -> Vec<(i32, EntityPosition)> {
+    let mut moved = Vec::new();
+    if let Value::Object(rooms) = game {
+        for (_, room) in rooms.iter_mut() {
+            let room_id = room["roomID"].as_i64().unwrap_or(0) as i32;
+            if let Some(npcs) = room.get_mut("npcs").and_then(|n| n.as_array_mut()) {
+                for npc in npcs.iter_mut() {
+                    if step_npc(npc, dt) {
+                        let id = npc["id"].as_u64().unwrap_or(0) as PlayerId;
+                        moved.push((room_id, entity_position(id, npc)));
+                    }
+                }
+            }
+        }
+    }
+    moved
+}
+
+fn step_npc(npc: &mut Value, dt: f32) -> bool {
+    let waypoints: Vec<(f32, f32)> = match npc["waypoints"].as_array() {
+        Some(waypoints) => waypoints.iter()
+            .filter_map(|w| Some((w[0].as_f64()? as f32, w[1].as_f64()? as f32)))
+            .collect(),
+        None => return false,
+    };
+    let speed = npc["speed"].as_f64().unwrap_or(0.0) as f32;
+    if waypoints.is_empty() || speed <= 0.0 {
+        return false;
+    }
+
+    let start = (npc["x"].as_f64().unwrap_or(0.0) as f32, npc["y"].as_f64().unwrap_or(0.0) as f32);
+    let (mut x, mut y) = start;
+    let mut index = npc["waypoint"].as_u64().unwrap_or(0) as usize % waypoints.len();
+    let mut budget = speed * dt;
+    // A fast NPC can pass more than one waypoint in a tick, but never lap the whole route
+    for _ in 0..waypoints.len() {
+        let (target_x, target_y) = waypoints[index];
+        let (dx, dy) = (target_x - x, target_y - y);
+        let distance = (dx * dx + dy * dy).sqrt();
+        if distance > budget {
+            x += dx / distance * budget;
+            y += dy / distance * budget;
+            break;
+        }
+        x = target_x;
+        y = target_y;
+        budget -= distance;
+        index = (index + 1) % waypoints.len();
+    }
+
+    npc["x"] = json!(x);
+    npc["y"] = json!(y);
+    npc["waypoint"] = json!(index);
+    (x, y) != start
+}
+
+/// Calls `tick` every `interval` until `shutdown`. Ticks are scheduled from when the loop
+/// started, so one slow tick makes the next come sooner instead of pushing all later ones back.
+/// Falling more than MAX_CATCH_UP ticks behind starts the schedule over from now.
+pub fn run_fixed_rate(interval: Duration, shutdown: &ShutdownHandle, mut tick: impl FnMut()) {
+    let mut next = Instant::now() + interval;
+    while !shutdown.is_shut_down() {
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+        tick();
+        next += interval;
+        let now = Instant::now();
+        if now.saturating_duration_since(next) > interval * MAX_CATCH_UP {
+            next = now + interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::{AsyncTcpClient, NetStream};
+    use async_std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_npcs_patrol_their_waypoints() {
+        let mut game = json!({
+            "room1": {
+                "npcs": [
+                    {"id": 1, "x": 0.0, "y": 0.0, "speed": 100.0, "waypoints": [[0, 0], [100, 0], [100, 100]]},
+                    {"id": 2, "x": 5.0, "y": 5.0},
+                ],
+                "roomID": 1
+            },
+        });
+
+        let moved = advance_npcs(&mut game, 0.5);
+        assert_eq!(moved.len(), 1);
+        assert_eq!((moved[0].0, moved[0].1.id, moved[0].1.x, moved[0].1.y), (1, 1, 50.0, 0.0));
+
+        // Past the corner and onwards to the next waypoint
+        advance_npcs(&mut game, 1.0);
+        let npc = &game["room1"]["npcs"][0];
+        assert_eq!((npc["x"].as_f64(), npc["y"].as_f64(), npc["waypoint"].as_u64()), (Some(100.0), Some(50.0), Some(2)));
+    }
+
+    #[test]
+    fn test_new_players_start_at_the_spawn() {
+        let mut game = json!({
+            "room1": {"objects": [{"x": 0, "y": 0, "width": 1000, "height": 1000, "id": 0}], "players": [], "spawn": {"x": 100, "y": 100}, "roomID": 1},
+            "room2": {"objects": [{"x": 0, "y": 0, "width": 1000, "height": 1000, "id": 0}], "players": [], "roomID": 2},
+        });
+        let mut players = Players::new();
+        players.add(7);
+        let received = Instant::now();
+        let update = |room, x, y| PositionUpdate { room, x, y, width: None, height: None, sprite_state: None, input: None };
+
+        // Joining straight into another room is refused
+        players.queue_input(QueuedInput { player: 7, update: update(2, 100.0, 100.0), received });
+        let mut simulation = Simulation::new(20);
+        let output = simulation.step(&mut game, &mut players);
+        assert_eq!(output.errors.len(), 1);
+        assert_eq!(game["room2"]["players"].as_array().map(Vec::len), Some(0));
+
+        // Joining far from the spawn only gets as far as a move from it could
+        players.queue_input(QueuedInput { player: 7, update: update(1, 900.0, 900.0), received });
+        simulation.step(&mut game, &mut players);
+        let x = game["room1"]["players"][0]["x"].as_f64().unwrap();
+        assert!(x > 100.0 && x < 900.0, "joined at x = {}", x);
+        assert_eq!(players.motion(7).map(|motion| motion.recent_violations()), Some(1));
+    }
+
+    #[async_std::test]
+    async fn test_queued_moves_go_out_once_per_tick() -> async_std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client: NetStream = TcpStream::connect(listener.local_addr()?).await?.into();
+        let (server_side, _) = listener.accept().await?;
+        let mut clients = ClientConnections::new();
+        clients.add_client(7, server_side.into());
+        let mut players = Players::new();
+        players.add(7);
+
+        let mut game = json!({
+            "room1": {
+                "objects": [{"x": 0, "y": 0, "width": 1000, "height": 1000, "id": 0}],
+                "players": [],
+                "npcs": [],
+                "roomID": 1
+            },
+        });
+        let received = Instant::now();
+        for (input, x) in [(1, 400.0), (2, 403.0), (3, 406.0)] {
+            let update = PositionUpdate { room: 1, x, y: 250.0, width: None, height: None, sprite_state: None, input: Some(input) };
+            players.queue_input(QueuedInput { player: 7, update, received });
+        }
+
+        // Nothing moves until the tick runs, and then all three moves go out as one update
+        assert_eq!(game["room1"]["players"].as_array().map(Vec::len), Some(0));
+        let mut simulation = Simulation::new(20);
+        let outgoing = simulation.tick(&mut game, &mut players, received);
+        assert!(clients.outbox(outgoing).send().await.is_empty());
+        assert_eq!(game["room1"]["players"][0]["x"].as_f64(), Some(406.0));
+
+        // The player comes into its own view first
+        let message = ServerMessage::from_json(&AsyncTcpClient::receive(&mut client).await?).unwrap();
+        match message {
+            ServerMessage::Spawn { room: 1, npc: false, entity } => assert_eq!(entity["id"], 7),
+            other => panic!("expected a spawn, got {:?}", other),
+        }
+        let message = ServerMessage::from_json(&AsyncTcpClient::receive(&mut client).await?).unwrap();
+        match message {
+            ServerMessage::RoomUpdate { tick, room, players, npcs } => {
+                assert_eq!((tick, room), (1, 1));
+                assert_eq!(players.len(), 1);
+                assert_eq!((players[0].id, players[0].x, players[0].input), (7, 406.0, Some(3)));
+                assert!(npcs.is_empty());
+            }
+            other => panic!("expected a room update, got {:?}", other),
+        }
+        assert!(players.drain_inputs().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use futures_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use futures_rustls::rustls::crypto::ring;
+use futures_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use serde_json::Value;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+
+// TLS setup for AsyncTcpServer / AsyncTcpClient, driven by these data.json settings:
+//   "TLS":             "off" (default), "on" or "dev"
+//   "TLS_CERT":        server certificate chain (PEM). In dev mode the generated certificate is written here.
+//   "TLS_KEY":         server private key (PEM), only used with "on"
+//   "TLS_CA":          certificate(s) the client trusts instead of the public roots
+//   "TLS_SERVER_NAME": name the client expects in the server certificate, defaults to "IP"
+
+/// Where the dev-mode certificate goes when `TLS_CERT` is not set.
+pub const DEV_CERT_PATH: &str = "dev_cert.pem";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsMode {
+    Off,
+    /// Real certificate from `TLS_CERT` / `TLS_KEY`.
+    On,
+    /// Throwaway self-signed certificate, made fresh every time the server starts.
+    Dev,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsSettings {
+    pub mode: TlsMode,
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub ca_path: Option<String>,
+    pub server_name: Option<String>,
+}
+
+impl TlsSettings {
+    /// Reads the TLS settings out of the `settings` object in data.json.
+    pub fn from_settings(settings: &Value) -> io::Result<Self> {
+        let text = |key: &str| settings[key].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+        let mode = match text("TLS").as_deref().unwrap_or("off") {
+            "off" => TlsMode::Off,
+            "on" => TlsMode::On,
+            "dev" => TlsMode::Dev,
+            other => return Err(io::Error::new(ErrorKind::InvalidInput, format!("TLS must be off, on or dev, not {:?}", other))),
+        };
+        Ok(TlsSettings {
+            mode,
+            cert_path: text("TLS_CERT"),
+            key_path: text("TLS_KEY"),
+            ca_path: text("TLS_CA"),
+            server_name: text("TLS_SERVER_NAME"),
+        })
+    }
+
+    /// Builds the server side of the config, or `None` when TLS is off. `hosts` are the names and
+    /// addresses a dev certificate is made out to.
+    pub fn server_config(&self, hosts: &[String]) -> io::Result<Option<Arc<ServerConfig>>> {
+        match self.mode {
+            TlsMode::Off => Ok(None),
+            TlsMode::On => {
+                let (cert_path, key_path) = match (&self.cert_path, &self.key_path) {
+                    (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+                    _ => return Err(io::Error::new(ErrorKind::InvalidInput, "TLS is on but TLS_CERT or TLS_KEY is not set")),
+                };
+                let certs = std::fs::read_to_string(cert_path)?;
+                let key = std::fs::read_to_string(key_path)?;
+                server_config_from_pem(&certs, &key).map(Some)
+            }
+            TlsMode::Dev => {
+                let (cert_pem, config) = self_signed_server_config(hosts)?;
+                let cert_path = self.cert_path.as_deref().unwrap_or(DEV_CERT_PATH);
+                std::fs::write(cert_path, cert_pem)?;
+                println!("Wrote self-signed dev certificate to {}; clients need it as TLS_CA", cert_path);
+                Ok(Some(config))
+            }
+        }
+    }
+
+    /// Builds the client side of the config and the name to check the server certificate against,
+    /// or `None` when TLS is off. `host` is used when `TLS_SERVER_NAME` is not set.
+    pub fn client_config(&self, host: &str) -> io::Result<Option<(Arc<ClientConfig>, ServerName<'static>)>> {
+        if self.mode == TlsMode::Off {
+            return Ok(None);
+        }
+        let ca_path = match self.mode {
+            TlsMode::Dev => Some(self.ca_path.as_deref().unwrap_or(DEV_CERT_PATH)),
+            _ => self.ca_path.as_deref(),
+        };
+        let ca_pem = ca_path.map(std::fs::read_to_string).transpose()?;
+        let name = self.server_name.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(name.to_string())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("bad TLS server name {:?}: {}", name, e)))?;
+        Ok(Some((client_config(ca_pem.as_deref())?, server_name)))
+    }
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+fn parse_certs(pem: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut pem.as_bytes()).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "no certificates in PEM"));
+    }
+    Ok(certs)
+}
+
+/// Server config from a PEM certificate chain and private key.
+pub fn server_config_from_pem(certs_pem: &str, key_pem: &str) -> io::Result<Arc<ServerConfig>> {
+    let certs = parse_certs(certs_pem)?;
+    let key = rustls_pemfile::private_key(&mut key_pem.as_bytes())?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "no private key in PEM"))?;
+    build_server_config(certs, key)
+}
+
+/// Makes a self-signed certificate for `hosts` and a server config that uses it.
+/// Returns the certificate as PEM, so clients can be told to trust it.
+pub fn self_signed_server_config(hosts: &[String]) -> io::Result<(String, Arc<ServerConfig>)> {
+    let certified = rcgen::generate_simple_self_signed(hosts.to_vec())
+        .map_err(io::Error::other)?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    let config = build_server_config(vec![certified.cert.der().clone()], key)?;
+    Ok((certified.cert.pem(), config))
+}
+
+fn build_server_config(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+/// Client config that trusts only the certificates in `ca_pem`, or the public web roots without one.
+pub fn client_config(ca_pem: Option<&str>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_pem {
+        Some(pem) => {
+            for cert in parse_certs(pem)? {
+                roots.add(cert).map_err(tls_error)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tls_settings() {
+        let off = TlsSettings::from_settings(&json!({"IP": "127.0.0.1"})).unwrap();
+        assert_eq!(off.mode, TlsMode::Off);
+        assert!(off.server_config(&[]).unwrap().is_none());
+        assert!(off.client_config("127.0.0.1").unwrap().is_none());
+
+        let on = TlsSettings::from_settings(&json!({"TLS": "on", "TLS_CA": "ca.pem"})).unwrap();
+        assert_eq!(on.mode, TlsMode::On);
+        assert_eq!(on.ca_path.as_deref(), Some("ca.pem"));
+        // A real certificate has to be configured
+        assert!(on.server_config(&[]).is_err());
+
+        assert!(TlsSettings::from_settings(&json!({"TLS": "yes"})).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::collision;
+use crate::movement::{Movement, PLAYER_SPEED};
+use raylib::prelude::Vector2;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Server-side checks on the positions clients report. A client moves at most `speed * 40` units
+// per second on each axis (see Movement::apply), so every player has a movement allowance that
+// refills at that rate. A move that needs more than what is left is cut short, and the result is
+// then run through the room's collision rules, the same ones the client applies. Whatever the
+// client claimed differently is corrected and counts as a violation; enough of them in a short
+// time and the player is flagged.
+
+/// Violations within FLAG_WINDOW that get a player flagged.
+pub const FLAG_THRESHOLD: usize = 10;
+pub const FLAG_WINDOW: Duration = Duration::from_secs(10);
+/// Corrections smaller than this are rounding, not cheating.
+const EPSILON: f32 = 0.5;
+
+/// What players are allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovementRules {
+    /// Same meaning as Movement::speed.
+    pub speed: f32,
+    pub width: i32,
+    pub height: i32,
+    /// How much movement can be saved up, e.g. while updates are stuck in the network.
+    pub max_burst: Duration,
+    /// Extra room on top of the speed, for timing jitter between client and server.
+    pub tolerance: f32,
+}
+
+impl Default for MovementRules {
+    fn default() -> Self {
+        MovementRules {
+            speed: PLAYER_SPEED,
+            width: 50,
+            height: 50,
+            max_burst: Duration::from_millis(500),
+            tolerance: 1.25,
+        }
+    }
+}
+
+impl MovementRules {
+    /// Units per second a player may cover on each axis.
+    fn units_per_second(&self) -> f32 {
+        self.speed * 40.0 * self.tolerance
+    }
+}
+
+/// What happened to a reported position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Legal,
+    /// The position was changed to one the player could have reached.
+    Corrected,
+    /// Corrected, and this was the violation that got the player flagged.
+    Flagged,
+}
+
+/// Movement state the server keeps per player.
+#[derive(Debug, Clone)]
+pub struct MotionCheck {
+    rules: MovementRules,
+    allowance: f32,
+    last_move: Option<Instant>,
+    violations: VecDeque<Instant>,
+    flagged: bool,
+}
+
+impl MotionCheck {
+    pub fn new(rules: MovementRules) -> Self {
+        let allowance = rules.units_per_second() * rules.max_burst.as_secs_f32();
+        MotionCheck { rules, allowance, last_move: None, violations: VecDeque::new(), flagged: false }
+    }
+
+    pub fn rules(&self) -> &MovementRules {
+        &self.rules
+    }
+
+    pub fn is_flagged(&self) -> bool {
+        self.flagged
+    }
+
+    /// Violations in the last FLAG_WINDOW.
+    pub fn recent_violations(&self) -> usize {
+        self.violations.len()
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let max = self.rules.units_per_second() * self.rules.max_burst.as_secs_f32();
+        if let Some(last) = self.last_move {
+            let elapsed = now.saturating_duration_since(last).as_secs_f32();
+            self.allowance = (self.allowance + elapsed * self.rules.units_per_second()).min(max);
+        }
+        self.last_move = Some(now);
+    }
+
+    /// Checks a move from `from` (None when the player is placed for the first time) to the
+    /// reported `to`, with the reported size, in a room with `objects`. Returns where the player
+    /// really is and whether that differs from the report.
+    pub fn check(&mut self, from: Option<(f32, f32)>, to: (f32, f32), size: (Option<i32>, Option<i32>), objects: &[Value], now: Instant) -> ((f32, f32), Verdict) {
+        self.refill(now);
+        let mut illegal = size.0.is_some_and(|w| w != self.rules.width) || size.1.is_some_and(|h| h != self.rules.height);
+
+        let mut target = to;
+        if let Some((x, y)) = from {
+            let (dx, dy) = (to.0 - x, to.1 - y);
+            let distance = dx.abs().max(dy.abs());
+            if distance <= self.allowance {
+                self.allowance -= distance;
+            } else {
+                // Only as far as the allowance goes, in the direction the client wanted
+                let scale = self.allowance / distance;
+                target = (x + dx * scale, y + dy * scale);
+                illegal |= distance - self.allowance > EPSILON;
+                self.allowance = 0.0;
+            }
+        }
+
+        let mut player = Movement {
+            position: Vector2::new(target.0, target.1),
+            speed: self.rules.speed,
+            width: self.rules.width,
+            height: self.rules.height,
+        };
+        collision::collide_with_room(&mut player, objects);
+        let position = (player.position.x, player.position.y);
+        illegal |= (position.0 - to.0).abs() > EPSILON || (position.1 - to.1).abs() > EPSILON;
+
+        if !illegal {
+            return (position, Verdict::Legal);
+        }
+        self.violations.push_back(now);
+        while self.violations.front().is_some_and(|t| now.saturating_duration_since(*t) > FLAG_WINDOW) {
+            self.violations.pop_front();
+        }
+        if !self.flagged && self.violations.len() >= FLAG_THRESHOLD {
+            self.flagged = true;
+            return (position, Verdict::Flagged);
+        }
+        (position, Verdict::Corrected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn floor() -> Vec<Value> {
+        vec![json!({"x": 0, "y": 0, "width": 1000, "height": 1000, "id": 0})]
+    }
+
+    #[test]
+    fn test_moves_are_held_to_the_speed_and_the_room() {
+        let start = Instant::now();
+        let mut check = MotionCheck::new(MovementRules::default());
+        let size = (Some(50), Some(50));
+
+        // Placed for the first time, then a normal step a frame later
+        assert_eq!(check.check(None, (400.0, 250.0), size, &floor(), start), ((400.0, 250.0), Verdict::Legal));
+        let later = start + Duration::from_millis(16);
+        assert_eq!(check.check(Some((400.0, 250.0)), (403.0, 247.0), size, &floor(), later), ((403.0, 247.0), Verdict::Legal));
+
+        // Teleporting across the room only gets as far as the saved up allowance: 250 * 0.5 - 3
+        let (position, verdict) = check.check(Some((403.0, 247.0)), (903.0, 247.0), size, &floor(), later);
+        assert_eq!(verdict, Verdict::Corrected);
+        assert!((position.0 - 525.0).abs() < 0.01 && position.1 == 247.0, "{:?}", position);
+
+        // Walking out of the room is pushed back in, and so is growing
+        let (position, verdict) = check.check(Some((0.0, 0.0)), (-1.0, 0.0), (Some(500), Some(50)), &floor(), later + Duration::from_secs(1));
+        assert_eq!(verdict, Verdict::Corrected);
+        assert_eq!(position, (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_repeat_offenders_are_flagged_once() {
+        let start = Instant::now();
+        let mut check = MotionCheck::new(MovementRules::default());
+        let mut verdicts = Vec::new();
+        for i in 0..FLAG_THRESHOLD + 2 {
+            let now = start + Duration::from_millis(i as u64 * 10);
+            verdicts.push(check.check(Some((0.0, 0.0)), (900.0, 900.0), (None, None), &floor(), now).1);
+        }
+        assert_eq!(verdicts.iter().filter(|v| **v == Verdict::Flagged).count(), 1);
+        assert_eq!(verdicts[FLAG_THRESHOLD - 1], Verdict::Flagged);
+        assert!(check.is_flagged());
+    }
+}